@@ -0,0 +1,11 @@
+-- 004_key_dwell_and_repeat.sql
+-- dwell_ms is how long the key was held, null when the release was never seen.
+-- auto-repeat presses are kept but flagged so they are not counted as keystrokes
+ALTER TABLE events ADD COLUMN dwell_ms INTEGER;
+ALTER TABLE events ADD COLUMN is_repeat BOOLEAN NOT NULL DEFAULT 0;
+
+DROP VIEW IF EXISTS today_events;
+
+CREATE VIEW IF NOT EXISTS today_events AS
+SELECT id, event_timestamp, key_name, dwell_ms, is_repeat FROM events
+WHERE date(event_timestamp / 1000, 'unixepoch') = date('now', 'utc');
@@ -0,0 +1,10 @@
+-- 005_shortcuts.sql
+-- chords such as ctrl+shift+t, recorded alongside the individual key events
+CREATE TABLE IF NOT EXISTS shortcuts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_timestamp INTEGER NOT NULL,
+    combo TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_shortcuts_timestamps
+ON shortcuts(event_timestamp);
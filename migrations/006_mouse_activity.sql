@@ -0,0 +1,12 @@
+-- 006_mouse_activity.sql
+-- mouse usage aggregated per minute, raw cursor coordinates are never stored
+CREATE TABLE IF NOT EXISTS mouse_activity (
+    bucket_start INTEGER PRIMARY KEY,
+    left_clicks INTEGER NOT NULL DEFAULT 0,
+    right_clicks INTEGER NOT NULL DEFAULT 0,
+    middle_clicks INTEGER NOT NULL DEFAULT 0,
+    other_clicks INTEGER NOT NULL DEFAULT 0,
+    scroll_x INTEGER NOT NULL DEFAULT 0,
+    scroll_y INTEGER NOT NULL DEFAULT 0,
+    travel_px REAL NOT NULL DEFAULT 0
+);
@@ -0,0 +1,31 @@
+-- 007_canonical_key_id.sql
+-- key_name is the display name, which differs between platforms for modifiers.
+-- key_id identifies the physical key the same way everywhere so data can be merged,
+-- it is null for keys that have no platform neutral identifier
+ALTER TABLE events ADD COLUMN key_id TEXT;
+
+-- Backfill the keys whose names were already unambiguous
+UPDATE events SET key_id = 'Key' || upper(key_name)
+WHERE length(key_name) = 1 AND key_name BETWEEN 'a' AND 'z';
+
+UPDATE events SET key_id = 'Digit' || key_name
+WHERE length(key_name) = 1 AND key_name BETWEEN '0' AND '9';
+
+UPDATE events SET key_id = CASE key_name
+    WHEN 'command_left' THEN 'MetaLeft'
+    WHEN 'command_right' THEN 'MetaRight'
+    WHEN 'opt_left' THEN 'AltLeft'
+    WHEN 'opt_right' THEN 'AltRight'
+    WHEN 'ctrl_left' THEN 'ControlLeft'
+    WHEN 'ctrl_right' THEN 'ControlRight'
+    WHEN 'shift_left' THEN 'ShiftLeft'
+    WHEN 'shift_right' THEN 'ShiftRight'
+    WHEN 'space' THEN 'Space'
+    WHEN 'return' THEN 'Enter'
+    WHEN 'backspace' THEN 'Backspace'
+    WHEN 'tab' THEN 'Tab'
+    WHEN 'escape' THEN 'Escape'
+END
+WHERE key_id IS NULL;
+
+CREATE INDEX IF NOT EXISTS idx_events_key_id ON events(key_id);
@@ -0,0 +1,8 @@
+-- 008_logical_key.sql
+-- The character a key typed under the layout configured when it was recorded.
+-- key_name and key_id describe the physical key, which differs from the character
+-- on anything but QWERTY. Older rows were recorded as if on QWERTY.
+ALTER TABLE events ADD COLUMN logical_key TEXT;
+
+UPDATE events SET logical_key = key_name
+WHERE key_id LIKE 'Key_' OR key_id LIKE 'Digit_';
@@ -0,0 +1,7 @@
+-- 009_typed_text.sql
+-- The text a key press produced, NFC normalised, so accented letters from dead keys,
+-- compose sequences and IME input are kept. char_class is the broad Unicode class of
+-- its first character (letter, mark, number, punctuation, symbol or whitespace).
+-- Older rows did not record the text.
+ALTER TABLE events ADD COLUMN typed_text TEXT;
+ALTER TABLE events ADD COLUMN char_class TEXT;
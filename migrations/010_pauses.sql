@@ -0,0 +1,11 @@
+-- 010_pauses.sql
+-- Stretches of time capture was paused, so they show as gaps rather than idle time
+CREATE TABLE IF NOT EXISTS pauses (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER NOT NULL,
+    paused_by TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_pauses_started_at
+ON pauses(started_at);
@@ -0,0 +1,19 @@
+-- 011_applications.sql
+-- The application focused when a key was pressed, and optionally its window title with
+-- anything that looks personal redacted. Older rows did not record either.
+CREATE TABLE IF NOT EXISTS applications (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE
+);
+
+ALTER TABLE events ADD COLUMN application_id INTEGER REFERENCES applications(id);
+ALTER TABLE events ADD COLUMN window_title TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_events_application
+ON events(application_id);
+
+DROP VIEW IF EXISTS today_events;
+
+CREATE VIEW IF NOT EXISTS today_events AS
+SELECT id, event_timestamp, key_name, dwell_ms, is_repeat, application_id FROM events
+WHERE date(event_timestamp / 1000, 'unixepoch') = date('now', 'utc');
@@ -0,0 +1,27 @@
+-- 012_sessions.sql
+-- Stretches of typing without an idle gap longer than daemon.idle_threshold_secs,
+-- extended as keystrokes are written. Existing history is split at the default of
+-- five minutes, `metmac rebuild-sessions` redoes it for another threshold.
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER NOT NULL,
+    event_count INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_started_at
+ON sessions(started_at);
+
+INSERT INTO sessions (started_at, ended_at, event_count)
+SELECT MIN(event_timestamp), MAX(event_timestamp), COUNT(*)
+FROM (
+    SELECT event_timestamp, SUM(is_start) OVER (ORDER BY event_timestamp) AS session
+    FROM (
+        SELECT
+            event_timestamp,
+            COALESCE(event_timestamp - LAG(event_timestamp) OVER (ORDER BY event_timestamp)
+                > 300000, 1) AS is_start
+        FROM events
+    )
+)
+GROUP BY session;
@@ -0,0 +1,4 @@
+-- 013_drop_today_events.sql
+-- "Today" depends on the time zone asked for, which SQLite cannot tell, so queries take
+-- explicit bounds computed in the server and this UTC-only view goes
+DROP VIEW IF EXISTS today_events;
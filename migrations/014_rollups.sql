@@ -0,0 +1,64 @@
+-- 014_rollups.sql
+-- Keystrokes counted per minute, hour and day, so long ranges read a few rows per key
+-- rather than every event. bucket_ms tells the three apart, buckets start on UTC
+-- boundaries. Repeats are not counted but do move first_ts and last_ts. Kept up to date
+-- as events are written, `metmac rebuild-rollups` fills them in again from the events.
+CREATE TABLE IF NOT EXISTS rollups (
+    bucket_ms INTEGER NOT NULL,
+    bucket_start INTEGER NOT NULL,
+    key_name TEXT NOT NULL,
+    -- key_id, or key_name for keystrokes recorded without one
+    physical_key TEXT NOT NULL,
+    -- logical_key, or key_name for keystrokes recorded without one
+    logical_key TEXT NOT NULL,
+    -- 0 when no application was recorded
+    application_id INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    first_ts INTEGER NOT NULL,
+    last_ts INTEGER NOT NULL,
+    PRIMARY KEY (bucket_ms, bucket_start, key_name, physical_key, logical_key, application_id)
+) WITHOUT ROWID;
+
+INSERT INTO rollups
+SELECT
+    60000,
+    event_timestamp / 60000 * 60000,
+    key_name,
+    COALESCE(key_id, key_name),
+    COALESCE(logical_key, key_name),
+    COALESCE(application_id, 0),
+    SUM(is_repeat = 0),
+    MIN(event_timestamp),
+    MAX(event_timestamp)
+FROM events
+GROUP BY 2, 3, 4, 5, 6;
+
+INSERT INTO rollups
+SELECT
+    3600000,
+    bucket_start / 3600000 * 3600000,
+    key_name,
+    physical_key,
+    logical_key,
+    application_id,
+    SUM(count),
+    MIN(first_ts),
+    MAX(last_ts)
+FROM rollups
+WHERE bucket_ms = 60000
+GROUP BY 2, 3, 4, 5, 6;
+
+INSERT INTO rollups
+SELECT
+    86400000,
+    bucket_start / 86400000 * 86400000,
+    key_name,
+    physical_key,
+    logical_key,
+    application_id,
+    SUM(count),
+    MIN(first_ts),
+    MAX(last_ts)
+FROM rollups
+WHERE bucket_ms = 3600000
+GROUP BY 2, 3, 4, 5, 6;
@@ -0,0 +1,16 @@
+-- 015_keystroke_rows.sql
+-- Every keystroke as a rollup row of its own. Ranges starting or ending within a minute
+-- read the keystrokes in that part of a minute from here, and the whole minutes, hours or
+-- days in between from the rollups, so bounds stay exact.
+CREATE VIEW IF NOT EXISTS keystroke_rows AS
+SELECT
+    0 as bucket_ms,
+    event_timestamp as bucket_start,
+    key_name,
+    COALESCE(key_id, key_name) as physical_key,
+    COALESCE(logical_key, key_name) as logical_key,
+    COALESCE(application_id, 0) as application_id,
+    is_repeat = 0 as count,
+    event_timestamp as first_ts,
+    event_timestamp as last_ts
+FROM events;
@@ -1,63 +1,356 @@
-use metmac::input::keyboard::handle_keyboard_event;
-use metmac::storage::{buffer::KeyEventBuffer, connection::Database};
+use metmac::config::{expand_home, CommonArgs, Config, InputConfig, PrivacyConfig};
+use metmac::control::{
+    ControlCommand, ControlResponse, ControlSignals, ControlSocket, DaemonStatus,
+};
+use metmac::input::source::{self, CaptureHandle, InputSourceKind, Recorder};
+use metmac::input::{focus, InputProcessor, ProcessorSettings};
+use metmac::pipeline::{self, PipelineHandle};
+use metmac::privacy::{PrivacyLevel, Scrambler};
+use metmac::shutdown::shutdown_signal;
+use metmac::storage::{
+    buffer::{FlushOutcome, KeyEventBuffer},
+    connection::Database,
+    lock::InstanceLock,
+    spool::Spool,
+};
 
 use anyhow::Result;
+use clap::Parser;
 use env_logger::init;
 
-use log::info;
-use rdev::{listen, Event};
-use std::process::exit;
-use std::sync::Arc;
-use std::{path::PathBuf, time::Duration};
-use tokio::sync::Mutex;
+use log::{error, info, warn};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Records keystrokes into the MetMac database
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Number of buffered events that triggers a flush
+    #[arg(long)]
+    flush_threshold: Option<usize>,
+
+    /// Maximum number of seconds events are buffered before being flushed
+    #[arg(long)]
+    flush_interval: Option<u64>,
+
+    /// Where input events come from
+    #[arg(long, value_enum)]
+    source: Option<InputSourceKind>,
+
+    /// Input device to read with the evdev source, may be given several times
+    #[arg(long = "evdev-device", value_name = "PATH")]
+    evdev_devices: Vec<PathBuf>,
+
+    /// Replay a recorded event log instead of capturing live input
+    #[arg(long, conflicts_with = "source")]
+    replay: Option<PathBuf>,
+
+    /// Also write every raw event to this file, for later use with --replay
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// How much of each keystroke is stored
+    #[arg(long, value_enum)]
+    privacy: Option<PrivacyLevel>,
+}
+
+impl Args {
+    /// Resolves the config with these flags taking precedence, also on reload
+    fn load_config(&self) -> Result<Config> {
+        let mut config = self.common.load_config()?;
+        if let Some(flush_threshold) = self.flush_threshold {
+            config.daemon.flush_threshold = flush_threshold;
+        }
+        if let Some(flush_interval) = self.flush_interval {
+            config.daemon.flush_interval_secs = flush_interval;
+        }
+        if let Some(source) = self.source {
+            config.input.source = source;
+        }
+        if !self.evdev_devices.is_empty() {
+            config.input.evdev_devices = self.evdev_devices.clone();
+        }
+        if let Some(privacy) = self.privacy {
+            config.privacy.level = privacy;
+        }
+        if let Some(replay) = &self.replay {
+            config.input.source = InputSourceKind::Replay;
+            config.input.replay_path = Some(replay.clone());
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     init(); // Init env logger
 
-    // TODO: Have some configuration logic, for path and flush
-    let db_path = PathBuf::from("~/.metmac/data.db");
-    let db = Database::new(db_path).await?;
+    let started = Instant::now();
+    let args = Args::parse();
+    let config = args.load_config()?;
+    // Two daemons on one database would record every keystroke twice
+    let _lock = InstanceLock::acquire(&config.database.path)?;
+
+    let input_source = source::from_config(&config.input)?;
+    let settings = ProcessorSettings::from_config(&config)?;
+    info!(
+        "Recording characters for the {} layout",
+        settings.layout.name
+    );
+    let recorder = args.record.as_deref().map(Recorder::create).transpose()?;
+    if recorder.is_some() && config.privacy.level != PrivacyLevel::Full {
+        warn!(
+            "--record writes every raw event, ignoring the {:?} privacy level",
+            config.privacy.level
+        );
+    }
+
+    let db = Database::new(config.database.path.clone())
+        .await?
+        .with_idle_threshold(Duration::from_secs(config.daemon.idle_threshold_secs));
     db.run_migrations().await?;
 
-    let flush_threshold = 30; // events
-    let flush_interval = 3; // seconds
+    let spool = Spool::open(&config.database.spool_path, config.database.spool_max_bytes)?;
+    let mut buffer = KeyEventBuffer::new(
+        db,
+        config.daemon.flush_threshold,
+        Duration::from_secs(config.daemon.flush_interval_secs),
+    )
+    .with_spool(spool);
+    if let Some(bucket) = config.privacy.timestamp_bucket_secs {
+        info!("Scrambling keystroke order within {}s buckets", bucket);
+        buffer = buffer.with_scrambler(Scrambler::new(Duration::from_secs(bucket)));
+    }
 
-    let buffer = KeyEventBuffer::new(db, flush_threshold, Duration::from_secs(flush_interval));
+    // Events left over from a previous run that could not reach the database
+    if let Err(e) = buffer.replay_spool().await {
+        warn!(
+            "Failed to replay spooled events, will retry on next flush: {:?}",
+            e
+        );
+    }
 
-    let buffer_arc = Arc::new(Mutex::new(buffer));
+    let (sender, writer, pipeline) = pipeline::channel(
+        config.daemon.channel_capacity,
+        config.daemon.backpressure,
+        config.daemon.spill_path.clone(),
+        buffer,
+    )?;
+    let writer_handle = tokio::spawn(writer.run());
 
     info!("Starting MetMac...");
-    let listener_handle = tokio::task::spawn_blocking(move || {
-        if let Err(e) = listen(move |event| {
-            let buffer_arc_clone = buffer_arc.clone();
-            tokio::spawn(async move {
-                callback(event, buffer_arc_clone).await;
-            });
-        }) {
-            info!("Error listening to events: {:?}", e);
+
+    info!(
+        "Storing keystrokes at the {:?} privacy level",
+        config.privacy.level
+    );
+    if let Some(hotkey) = &settings.pause_hotkey {
+        info!("Press {} to pause and resume recording", hotkey);
+    }
+    let mut processor = InputProcessor::from_settings(settings);
+    if let Some(focus) = focus::from_config(&config.input)? {
+        processor = processor.with_focus(focus, config.input.window_titles);
+    }
+    let (capture, mut capture_finished) =
+        source::spawn_capture(input_source, processor, sender, recorder);
+
+    let mut control_signals = ControlSignals::new()?;
+    let mut control_socket =
+        ControlSocket::bind(&expand_home(&config.daemon.control_socket)?).await?;
+    let mut control = Control {
+        args: &args,
+        config,
+        capture: capture.clone(),
+        pipeline: pipeline.clone(),
+        started,
+    };
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    // Mouse activity is held until more input arrives, so hand it to the writer as often
+    // as it flushes
+    let mut ticks = tokio::time::interval(Duration::from_secs(
+        control.config.daemon.flush_interval_secs,
+    ));
+
+    let mut exit_code = ExitCode::SUCCESS;
+
+    loop {
+        let resume_at = capture.resume_at();
+
+        tokio::select! {
+            signal = &mut shutdown => {
+                info!("Received {}, shutting down", signal?);
+                // Live sources keep running, so end a pause and send pending activity now
+                capture.finish().await;
+                break;
+            }
+            result = &mut capture_finished => {
+                match result {
+                    // Replay and synthetic sources run out, which is a normal way to finish
+                    Ok(Ok(())) => info!("Input source finished, shutting down"),
+                    Ok(Err(e)) => {
+                        error!("Input source failed: {:?}", e);
+                        exit_code = ExitCode::FAILURE;
+                    }
+                    Err(_) => {
+                        error!("Input thread exited unexpectedly");
+                        exit_code = ExitCode::FAILURE;
+                    }
+                }
+                break;
+            }
+            command = control_signals.recv() => {
+                if control.handle(command).await == ControlResponse::done(false) {
+                    info!("Ignoring {:?}, nothing to do", command);
+                }
+            }
+            (command, reply) = control_socket.recv() => {
+                if command == ControlCommand::Shutdown {
+                    info!("Shutdown requested on the control socket");
+                    let _ = reply.send(ControlResponse::done(true));
+                    capture.finish().await;
+                    break;
+                }
+                let _ = reply.send(control.handle(command).await);
+            }
+            _ = ticks.tick() => capture.tick().await,
+            // Without input nothing else notices that a pause has timed out
+            _ = sleep_until(resume_at) => capture.expire().await,
         }
-    });
+    }
 
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            info!("Received Ctrl-C, shutting down");
-            exit(0);
+    // Stop accepting new events, the writer then drains the queue and the buffer
+    pipeline.close();
+    match writer_handle.await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("Failed to flush buffered events on shutdown: {:?}", e);
+            exit_code = ExitCode::FAILURE;
         }
-        _ = listener_handle => {
-            info!("Listener task completed");
+        Err(e) => {
+            error!("Writer task failed: {:?}", e);
+            exit_code = ExitCode::FAILURE;
         }
     }
 
+    drop(control_socket);
     info!("Exiting");
-    Ok(())
+    Ok(exit_code)
+}
+
+/// What control commands act on
+struct Control<'a> {
+    args: &'a Args,
+    /// The config in effect, with reloaded settings applied
+    config: Config,
+    capture: CaptureHandle,
+    pipeline: PipelineHandle,
+    started: Instant,
+}
+
+impl Control<'_> {
+    /// Runs every command but shutdown, which ends the main loop
+    async fn handle(&mut self, command: ControlCommand) -> ControlResponse {
+        let result = match command {
+            ControlCommand::Status => self.status().await.map(ControlResponse::Status),
+            ControlCommand::Flush => self.pipeline.flush_now().await.map(|outcome| {
+                let note = (outcome == FlushOutcome::Spooled).then(|| {
+                    "The database is unavailable, events were spooled to write later".to_string()
+                });
+                ControlResponse::Done {
+                    changed: true,
+                    note,
+                }
+            }),
+            ControlCommand::ReloadConfig => self.reload_config().await,
+            ControlCommand::Pause
+            | ControlCommand::Resume
+            | ControlCommand::Toggle
+            | ControlCommand::Shutdown => {
+                Ok(ControlResponse::done(command.apply(&self.capture).await))
+            }
+        };
+
+        result.unwrap_or_else(|e| {
+            warn!("Control command {:?} failed: {:?}", command, e);
+            ControlResponse::error(e)
+        })
+    }
+
+    async fn status(&self) -> Result<DaemonStatus> {
+        let resume_at = self.capture.resume_at().map(|time| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+
+        Ok(DaemonStatus {
+            pid: std::process::id(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            source: self.config.input.source,
+            privacy: self.config.privacy.level,
+            paused: self.capture.is_paused(),
+            resume_at,
+            buffer: self.pipeline.buffer_status().await?,
+            pipeline: self.pipeline.stats(),
+        })
+    }
+
+    /// Applies the keymap, layout, privacy level and pause settings from the config file,
+    /// anything else only changes on restart
+    async fn reload_config(&mut self) -> Result<ControlResponse> {
+        let config = self.args.load_config()?;
+        self.capture
+            .apply_settings(ProcessorSettings::from_config(&config)?)
+            .await;
+        info!(
+            "Reloaded config, storing keystrokes at the {:?} privacy level",
+            config.privacy.level
+        );
+
+        let applied = Config {
+            input: InputConfig {
+                keymap_path: config.input.keymap_path.clone(),
+                layout: config.input.layout.clone(),
+                pause_hotkey: config.input.pause_hotkey.clone(),
+                pause_timeout_secs: config.input.pause_timeout_secs,
+                ..self.config.input.clone()
+            },
+            privacy: PrivacyConfig {
+                level: config.privacy.level,
+                ..self.config.privacy.clone()
+            },
+            ..self.config.clone()
+        };
+        let note = (applied != config)
+            .then(|| "Some changed settings only take effect after a restart".to_string());
+        if let Some(note) = &note {
+            warn!("{}", note);
+        }
+        self.config = applied;
+
+        Ok(ControlResponse::Done {
+            changed: true,
+            note,
+        })
+    }
 }
 
-async fn callback(event: Event, buffer_arc: Arc<Mutex<KeyEventBuffer>>) {
-    if let Some(key_event) = handle_keyboard_event(&event) {
-        let mut buffer = buffer_arc.lock().await;
-        if let Err(e) = buffer.push(key_event).await {
-            info!("Error pushing event to buffer: {:?}", e);
+/// Sleeps until the wall clock reaches `time`, forever if `None`
+async fn sleep_until(time: Option<SystemTime>) {
+    match time {
+        Some(time) => {
+            let wait = time.duration_since(SystemTime::now()).unwrap_or_default();
+            tokio::time::sleep(wait).await
         }
+        None => std::future::pending().await,
     }
 }
@@ -0,0 +1,266 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use env_logger::init;
+use log::warn;
+use metmac::config::{expand_home, CommonArgs, Config};
+use metmac::control::{self, ControlCommand, ControlResponse};
+use metmac::input::keymap::KeyMap;
+use metmac::privacy::PrivacyLevel;
+use metmac::storage::connection::Database;
+use metmac::storage::spool::Spool;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Maintenance commands for the MetMac database
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List key codes stored as `unknown_<code>`, so they can be named in a keymap file
+    UnknownKeys,
+
+    /// Rename stored `unknown_<code>` keys that the keymap now has a name for
+    BackfillKeys {
+        /// Keymap override file [default: input.keymap_path]
+        #[arg(long)]
+        keymap: Option<PathBuf>,
+
+        /// Only report what would be renamed
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Split stored keystrokes into typing sessions again, after daemon.idle_threshold_secs
+    /// changed
+    RebuildSessions,
+
+    /// Count stored keystrokes into the minute, hour and day rollups again
+    RebuildRollups,
+
+    /// Send a command to the running daemon
+    Ctl {
+        /// Control socket of the daemon [default: daemon.control_socket]
+        #[arg(long)]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: ControlCommand,
+    },
+
+    /// Reduce stored keystrokes and shortcuts to a privacy level, this cannot be undone
+    Downgrade {
+        /// Privacy level to keep [default: privacy.level]
+        #[arg(long, value_enum)]
+        level: Option<PrivacyLevel>,
+
+        /// Only report what would be changed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init(); // Init env logger
+
+    let args = Args::parse();
+    let config = args.common.load_config()?;
+
+    // Talks to the daemon only, which may hold the database
+    if let Command::Ctl { socket, command } = args.command {
+        let socket = socket.unwrap_or(config.daemon.control_socket);
+        return ctl(&expand_home(&socket)?, command).await;
+    }
+
+    let db = Database::new(config.database.path.clone())
+        .await?
+        .with_idle_threshold(Duration::from_secs(config.daemon.idle_threshold_secs));
+    db.run_migrations().await?;
+
+    let result = match args.command {
+        Command::UnknownKeys => unknown_keys(&db).await,
+        Command::BackfillKeys { keymap, dry_run } => {
+            backfill_keys(&db, &config, keymap, dry_run).await
+        }
+        Command::Downgrade { level, dry_run } => {
+            downgrade(&db, &config, level.unwrap_or(config.privacy.level), dry_run).await
+        }
+        Command::RebuildSessions => rebuild_sessions(&db, &config).await,
+        Command::RebuildRollups => rebuild_rollups(&db).await,
+        Command::Ctl { .. } => unreachable!("handled before opening the database"),
+    };
+
+    db.close().await;
+    result
+}
+
+async fn ctl(socket: &Path, command: ControlCommand) -> Result<()> {
+    match control::send(socket, command).await? {
+        ControlResponse::Done { changed, note } => {
+            if !changed {
+                println!("Nothing to do");
+            }
+            if let Some(note) = note {
+                println!("{}", note);
+            }
+        }
+        ControlResponse::Status(status) => {
+            let state = match status.resume_at {
+                Some(resume_at) => {
+                    let resume_at = DateTime::<Utc>::from_timestamp(resume_at as i64, 0)
+                        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                        .unwrap_or_default();
+                    format!("paused until {}", resume_at)
+                }
+                None if status.paused => "paused".to_string(),
+                None => "recording".to_string(),
+            };
+
+            println!("metmac daemon {} (pid {})", status.version, status.pid);
+            println!("state:       {}", state);
+            println!("uptime:      {}s", status.uptime_secs);
+            println!("source:      {:?}", status.source);
+            println!("privacy:     {:?}", status.privacy);
+            println!("buffered:    {} events", status.buffer.buffered_events);
+            println!("last flush:  {}s ago", status.buffer.secs_since_flush);
+            println!(
+                "events:      {} received, {} dropped, {} spilled",
+                status.pipeline.received, status.pipeline.dropped, status.pipeline.spilled
+            );
+        }
+        ControlResponse::Error { message } => {
+            bail!("The daemon failed to {:?}: {}", command, message)
+        }
+    }
+
+    Ok(())
+}
+
+async fn rebuild_sessions(db: &Database, config: &Config) -> Result<()> {
+    let sessions = db.rebuild_sessions().await?;
+    println!(
+        "Split keystrokes into {} sessions at gaps over {}s",
+        sessions, config.daemon.idle_threshold_secs
+    );
+
+    Ok(())
+}
+
+async fn rebuild_rollups(db: &Database) -> Result<()> {
+    let rows = db.rebuild_rollups().await?;
+    println!("Rebuilt {} rollup rows", rows);
+
+    Ok(())
+}
+
+async fn unknown_keys(db: &Database) -> Result<()> {
+    let unknown_keys = db.get_unknown_keys().await?;
+    if unknown_keys.is_empty() {
+        println!("No unknown keys recorded");
+        return Ok(());
+    }
+
+    println!("{:>6}  {:>8}  last seen", "code", "count");
+    for key in &unknown_keys {
+        let last_seen = DateTime::<Utc>::from_timestamp_millis(key.last_seen)
+            .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        println!("{:>6}  {:>8}  {}", key.code, key.count, last_seen);
+    }
+
+    println!();
+    println!("Name them in the [unknown] section of a keymap file, then run backfill-keys:");
+    println!();
+    println!("[unknown]");
+    for key in &unknown_keys {
+        println!("{} = \"...\"", key.code);
+    }
+
+    Ok(())
+}
+
+async fn backfill_keys(
+    db: &Database,
+    config: &Config,
+    keymap: Option<PathBuf>,
+    dry_run: bool,
+) -> Result<()> {
+    let keymap_path = keymap.or_else(|| config.input.keymap_path.clone());
+    let keymap = KeyMap::load(keymap_path.as_deref())?;
+
+    let mut renamed = 0;
+    for key in db.get_unknown_keys().await? {
+        let Some(name) = keymap.unknown_name(key.code) else {
+            continue;
+        };
+
+        let from = format!("unknown_{}", key.code);
+        if dry_run {
+            println!("{} -> {} ({} events)", from, name, key.count);
+            renamed += key.count as u64;
+        } else {
+            let count = db.rename_key(&from, name).await?;
+            println!("{} -> {} ({} events)", from, name, count);
+            renamed += count;
+        }
+    }
+
+    if dry_run {
+        println!("Would rename {} events", renamed);
+    } else {
+        println!("Renamed {} events", renamed);
+    }
+
+    Ok(())
+}
+
+async fn downgrade(
+    db: &Database,
+    config: &Config,
+    level: PrivacyLevel,
+    dry_run: bool,
+) -> Result<()> {
+    if level == PrivacyLevel::Full {
+        println!("The full level keeps everything, nothing to downgrade");
+        return Ok(());
+    }
+
+    // Events waiting on disk were captured at whatever level the daemon ran with
+    let spool = Spool::open(&config.database.spool_path, config.database.spool_max_bytes)?;
+    if !spool.is_empty()? {
+        warn!(
+            "{:?} holds events not yet in the database, they are not downgraded",
+            spool.path()
+        );
+    }
+    let spill_path = expand_home(&config.daemon.spill_path)?;
+    if fs::metadata(&spill_path).is_ok_and(|metadata| metadata.len() > 0) {
+        warn!(
+            "{:?} holds events not yet in the database, they are not downgraded",
+            spill_path
+        );
+    }
+
+    let summary = db.downgrade_privacy(level, dry_run).await?;
+    let verb = if dry_run { "Would change" } else { "Changed" };
+    println!(
+        "{} {} keystrokes and {} shortcuts to the {:?} level",
+        verb, summary.keys, summary.shortcuts, level
+    );
+    if summary.window_titles > 0 {
+        let verb = if dry_run { "Would remove" } else { "Removed" };
+        println!("{} {} window titles", verb, summary.window_titles);
+    }
+
+    Ok(())
+}
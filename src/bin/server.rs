@@ -1,30 +1,52 @@
-use std::path::PathBuf;
-
 use anyhow::Result;
 use axum::{
-    extract::State,
+    extract::{rejection::QueryRejection, Query, State},
     http::StatusCode,
     response::Response,
     response::{Html, IntoResponse, Json},
     routing::get,
     serve, Router,
 };
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use clap::Parser;
 use env_logger::init;
+use log::info;
+use metmac::config::CommonArgs;
+use metmac::input::layout::Layout;
+use metmac::models::range::{parse_zone, Bucket, TimeRange};
+use metmac::models::stats::{ActivitySummary, KeyView};
 use metmac::storage::connection::Database;
+use serde::Deserialize;
 use serde_json::json;
+use std::sync::Arc;
 
 enum AppError {
+    /// The request's parameters were missing or invalid
+    BadRequest(String),
     DatabaseError(String),
 }
 
+impl AppError {
+    fn bad_request(error: anyhow::Error) -> Self {
+        AppError::BadRequest(format!("{:#}", error))
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        match self {
-            AppError::DatabaseError(message) => {
-                let payload = json!({"error": message});
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(payload)).into_response()
-            }
-        }
+        let (status, message) = match self {
+            AppError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::DatabaseError(message) => (StatusCode::INTERNAL_SERVER_ERROR, message),
+        };
+
+        (status, Json(json!({"error": message}))).into_response()
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        AppError::BadRequest(rejection.body_text())
     }
 }
 
@@ -34,23 +56,67 @@ impl From<anyhow::Error> for AppError {
     }
 }
 
+/// What the data handlers share
+#[derive(Clone)]
+struct AppState {
+    db: Database,
+    /// Zone days start in when a request does not name one
+    zone: Tz,
+}
+
+/// Serves the MetMac dashboard and API
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Address the HTTP server listens on
+    #[arg(long)]
+    bind: Option<String>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     init(); // Init env logger
 
-    // TODO: Make configurable path
-    let db = Database::new(PathBuf::from("~/.metmac/data.db")).await?;
+    let args = Args::parse();
+    let mut config = args.common.load_config()?;
+    if let Some(bind) = args.bind {
+        config.server.bind_address = bind;
+    }
+    config.validate()?;
+
+    let db = Database::new(config.database.path.clone()).await?;
     db.run_migrations().await?;
 
+    let zone = config.server.zone()?;
+    info!("Days start at midnight in {}", zone.name());
+    let state = AppState { db, zone };
+
+    // The dashboard labels keys with the characters of the configured layout
+    let layout = Layout::resolve(&config.input.layout)?;
+
     let app = Router::new()
         .route("/", get(serve_dashboard))
-        .route("/api/stats", get(get_stats).with_state(db.clone()))
+        .route("/api/stats", get(get_stats).with_state(state.clone()))
         .route(
             "/api/keyboard-stats",
-            get(get_keyboard_stats).with_state(db),
-        );
+            get(get_keyboard_stats).with_state(state.clone()),
+        )
+        .route("/api/hourly", get(get_hourly).with_state(state.clone()))
+        .route("/api/layout", get(get_layout).with_state(Arc::new(layout)))
+        .route(
+            "/api/shortcuts",
+            get(get_shortcuts).with_state(state.clone()),
+        )
+        .route("/api/pauses", get(get_pauses).with_state(state.clone()))
+        .route("/api/sessions", get(get_sessions).with_state(state.clone()))
+        .route("/api/activity", get(get_activity).with_state(state.clone()))
+        .route("/api/mouse-stats", get(get_mouse_stats).with_state(state));
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3004").await?;
+    info!("Serving dashboard on {}", config.server.bind_address);
+    let listener = tokio::net::TcpListener::bind(&config.server.bind_address).await?;
     serve(listener, app).await?;
 
     Ok(())
@@ -60,16 +126,207 @@ async fn serve_dashboard() -> Html<&'static str> {
     Html(include_str!("../../static/index.html"))
 }
 
-async fn get_stats(State(db): State<Database>) -> impl IntoResponse {
-    match db.get_stats().await {
-        Ok(stats) => Json(stats).into_response(),
-        Err(err) => AppError::from(err).into_response(),
+/// Time zone a request counts days in
+#[derive(Deserialize)]
+struct ZoneQuery {
+    /// IANA name such as `Europe/Berlin`, the configured zone when left out
+    tz: Option<String>,
+}
+
+impl ZoneQuery {
+    fn resolve(&self, default: Tz) -> Result<Tz, AppError> {
+        match &self.tz {
+            Some(name) => parse_zone(name).map_err(AppError::bad_request),
+            None => Ok(default),
+        }
+    }
+}
+
+/// Time range and grouping shared by the stats endpoints
+#[derive(Deserialize)]
+struct RangeQuery {
+    /// Start of the range, in milliseconds since the epoch, RFC 3339 or `YYYY-MM-DD`
+    from: Option<String>,
+    /// End of the range, exclusive
+    to: Option<String>,
+    /// Also count per minute, hour, day, week or month
+    bucket: Option<Bucket>,
+}
+
+impl RangeQuery {
+    /// The range asked for, `default` filling in missing bounds and dates starting at
+    /// midnight in `zone`
+    fn resolve(&self, default: TimeRange, zone: Tz) -> Result<TimeRange, AppError> {
+        let range = TimeRange::parse(self.from.as_deref(), self.to.as_deref(), default, zone)
+            .map_err(AppError::bad_request)?;
+        if let Some(bucket) = self.bucket {
+            range.check_buckets(bucket).map_err(AppError::bad_request)?;
+        }
+
+        Ok(range)
+    }
+}
+
+async fn get_stats(
+    State(state): State<AppState>,
+    Query(zone): Query<ZoneQuery>,
+    query: Result<Query<RangeQuery>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let Query(query) = query?;
+    let zone = zone.resolve(state.zone)?;
+    let range = query.resolve(TimeRange::today(zone), zone)?;
+
+    let mut stats = state.db.get_stats(range).await?;
+    if let Some(bucket) = query.bucket {
+        stats.buckets = Some(state.db.get_keystroke_buckets(range, bucket, zone).await?);
     }
+
+    Ok(Json(stats).into_response())
+}
+
+#[derive(Deserialize)]
+struct KeyboardStatsQuery {
+    /// Group keystrokes by display name, physical key or typed character
+    #[serde(default)]
+    view: KeyView,
+    #[serde(flatten)]
+    range: RangeQuery,
+}
+
+async fn get_keyboard_stats(
+    State(state): State<AppState>,
+    Query(zone): Query<ZoneQuery>,
+    query: Result<Query<KeyboardStatsQuery>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let Query(query) = query?;
+    let zone = zone.resolve(state.zone)?;
+    let range = query.range.resolve(TimeRange::all(), zone)?;
+
+    let response = match query.range.bucket {
+        Some(bucket) => Json(
+            state
+                .db
+                .get_keyboard_buckets(query.view, range, bucket, zone)
+                .await?,
+        )
+        .into_response(),
+        None => Json(state.db.get_keyboard_stats(query.view, range).await?).into_response(),
+    };
+
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct HourlyQuery {
+    /// Day to count per hour, `YYYY-MM-DD`, today when left out
+    date: Option<String>,
+    /// With `to`, count by weekday and hour over this range instead
+    from: Option<String>,
+    to: Option<String>,
 }
 
-async fn get_keyboard_stats(State(db): State<Database>) -> impl IntoResponse {
-    match db.get_keyboard_stats().await {
-        Ok(stats) => Json(stats).into_response(),
-        Err(err) => AppError::from(err).into_response(),
+async fn get_hourly(
+    State(state): State<AppState>,
+    Query(zone): Query<ZoneQuery>,
+    query: Result<Query<HourlyQuery>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let Query(query) = query?;
+    let zone = zone.resolve(state.zone)?;
+
+    if query.from.is_some() || query.to.is_some() {
+        if query.date.is_some() {
+            return Err(AppError::BadRequest(
+                "date cannot be combined with from and to".to_string(),
+            ));
+        }
+
+        let range = TimeRange::parse(
+            query.from.as_deref(),
+            query.to.as_deref(),
+            TimeRange::all(),
+            zone,
+        )
+        .map_err(AppError::bad_request)?;
+        return Ok(Json(state.db.get_weekday_hours(range, zone).await?).into_response());
     }
+
+    let day = match &query.date {
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|e| AppError::BadRequest(format!("Invalid date {:?}: {}", date, e)))
+            .and_then(|date| TimeRange::day(date, zone).map_err(AppError::bad_request))?,
+        None => TimeRange::today(zone),
+    };
+
+    Ok(Json(state.db.get_hourly(day, zone).await?).into_response())
+}
+
+async fn get_layout(State(layout): State<Arc<Layout>>) -> impl IntoResponse {
+    Json(layout.as_ref().clone())
+}
+
+async fn get_mouse_stats(
+    State(state): State<AppState>,
+    Query(zone): Query<ZoneQuery>,
+) -> Result<Response, AppError> {
+    let today = TimeRange::today(zone.resolve(state.zone)?);
+    Ok(Json(state.db.get_mouse_stats(today).await?).into_response())
+}
+
+async fn get_pauses(
+    State(state): State<AppState>,
+    Query(zone): Query<ZoneQuery>,
+) -> Result<Response, AppError> {
+    let today = TimeRange::today(zone.resolve(state.zone)?);
+    Ok(Json(state.db.get_pauses(today).await?).into_response())
+}
+
+async fn get_sessions(
+    State(state): State<AppState>,
+    Query(zone): Query<ZoneQuery>,
+) -> Result<Response, AppError> {
+    let today = TimeRange::today(zone.resolve(state.zone)?);
+    Ok(Json(state.db.get_sessions(today).await?).into_response())
+}
+
+async fn get_activity(
+    State(state): State<AppState>,
+    Query(zone): Query<ZoneQuery>,
+) -> Result<Response, AppError> {
+    let today = TimeRange::today(zone.resolve(state.zone)?);
+    let sessions = state.db.get_sessions(today).await?;
+    Ok(Json(ActivitySummary::from_sessions(&sessions)).into_response())
+}
+
+#[derive(Deserialize)]
+struct ShortcutsQuery {
+    /// Number of days to report on, including today
+    #[serde(default = "default_shortcut_days")]
+    days: u32,
+    /// Maximum number of shortcuts listed per day
+    #[serde(default = "default_shortcut_limit")]
+    limit: usize,
+}
+
+fn default_shortcut_days() -> u32 {
+    7
+}
+
+fn default_shortcut_limit() -> usize {
+    10
+}
+
+async fn get_shortcuts(
+    State(state): State<AppState>,
+    Query(zone): Query<ZoneQuery>,
+    query: Result<Query<ShortcutsQuery>, QueryRejection>,
+) -> Result<Response, AppError> {
+    let Query(query) = query?;
+    let zone = zone.resolve(state.zone)?;
+    let range = TimeRange::last_days(query.days, zone).map_err(AppError::bad_request)?;
+    let shortcuts = state
+        .db
+        .get_daily_shortcuts(range, query.limit, zone)
+        .await?;
+
+    Ok(Json(shortcuts).into_response())
 }
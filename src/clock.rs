@@ -0,0 +1,69 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Source of the current time, so time based behaviour can be tested without sleeping
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The current wall clock time, for comparing against event timestamps
+    fn wall_time(&self) -> SystemTime;
+}
+
+/// Clock backed by the system's monotonic clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that only moves when told to
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    wall_start: SystemTime,
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::at(SystemTime::now())
+    }
+
+    /// A clock whose wall time starts at `wall_start`
+    pub fn at(wall_start: SystemTime) -> Self {
+        let start = Instant::now();
+        Self {
+            start,
+            wall_start,
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.wall_start + (self.now() - self.start)
+    }
+}
@@ -0,0 +1,488 @@
+use anyhow::{bail, Context, Result};
+use chrono_tz::Tz;
+use clap::Args;
+use directories::BaseDirs;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use crate::input::focus::FocusSourceKind;
+use crate::input::modifiers::normalize_combo;
+use crate::input::source::InputSourceKind;
+use crate::models::range::{local_zone, parse_zone};
+use crate::pipeline::BackpressurePolicy;
+use crate::privacy::PrivacyLevel;
+
+/// Runtime configuration shared by the daemon and the server.
+///
+/// Values are resolved in order of precedence (last wins):
+/// built-in defaults, the TOML config file, `METMAC_*` environment variables,
+/// and finally command line flags.
+///
+/// ```toml
+/// [database]
+/// path = "~/.metmac/data.db"
+/// spool_path = "~/.metmac/spool.bin"
+/// spool_max_bytes = 67108864
+///
+/// [daemon]
+/// flush_threshold = 30
+/// flush_interval_secs = 3
+/// channel_capacity = 1024
+/// backpressure = "block"         # or "drop_oldest", "spill"
+/// spill_path = "~/.metmac/spill.jsonl"
+/// control_socket = "~/.metmac/control.sock"
+/// idle_threshold_secs = 300
+///
+/// [input]
+/// source = "rdev"                # or "evdev", "replay", "synthetic"
+/// evdev_devices = ["/dev/input/event3"]
+/// keymap_path = "~/.config/metmac/keymap.toml"
+/// layout = "qwerty"              # or "dvorak", "colemak", "azerty", a layout file
+/// replay_path = "~/events.jsonl"
+/// replay_realtime = false
+/// synthetic_text = "hello world"
+/// pause_hotkey = "ctrl+alt+p"
+/// pause_timeout_secs = 1800
+/// focus = "auto"                 # or "none", "x11"
+/// window_titles = false
+///
+/// [privacy]
+/// level = "full"                 # or "key_class", "counts"
+/// timestamp_bucket_secs = 300
+///
+/// [server]
+/// bind_address = "0.0.0.0:3004"
+/// timezone = "Europe/Berlin"
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub daemon: DaemonConfig,
+    pub input: InputConfig,
+    pub privacy: PrivacyConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+    /// Where batches the database rejects are kept until they can be written
+    pub spool_path: PathBuf,
+    /// Upper bound on the spool size, batches beyond it are held in memory instead
+    pub spool_max_bytes: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("~/.metmac/data.db"),
+            spool_path: PathBuf::from("~/.metmac/spool.bin"),
+            spool_max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Number of buffered events that triggers a flush
+    pub flush_threshold: usize,
+    /// Maximum number of seconds events are held before being flushed
+    pub flush_interval_secs: u64,
+    /// Number of captured events that can be queued for the writer
+    pub channel_capacity: usize,
+    /// What to do with new events when the queue is full
+    pub backpressure: BackpressurePolicy,
+    /// File events overflow into when using the `spill` policy
+    pub spill_path: PathBuf,
+    /// Unix socket `metmac ctl` talks to the running daemon through
+    pub control_socket: PathBuf,
+    /// A gap between keystrokes longer than this ends a typing session
+    pub idle_threshold_secs: u64,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            flush_threshold: 30,
+            flush_interval_secs: 3,
+            channel_capacity: 1024,
+            backpressure: BackpressurePolicy::Block,
+            spill_path: PathBuf::from("~/.metmac/spill.jsonl"),
+            control_socket: PathBuf::from("~/.metmac/control.sock"),
+            idle_threshold_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    /// Where the daemon reads input events from
+    pub source: InputSourceKind,
+    /// Devices read by the `evdev` source, every keyboard and mouse when empty
+    pub evdev_devices: Vec<PathBuf>,
+    /// Extra key names layered over the bundled mapping table
+    pub keymap_path: Option<PathBuf>,
+    /// Keyboard layout, the name of a bundled layout or the path of a layout file
+    pub layout: String,
+    /// Event log read by the `replay` source
+    pub replay_path: Option<PathBuf>,
+    /// Reproduce the recorded timing instead of replaying as fast as possible
+    pub replay_realtime: bool,
+    /// Text typed by the `synthetic` source
+    pub synthetic_text: String,
+    /// Shortcut that pauses and resumes capture, e.g. `ctrl+alt+p`
+    pub pause_hotkey: Option<String>,
+    /// Resume capture by itself after being paused this many seconds
+    pub pause_timeout_secs: Option<u64>,
+    /// Where the application keystrokes go to is read from
+    pub focus: FocusSourceKind,
+    /// Also record the focused window's title, with personal details redacted
+    pub window_titles: bool,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            source: InputSourceKind::Rdev,
+            evdev_devices: Vec::new(),
+            keymap_path: None,
+            layout: "qwerty".to_string(),
+            replay_path: None,
+            replay_realtime: false,
+            synthetic_text: "the quick brown fox jumps over the lazy dog".to_string(),
+            pause_hotkey: None,
+            pause_timeout_secs: None,
+            focus: FocusSourceKind::Auto,
+            window_titles: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    /// How much of each keystroke is stored, applied before events are buffered
+    pub level: PrivacyLevel,
+    /// Store keystroke and shortcut times rounded down to buckets of this many seconds,
+    /// in random order within each bucket
+    pub timestamp_bucket_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    /// IANA time zone days start in, e.g. `Europe/Berlin`, the system's when unset
+    pub timezone: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0:3004".to_string(),
+            timezone: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The zone whose midnights split days, unless a request names another
+    pub fn zone(&self) -> Result<Tz> {
+        match &self.timezone {
+            Some(name) => parse_zone(name).context("Invalid server.timezone"),
+            None => Ok(local_zone()),
+        }
+    }
+}
+
+/// Environment variable pointing at an alternative config file
+pub const CONFIG_ENV: &str = "METMAC_CONFIG";
+
+impl Config {
+    /// Returns the default config file location, `$XDG_CONFIG_HOME/metmac/config.toml`
+    /// falling back to `~/.config/metmac/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => BaseDirs::new()?.home_dir().join(".config"),
+        };
+
+        Some(config_home.join("metmac").join("config.toml"))
+    }
+
+    /// Loads the config file (if any) and applies environment overrides.
+    ///
+    /// An explicitly provided path must exist, while a missing file at the
+    /// default location just means the defaults are used.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => match Self::default_path() {
+                Some(default) if default.exists() => Self::from_file(&default)?,
+                _ => {
+                    debug!("No config file found, using defaults");
+                    Self::default()
+                }
+            },
+        };
+
+        config.apply_env(|key| env::var(key).ok())?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        info!("Loading config from {:?}", path);
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+
+        toml::from_str(&contents).with_context(|| format!("Failed to parse config file {:?}", path))
+    }
+
+    /// Applies `METMAC_*` overrides, `lookup` resolves a variable name to its value
+    pub fn apply_env<F>(&mut self, lookup: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(path) = lookup("METMAC_DB_PATH") {
+            self.database.path = PathBuf::from(path);
+        }
+        if let Some(threshold) = lookup("METMAC_FLUSH_THRESHOLD") {
+            self.daemon.flush_threshold = parse_env("METMAC_FLUSH_THRESHOLD", &threshold)?;
+        }
+        if let Some(interval) = lookup("METMAC_FLUSH_INTERVAL") {
+            self.daemon.flush_interval_secs = parse_env("METMAC_FLUSH_INTERVAL", &interval)?;
+        }
+        if let Some(address) = lookup("METMAC_BIND_ADDRESS") {
+            self.server.bind_address = address;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.daemon.flush_threshold == 0 {
+            bail!("daemon.flush_threshold must be greater than 0");
+        }
+        if self.daemon.flush_interval_secs == 0 {
+            bail!("daemon.flush_interval_secs must be greater than 0");
+        }
+        if self.daemon.channel_capacity == 0 {
+            bail!("daemon.channel_capacity must be greater than 0");
+        }
+        if self.daemon.idle_threshold_secs == 0 {
+            bail!("daemon.idle_threshold_secs must be greater than 0");
+        }
+        if let Some(bucket) = self.privacy.timestamp_bucket_secs {
+            // Hours start on a 15 minute boundary in every time zone
+            if bucket == 0 || 900 % bucket != 0 {
+                bail!(
+                    "privacy.timestamp_bucket_secs must divide 900 (15 minutes), got {}",
+                    bucket
+                );
+            }
+            // Keystrokes in a bucket share its start, so shorter gaps cannot be told apart
+            if bucket > self.daemon.idle_threshold_secs {
+                bail!(
+                    "daemon.idle_threshold_secs ({}) must be at least \
+                     privacy.timestamp_bucket_secs ({}) to split sessions",
+                    self.daemon.idle_threshold_secs,
+                    bucket
+                );
+            }
+        }
+        if let Some(hotkey) = &self.input.pause_hotkey {
+            normalize_combo(hotkey).context("Invalid input.pause_hotkey")?;
+        }
+        if self.input.pause_timeout_secs == Some(0) {
+            bail!("input.pause_timeout_secs must be greater than 0");
+        }
+        if self.input.source == InputSourceKind::Replay && self.input.replay_path.is_none() {
+            bail!("input.replay_path is required for the replay source");
+        }
+        if let Some(name) = &self.server.timezone {
+            parse_zone(name).context("Invalid server.timezone")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Expands a leading `~` to the user's home directory
+pub fn expand_home(path: &Path) -> Result<PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(without_tilde) => {
+            let base_dirs = BaseDirs::new().context("Failed to get base directory")?;
+            Ok(base_dirs.home_dir().join(without_tilde))
+        }
+        Err(_) => Ok(path.to_path_buf()),
+    }
+}
+
+fn parse_env<T>(key: &str, value: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .with_context(|| format!("Invalid value for {}: {:?}", key, value))
+}
+
+/// Command line flags shared by every binary
+#[derive(Args, Debug, Default)]
+pub struct CommonArgs {
+    /// Path to the config file [default: $XDG_CONFIG_HOME/metmac/config.toml]
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Path to the SQLite database
+    #[arg(long)]
+    pub db_path: Option<PathBuf>,
+}
+
+impl CommonArgs {
+    /// Resolves the full config, with these flags taking precedence
+    pub fn load_config(&self) -> Result<Config> {
+        let path = self
+            .config
+            .clone()
+            .or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from));
+
+        let mut config = Config::load(path.as_deref())?;
+
+        if let Some(db_path) = &self.db_path {
+            config.database.path = db_path.clone();
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_config_from_partial_file() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(
+            file,
+            r#"
+            [daemon]
+            flush_threshold = 100
+
+            [server]
+            bind_address = "127.0.0.1:4000"
+            "#
+        )?;
+
+        let config = Config::from_file(file.path())?;
+
+        assert_eq!(config.daemon.flush_threshold, 100);
+        assert_eq!(config.daemon.flush_interval_secs, 3);
+        assert_eq!(config.server.bind_address, "127.0.0.1:4000");
+        assert_eq!(config.database, DatabaseConfig::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_rejects_unknown_fields() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "[daemon]\nflush_treshold = 100")?;
+
+        assert!(Config::from_file(file.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_env_overrides() -> Result<()> {
+        let vars = HashMap::from([
+            ("METMAC_DB_PATH", "/tmp/metmac-test.db"),
+            ("METMAC_FLUSH_THRESHOLD", "5"),
+            ("METMAC_BIND_ADDRESS", "127.0.0.1:3005"),
+        ]);
+
+        let mut config = Config::default();
+        config.apply_env(|key| vars.get(key).map(|v| v.to_string()))?;
+
+        assert_eq!(config.database.path, PathBuf::from("/tmp/metmac-test.db"));
+        assert_eq!(config.daemon.flush_threshold, 5);
+        assert_eq!(config.daemon.flush_interval_secs, 3);
+        assert_eq!(config.server.bind_address, "127.0.0.1:3005");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_invalid_env_value() {
+        let mut config = Config::default();
+        let result =
+            config.apply_env(|key| (key == "METMAC_FLUSH_INTERVAL").then(|| "soon".to_string()));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_cli_overrides_file() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "[database]\npath = \"/tmp/from-file.db\"")?;
+
+        let args = CommonArgs {
+            config: Some(file.path().to_path_buf()),
+            db_path: Some(PathBuf::from("/tmp/from-cli.db")),
+        };
+
+        let config = args.load_config()?;
+        assert_eq!(config.database.path, PathBuf::from("/tmp/from-cli.db"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_validates_timestamp_bucket() {
+        let mut config = Config::default();
+        config.daemon.idle_threshold_secs = 900;
+
+        for bucket in [1, 60, 300, 900] {
+            config.privacy.timestamp_bucket_secs = Some(bucket);
+            assert!(config.validate().is_ok(), "{}", bucket);
+        }
+
+        // Would smear counts across hours
+        for bucket in [0, 7, 1200, 3600] {
+            config.privacy.timestamp_bucket_secs = Some(bucket);
+            assert!(config.validate().is_err(), "{}", bucket);
+        }
+
+        // Every bucket would be a session of its own
+        config.privacy.timestamp_bucket_secs = Some(300);
+        config.daemon.idle_threshold_secs = 120;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_validates_timezone() -> Result<()> {
+        let mut config = Config::default();
+
+        config.server.timezone = Some("Europe/Berlin".to_string());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.server.zone()?, Tz::Europe__Berlin);
+
+        config.server.timezone = Some("CEST".to_string());
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+}
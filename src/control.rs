@@ -0,0 +1,391 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::sync::oneshot;
+
+#[cfg(unix)]
+use anyhow::{bail, Context, Error};
+#[cfg(unix)]
+use log::{debug, info, warn};
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+#[cfg(unix)]
+use tokio::sync::mpsc;
+#[cfg(unix)]
+use tokio::task::JoinHandle;
+
+use crate::input::pause::PauseSource;
+use crate::input::source::{CaptureHandle, InputSourceKind};
+use crate::pipeline::{BufferStatus, PipelineStatsSnapshot};
+use crate::privacy::PrivacyLevel;
+
+/// Commands that change what a running daemon does, or ask how it is doing.
+///
+/// On the control socket each is a line of JSON such as `{"command":"pause"}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::Subcommand)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Report uptime, buffered events and what happened to captured events
+    Status,
+    /// Stop recording input until resumed
+    Pause,
+    /// Start recording input again
+    Resume,
+    /// Pause if recording, resume if paused
+    Toggle,
+    /// Write buffered events to the database now
+    Flush,
+    /// Re-read the config file and apply the settings that can change while running
+    ReloadConfig,
+    /// Flush everything and exit
+    Shutdown,
+}
+
+impl ControlCommand {
+    /// Applies a pause command to a running capture, returning false if it changed
+    /// nothing. Other commands are handled by the daemon and change nothing here
+    pub async fn apply(self, capture: &CaptureHandle) -> bool {
+        match self {
+            Self::Pause => capture.pause(PauseSource::Command).await,
+            Self::Resume => capture.resume().await,
+            Self::Toggle if capture.is_paused() => capture.resume().await,
+            Self::Toggle => capture.pause(PauseSource::Command).await,
+            _ => false,
+        }
+    }
+}
+
+/// Reply to a [`ControlCommand`], one line of JSON tagged with `result`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Done {
+        /// False when the command had nothing to do, e.g. pausing while paused
+        changed: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+    },
+    Status(DaemonStatus),
+    Error {
+        message: String,
+    },
+}
+
+impl ControlResponse {
+    pub fn done(changed: bool) -> Self {
+        Self::Done {
+            changed,
+            note: None,
+        }
+    }
+
+    pub fn error(error: anyhow::Error) -> Self {
+        Self::Error {
+            message: format!("{:#}", error),
+        }
+    }
+}
+
+/// What a running daemon reports for [`ControlCommand::Status`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub version: String,
+    pub uptime_secs: u64,
+    pub source: InputSourceKind,
+    pub privacy: PrivacyLevel,
+    pub paused: bool,
+    /// When a timed pause ends, in seconds since the epoch
+    pub resume_at: Option<u64>,
+    #[serde(flatten)]
+    pub buffer: BufferStatus,
+    #[serde(flatten)]
+    pub pipeline: PipelineStatsSnapshot,
+}
+
+/// A command received on the control socket and where its reply goes
+pub type ControlRequest = (ControlCommand, oneshot::Sender<ControlResponse>);
+
+/// Control commands sent as signals, `SIGUSR1` pauses and `SIGUSR2` resumes
+#[cfg(unix)]
+pub struct ControlSignals {
+    pause: Signal,
+    resume: Signal,
+}
+
+#[cfg(unix)]
+impl ControlSignals {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            pause: signal(SignalKind::user_defined1())?,
+            resume: signal(SignalKind::user_defined2())?,
+        })
+    }
+
+    /// Resolves with the command for the next signal received
+    pub async fn recv(&mut self) -> ControlCommand {
+        tokio::select! {
+            _ = self.pause.recv() => ControlCommand::Pause,
+            _ = self.resume.recv() => ControlCommand::Resume,
+        }
+    }
+}
+
+/// Control commands sent as signals, not available on this platform
+#[cfg(not(unix))]
+pub struct ControlSignals;
+
+#[cfg(not(unix))]
+impl ControlSignals {
+    pub fn new() -> Result<Self> {
+        Ok(Self)
+    }
+
+    /// Never resolves
+    pub async fn recv(&mut self) -> ControlCommand {
+        std::future::pending().await
+    }
+}
+
+/// Unix domain socket accepting control commands, removed again when dropped.
+///
+/// Only the owner can connect, the socket has mode `0600` before it appears at its path.
+#[cfg(unix)]
+pub struct ControlSocket {
+    path: PathBuf,
+    requests: mpsc::Receiver<ControlRequest>,
+    listener: JoinHandle<()>,
+}
+
+#[cfg(unix)]
+impl ControlSocket {
+    /// Listens on `path`, replacing a socket left behind by a daemon that did not exit
+    /// cleanly
+    pub async fn bind(path: &Path) -> Result<Self> {
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                bail!("Another daemon is listening on {:?}", path);
+            }
+            warn!("Removing stale control socket {:?}", path);
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {:?}", path))?;
+        }
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(parent)?;
+
+        // Bound inside a directory only the owner can enter and moved into place once
+        // private, so nobody else can connect in between
+        let staging = tempfile::Builder::new()
+            .prefix(".control")
+            .permissions(fs::Permissions::from_mode(0o700))
+            .tempdir_in(parent)
+            .with_context(|| format!("Failed to create a directory in {:?}", parent))?;
+        let staged = staging.path().join("control.sock");
+        let listener = UnixListener::bind(&staged)
+            .with_context(|| format!("Failed to bind control socket {:?}", path))?;
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)
+            .with_context(|| format!("Failed to move control socket to {:?}", path))?;
+        info!("Listening for control commands on {:?}", path);
+
+        let (tx, requests) = mpsc::channel(16);
+        let listener = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_connection(stream, tx.clone()));
+                    }
+                    Err(e) => warn!("Failed to accept control connection: {:?}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            requests,
+            listener,
+        })
+    }
+
+    /// Resolves with the next command received
+    pub async fn recv(&mut self) -> ControlRequest {
+        match self.requests.recv().await {
+            Some(request) => request,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.listener.abort();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Answers every command sent on one connection, in order
+#[cfg(unix)]
+async fn serve_connection(stream: UnixStream, requests: mpsc::Sender<ControlRequest>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str::<ControlCommand>(&line) {
+            Ok(command) => {
+                debug!("Control command {:?}", command);
+                let (tx, rx) = oneshot::channel();
+                if requests.send((command, tx)).await.is_err() {
+                    break;
+                }
+                match rx.await {
+                    Ok(response) => response,
+                    Err(_) => break,
+                }
+            }
+            Err(e) => ControlResponse::Error {
+                message: format!("Invalid command: {}", e),
+            },
+        };
+
+        let Ok(mut reply) = serde_json::to_string(&response) else {
+            break;
+        };
+        reply.push('\n');
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Control socket, not available on this platform
+#[cfg(not(unix))]
+pub struct ControlSocket;
+
+#[cfg(not(unix))]
+impl ControlSocket {
+    pub async fn bind(_path: &Path) -> Result<Self> {
+        log::warn!("The control socket is only available on Unix");
+        Ok(Self)
+    }
+
+    /// Never resolves
+    pub async fn recv(&mut self) -> ControlRequest {
+        std::future::pending().await
+    }
+}
+
+/// Sends `command` to the daemon listening on `path` and waits for its reply
+#[cfg(unix)]
+pub async fn send(path: &Path, command: ControlCommand) -> Result<ControlResponse> {
+    let mut stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("Failed to connect to {:?}, is the daemon running?", path))?;
+
+    let mut request = serde_json::to_string(&command)?;
+    request.push('\n');
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await?;
+    if line.is_empty() {
+        return Err(Error::msg(
+            "The daemon closed the connection without replying",
+        ));
+    }
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Sends `command` to a running daemon, not available on this platform
+#[cfg(not(unix))]
+pub async fn send(_path: &Path, _command: ControlCommand) -> Result<ControlResponse> {
+    anyhow::bail!("The control socket is only available on Unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_protocol_format() -> Result<()> {
+        assert_eq!(
+            serde_json::to_string(&ControlCommand::ReloadConfig)?,
+            r#"{"command":"reload_config"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<ControlCommand>(r#"{"command":"pause"}"#)?,
+            ControlCommand::Pause
+        );
+        assert_eq!(
+            serde_json::to_string(&ControlResponse::done(true))?,
+            r#"{"result":"done","changed":true}"#
+        );
+
+        let status = ControlResponse::Status(DaemonStatus {
+            pid: 42,
+            version: "0.1.0".to_string(),
+            uptime_secs: 60,
+            source: InputSourceKind::Rdev,
+            privacy: PrivacyLevel::Full,
+            paused: false,
+            resume_at: None,
+            buffer: BufferStatus {
+                buffered_events: 3,
+                secs_since_flush: 2,
+            },
+            pipeline: PipelineStatsSnapshot {
+                received: 10,
+                dropped: 1,
+                spilled: 0,
+            },
+        });
+        let json = serde_json::to_string(&status)?;
+        assert!(json.contains(r#""buffered_events":3"#));
+        assert_eq!(serde_json::from_str::<ControlResponse>(&json)?, status);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_control_socket_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("control.sock");
+        let mut socket = ControlSocket::bind(&path).await?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        // A second daemon must not take over the socket
+        assert!(ControlSocket::bind(&path).await.is_err());
+
+        let daemon = tokio::spawn(async move {
+            let (command, reply) = socket.recv().await;
+            let _ = reply.send(ControlResponse::done(command == ControlCommand::Flush));
+            socket
+        });
+
+        assert_eq!(
+            send(&path, ControlCommand::Flush).await?,
+            ControlResponse::done(true)
+        );
+
+        drop(daemon.await?);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,536 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use rdev::{Button, Event, EventType, Key};
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::source::{EventCallback, InputSource};
+
+/// Size of a `struct input_event`, a `timeval` followed by type, code and value
+#[cfg(target_pointer_width = "64")]
+const EVENT_SIZE: usize = 24;
+#[cfg(not(target_pointer_width = "64"))]
+const EVENT_SIZE: usize = 16;
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_REP: u32 = 0x14;
+
+const SYN_REPORT: u16 = 0x00;
+
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+
+const BTN_MISC: u16 = 0x100;
+const BTN_MOUSE: u16 = 0x110;
+const BTN_TASK: u16 = 0x117;
+/// First key code after the buttons
+const KEY_OK: u16 = 0x160;
+
+/// Where the kernel lists input devices and their capabilities
+const PROC_DEVICES: &str = "/proc/bus/input/devices";
+
+/// One `struct input_event` as read from an evdev device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawInputEvent {
+    pub time: SystemTime,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl RawInputEvent {
+    pub fn parse(bytes: &[u8; EVENT_SIZE]) -> Self {
+        let (seconds, micros) = parse_timeval(bytes);
+        let rest = &bytes[EVENT_SIZE - 8..];
+
+        Self {
+            time: UNIX_EPOCH
+                + Duration::from_secs(seconds.max(0) as u64)
+                + Duration::from_micros(micros.max(0) as u64),
+            kind: u16::from_ne_bytes([rest[0], rest[1]]),
+            code: u16::from_ne_bytes([rest[2], rest[3]]),
+            value: i32::from_ne_bytes([rest[4], rest[5], rest[6], rest[7]]),
+        }
+    }
+}
+
+#[cfg(target_pointer_width = "64")]
+fn parse_timeval(bytes: &[u8; EVENT_SIZE]) -> (i64, i64) {
+    let mut seconds = [0; 8];
+    let mut micros = [0; 8];
+    seconds.copy_from_slice(&bytes[0..8]);
+    micros.copy_from_slice(&bytes[8..16]);
+    (i64::from_ne_bytes(seconds), i64::from_ne_bytes(micros))
+}
+
+#[cfg(not(target_pointer_width = "64"))]
+fn parse_timeval(bytes: &[u8; EVENT_SIZE]) -> (i64, i64) {
+    let mut seconds = [0; 4];
+    let mut micros = [0; 4];
+    seconds.copy_from_slice(&bytes[0..4]);
+    micros.copy_from_slice(&bytes[4..8]);
+    (
+        i32::from_ne_bytes(seconds) as i64,
+        i32::from_ne_bytes(micros) as i64,
+    )
+}
+
+/// Turns raw evdev events into the same `rdev` events the other sources produce, so key
+/// names and everything downstream are shared.
+///
+/// Relative pointer motion is accumulated into a virtual position, as evdev has no notion
+/// of the cursor's place on screen.
+#[derive(Default)]
+pub struct EvdevDecoder {
+    x: f64,
+    y: f64,
+    moved: bool,
+}
+
+impl EvdevDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decode(&mut self, raw: RawInputEvent) -> Option<Event> {
+        let event_type = match (raw.kind, raw.code) {
+            (EV_KEY, code) if (BTN_MOUSE..=BTN_TASK).contains(&code) => {
+                let button = button_from_code(code);
+                match raw.value {
+                    0 => EventType::ButtonRelease(button),
+                    1 => EventType::ButtonPress(button),
+                    _ => return None,
+                }
+            }
+            // Touchpad contacts, joystick and gamepad buttons, which are not keystrokes
+            (EV_KEY, code) if (BTN_MISC..KEY_OK).contains(&code) => return None,
+            // A value of 2 is auto-repeat, which the key tracker flags like any other repeat
+            (EV_KEY, code) => match raw.value {
+                0 => EventType::KeyRelease(key_from_code(code)),
+                1 | 2 => EventType::KeyPress(key_from_code(code)),
+                _ => return None,
+            },
+            (EV_REL, REL_X) => {
+                self.x += raw.value as f64;
+                self.moved = true;
+                return None;
+            }
+            (EV_REL, REL_Y) => {
+                self.y += raw.value as f64;
+                self.moved = true;
+                return None;
+            }
+            (EV_REL, REL_WHEEL) => EventType::Wheel {
+                delta_x: 0,
+                delta_y: raw.value as i64,
+            },
+            (EV_REL, REL_HWHEEL) => EventType::Wheel {
+                delta_x: raw.value as i64,
+                delta_y: 0,
+            },
+            // Motion on both axes arrives as separate events, completed by a report
+            (EV_SYN, SYN_REPORT) if self.moved => {
+                self.moved = false;
+                EventType::MouseMove {
+                    x: self.x,
+                    y: self.y,
+                }
+            }
+            _ => return None,
+        };
+
+        Some(Event {
+            time: raw.time,
+            name: None,
+            event_type,
+        })
+    }
+}
+
+/// Maps a Linux key code to the `rdev` key
+pub fn key_from_code(code: u16) -> Key {
+    match code {
+        1 => Key::Escape,
+        2 => Key::Num1,
+        3 => Key::Num2,
+        4 => Key::Num3,
+        5 => Key::Num4,
+        6 => Key::Num5,
+        7 => Key::Num6,
+        8 => Key::Num7,
+        9 => Key::Num8,
+        10 => Key::Num9,
+        11 => Key::Num0,
+        12 => Key::Minus,
+        13 => Key::Equal,
+        14 => Key::Backspace,
+        15 => Key::Tab,
+        16 => Key::KeyQ,
+        17 => Key::KeyW,
+        18 => Key::KeyE,
+        19 => Key::KeyR,
+        20 => Key::KeyT,
+        21 => Key::KeyY,
+        22 => Key::KeyU,
+        23 => Key::KeyI,
+        24 => Key::KeyO,
+        25 => Key::KeyP,
+        26 => Key::LeftBracket,
+        27 => Key::RightBracket,
+        28 => Key::Return,
+        29 => Key::ControlLeft,
+        30 => Key::KeyA,
+        31 => Key::KeyS,
+        32 => Key::KeyD,
+        33 => Key::KeyF,
+        34 => Key::KeyG,
+        35 => Key::KeyH,
+        36 => Key::KeyJ,
+        37 => Key::KeyK,
+        38 => Key::KeyL,
+        39 => Key::SemiColon,
+        40 => Key::Quote,
+        41 => Key::BackQuote,
+        42 => Key::ShiftLeft,
+        43 => Key::BackSlash,
+        44 => Key::KeyZ,
+        45 => Key::KeyX,
+        46 => Key::KeyC,
+        47 => Key::KeyV,
+        48 => Key::KeyB,
+        49 => Key::KeyN,
+        50 => Key::KeyM,
+        51 => Key::Comma,
+        52 => Key::Dot,
+        53 => Key::Slash,
+        54 => Key::ShiftRight,
+        55 => Key::KpMultiply,
+        56 => Key::Alt,
+        57 => Key::Space,
+        58 => Key::CapsLock,
+        59 => Key::F1,
+        60 => Key::F2,
+        61 => Key::F3,
+        62 => Key::F4,
+        63 => Key::F5,
+        64 => Key::F6,
+        65 => Key::F7,
+        66 => Key::F8,
+        67 => Key::F9,
+        68 => Key::F10,
+        69 => Key::NumLock,
+        70 => Key::ScrollLock,
+        71 => Key::Kp7,
+        72 => Key::Kp8,
+        73 => Key::Kp9,
+        74 => Key::KpMinus,
+        75 => Key::Kp4,
+        76 => Key::Kp5,
+        77 => Key::Kp6,
+        78 => Key::KpPlus,
+        79 => Key::Kp1,
+        80 => Key::Kp2,
+        81 => Key::Kp3,
+        82 => Key::Kp0,
+        83 => Key::KpDelete,
+        86 => Key::IntlBackslash,
+        87 => Key::F11,
+        88 => Key::F12,
+        96 => Key::KpReturn,
+        97 => Key::ControlRight,
+        98 => Key::KpDivide,
+        99 => Key::PrintScreen,
+        100 => Key::AltGr,
+        102 => Key::Home,
+        103 => Key::UpArrow,
+        104 => Key::PageUp,
+        105 => Key::LeftArrow,
+        106 => Key::RightArrow,
+        107 => Key::End,
+        108 => Key::DownArrow,
+        109 => Key::PageDown,
+        110 => Key::Insert,
+        111 => Key::Delete,
+        119 => Key::Pause,
+        125 => Key::MetaLeft,
+        126 => Key::MetaRight,
+        // rdev reports unknown keys by their X11 keycode, which is the evdev code plus 8
+        code => Key::Unknown(code as u32 + 8),
+    }
+}
+
+fn button_from_code(code: u16) -> Button {
+    match code {
+        0x110 => Button::Left,
+        0x111 => Button::Right,
+        0x112 => Button::Middle,
+        code => Button::Unknown((code - BTN_MOUSE) as u8),
+    }
+}
+
+/// Reads events straight from evdev devices, which works under Wayland and on a TTY where
+/// rdev sees nothing. Reading `/dev/input/event*` usually requires membership of the
+/// `input` group.
+///
+/// Any file of recorded `input_event` structs can stand in for a device, it is read until
+/// it runs out.
+pub struct EvdevSource {
+    devices: Vec<PathBuf>,
+}
+
+impl EvdevSource {
+    pub fn new(devices: Vec<PathBuf>) -> Self {
+        Self { devices }
+    }
+
+    /// Uses every keyboard and mouse the kernel knows about
+    pub fn detect() -> Result<Self> {
+        let listing = fs::read_to_string(PROC_DEVICES)
+            .with_context(|| format!("Failed to read {}", PROC_DEVICES))?;
+
+        let devices = detect_devices(&listing);
+        if devices.is_empty() {
+            bail!("No keyboard or mouse devices found in {}", PROC_DEVICES);
+        }
+
+        Ok(Self::new(devices))
+    }
+}
+
+impl InputSource for EvdevSource {
+    fn run(self: Box<Self>, mut callback: EventCallback) -> Result<()> {
+        let mut readers = Vec::with_capacity(self.devices.len());
+        for path in &self.devices {
+            let file = File::open(path).with_context(|| {
+                format!(
+                    "Failed to open input device {:?}, is the user in the input group?",
+                    path
+                )
+            })?;
+            info!("Reading input events from {:?}", path);
+            readers.push((path.clone(), file));
+        }
+
+        // Devices are read in parallel and merged in arrival order
+        let (tx, rx) = mpsc::channel();
+        for (path, file) in readers {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut decoder = EvdevDecoder::new();
+                let result = read_events(BufReader::new(file), |raw| {
+                    if let Some(event) = decoder.decode(raw) {
+                        let _ = tx.send(event);
+                    }
+                });
+                match result {
+                    Ok(()) => debug!("Reached the end of {:?}", path),
+                    Err(e) => warn!("Stopped reading {:?}: {:?}", path, e),
+                }
+            });
+        }
+        drop(tx);
+
+        for event in rx {
+            callback(event);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads `input_event` structs until the end of the stream
+pub fn read_events<R: Read>(mut reader: R, mut handle: impl FnMut(RawInputEvent)) -> Result<()> {
+    let mut bytes = [0; EVENT_SIZE];
+    loop {
+        let mut filled = 0;
+        while filled < EVENT_SIZE {
+            match reader.read(&mut bytes[filled..]) {
+                Ok(0) => {
+                    if filled > 0 {
+                        warn!("Ignoring {} trailing bytes of a partial event", filled);
+                    }
+                    return Ok(());
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        handle(RawInputEvent::parse(&bytes));
+    }
+}
+
+/// Picks the event devices of keyboards and mice out of a `/proc/bus/input/devices` listing.
+///
+/// Keyboards are told apart from power buttons and the like, which also use the `kbd`
+/// handler, by their support for key repeat.
+fn detect_devices(listing: &str) -> Vec<PathBuf> {
+    let mut devices = Vec::new();
+
+    for block in listing.split("\n\n") {
+        let mut handlers: Vec<&str> = Vec::new();
+        let mut ev_bits = 0u32;
+        let mut name = "";
+
+        for line in block.lines() {
+            if let Some(value) = line.strip_prefix("N: Name=") {
+                name = value.trim_matches('"');
+            } else if let Some(value) = line.strip_prefix("H: Handlers=") {
+                handlers = value.split_whitespace().collect();
+            } else if let Some(value) = line.strip_prefix("B: EV=") {
+                ev_bits = u32::from_str_radix(value.trim(), 16).unwrap_or(0);
+            }
+        }
+
+        let is_keyboard = handlers.contains(&"kbd") && ev_bits & (1 << EV_REP) != 0;
+        let is_mouse = handlers.iter().any(|handler| handler.starts_with("mouse"));
+        if !is_keyboard && !is_mouse {
+            continue;
+        }
+
+        if let Some(event) = handlers.iter().find(|handler| handler.starts_with("event")) {
+            debug!("Detected input device {:?} ({})", name, event);
+            devices.push(Path::new("/dev/input").join(event));
+        }
+    }
+
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::InputProcessor;
+    use crate::models::events::{InputEvent, MouseActivity};
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/evdev")
+            .join(name)
+    }
+
+    fn capture(path: PathBuf) -> Result<Vec<InputEvent>> {
+        let (tx, rx) = mpsc::channel();
+        Box::new(EvdevSource::new(vec![path])).run(Box::new(move |event| {
+            let _ = tx.send(event);
+        }))?;
+
+        let mut processor = InputProcessor::new();
+        let mut events = rx
+            .into_iter()
+            .flat_map(|event| processor.process(&event))
+            .collect::<Vec<_>>();
+        events.extend(processor.finish(SystemTime::now()));
+        Ok(events)
+    }
+
+    #[test]
+    fn test_evdev_keyboard_recording() -> Result<()> {
+        // "Hi" with a held shift, then an auto-repeating backspace
+        let keys = capture(fixture("keyboard.bin"))?
+            .into_iter()
+            .filter_map(|event| match event {
+                InputEvent::Key(key) => Some((key.key_name, key.dwell_ms, key.is_repeat)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            keys,
+            vec![
+                ("h".to_string(), Some(80), false),
+                ("shift_left".to_string(), Some(150), false),
+                ("i".to_string(), Some(70), false),
+                ("backspace".to_string(), Some(600), false),
+                ("backspace".to_string(), None, true),
+                ("backspace".to_string(), None, true),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evdev_mouse_recording() -> Result<()> {
+        // A left click, a nudge, 30px right and 40px down in separate reports, two wheel notches
+        let activity = capture(fixture("mouse.bin"))?
+            .into_iter()
+            .filter_map(|event| match event {
+                InputEvent::Mouse(activity) => Some(activity),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let total = |field: fn(&MouseActivity) -> f64| activity.iter().map(field).sum::<f64>();
+        assert_eq!(total(|a| a.left_clicks as f64), 1.0);
+        assert_eq!(total(|a| a.scroll_y as f64), 2.0);
+        assert_eq!(total(|a| a.travel_px), 70.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_events_ignores_partial_event() -> Result<()> {
+        let mut bytes = fs::read(fixture("keyboard.bin"))?;
+        let complete = bytes.len() / EVENT_SIZE;
+        bytes.extend_from_slice(&[0; 5]);
+
+        let mut count = 0;
+        read_events(bytes.as_slice(), |_| count += 1)?;
+        assert_eq!(count, complete);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_touchpad_buttons_are_not_keys() {
+        let mut decoder = EvdevDecoder::new();
+        let raw = |code, value| RawInputEvent {
+            time: UNIX_EPOCH,
+            kind: EV_KEY,
+            code,
+            value,
+        };
+
+        // BTN_TOUCH and BTN_TOOL_FINGER as a finger lands on a touchpad
+        assert!(decoder.decode(raw(0x14a, 1)).is_none());
+        assert!(decoder.decode(raw(0x145, 1)).is_none());
+        assert!(matches!(
+            decoder.decode(raw(0x110, 1)).map(|event| event.event_type),
+            Some(EventType::ButtonPress(Button::Left))
+        ));
+        assert!(matches!(
+            decoder.decode(raw(KEY_OK, 1)).map(|event| event.event_type),
+            Some(EventType::KeyPress(_))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_codes_use_x11_keycodes() {
+        assert_eq!(key_from_code(30), Key::KeyA);
+        assert_eq!(key_from_code(115), Key::Unknown(123));
+    }
+
+    #[test]
+    fn test_detect_devices() -> Result<()> {
+        let listing = fs::read_to_string(fixture("devices.txt"))?;
+        assert_eq!(
+            detect_devices(&listing),
+            vec![
+                PathBuf::from("/dev/input/event3"),
+                PathBuf::from("/dev/input/event5"),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+#[cfg(target_os = "linux")]
+pub mod x11;
+
+use anyhow::Result;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use super::source::InputSourceKind;
+use crate::config::InputConfig;
+
+/// Window titles are cut to this many characters
+pub const MAX_TITLE_CHARS: usize = 80;
+
+/// Stands in for the parts of a window title that could identify a person or a file
+pub const REDACTED: &str = "…";
+
+/// How far back focus changes are remembered, keys held down longer are not attributed
+const FOCUS_HISTORY: Duration = Duration::from_secs(600);
+
+/// Where the focused application is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FocusSourceKind {
+    /// X11 when it can be reached, otherwise nothing
+    #[default]
+    Auto,
+    /// Do not record the focused application
+    None,
+    /// The active window of the X server, through `_NET_ACTIVE_WINDOW`
+    X11,
+}
+
+/// The application keystrokes go to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Focus {
+    /// Stable name of the application, e.g. the X11 window class
+    pub application: String,
+    pub window_title: Option<String>,
+}
+
+impl Focus {
+    pub fn new(application: impl Into<String>) -> Self {
+        Self {
+            application: application.into(),
+            window_title: None,
+        }
+    }
+
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.window_title = Some(title.into());
+        self
+    }
+}
+
+/// Tells which application has the keyboard focus
+pub trait FocusProvider: Send {
+    /// The focus at `time`, `None` when nothing is focused or it cannot be told
+    fn focused(&mut self, time: SystemTime) -> Option<Focus>;
+}
+
+/// Builds the provider selected in the config, `None` when focus is not recorded
+pub fn from_config(config: &InputConfig) -> Result<Option<Box<dyn FocusProvider>>> {
+    match config.focus {
+        FocusSourceKind::None => Ok(None),
+        #[cfg(target_os = "linux")]
+        FocusSourceKind::X11 => Ok(Some(Box::new(x11::X11Focus::connect(
+            config.window_titles,
+        )?))),
+        #[cfg(not(target_os = "linux"))]
+        FocusSourceKind::X11 => anyhow::bail!("The X11 focus source is only available on Linux"),
+        // Recorded and scripted input was not typed into whatever has the focus now
+        FocusSourceKind::Auto
+            if matches!(
+                config.source,
+                InputSourceKind::Replay | InputSourceKind::Synthetic
+            ) =>
+        {
+            Ok(None)
+        }
+        FocusSourceKind::Auto => {
+            #[cfg(target_os = "linux")]
+            match x11::X11Focus::connect(config.window_titles) {
+                Ok(provider) => return Ok(Some(Box::new(provider))),
+                Err(e) => log::warn!("Not recording applications: {:#}", e),
+            }
+
+            info!("No focus source available, applications are not recorded");
+            Ok(None)
+        }
+    }
+}
+
+/// Recent focus changes, so keystrokes go to whatever had the focus when they were pressed
+/// rather than when they were released
+#[derive(Debug, Default)]
+pub struct FocusHistory {
+    /// Each change lasts until the next, the oldest is the one in force
+    /// [`FOCUS_HISTORY`] ago
+    changes: VecDeque<(SystemTime, Option<Focus>)>,
+}
+
+impl FocusHistory {
+    /// Notes that `focus` has had the focus since `time`, changes must come in time order
+    pub fn record(&mut self, time: SystemTime, focus: Option<Focus>) {
+        if self.changes.back().is_some_and(|(_, last)| *last == focus) {
+            return;
+        }
+        self.changes.push_back((time, focus));
+
+        let Some(cutoff) = time.checked_sub(FOCUS_HISTORY) else {
+            return;
+        };
+        while self.changes.len() > 1 && self.changes[1].0 <= cutoff {
+            self.changes.pop_front();
+        }
+    }
+
+    /// The focus at `time`, `None` before the oldest change remembered
+    pub fn at(&self, time: SystemTime) -> Option<Focus> {
+        self.changes
+            .iter()
+            .rev()
+            .find(|(since, _)| *since <= time)
+            .and_then(|(_, focus)| focus.clone())
+    }
+}
+
+/// Replays a fixed timeline of focus changes, for tests and synthetic input
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedFocus {
+    /// Focus changes in time order, each lasting until the next
+    changes: Vec<(SystemTime, Focus)>,
+}
+
+impl ScriptedFocus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Focuses `focus` from `time` on, changes must be added in time order
+    pub fn then(mut self, time: SystemTime, focus: Focus) -> Self {
+        self.changes.push((time, focus));
+        self
+    }
+}
+
+impl FocusProvider for ScriptedFocus {
+    fn focused(&mut self, time: SystemTime) -> Option<Focus> {
+        self.changes
+            .iter()
+            .rev()
+            .find(|(since, _)| *since <= time)
+            .map(|(_, focus)| focus.clone())
+    }
+}
+
+/// Masks what in a window title could name a person, an address or a file, and cuts it to
+/// [`MAX_TITLE_CHARS`]. Digits become `#`, so numbered titles still group together
+pub fn redact_title(title: &str) -> String {
+    let words = title
+        .split_whitespace()
+        .map(|word| {
+            let personal = word.contains('@')
+                || word.contains("://")
+                || word.starts_with('/')
+                || word.starts_with('~')
+                || word.contains('\\');
+            if personal {
+                REDACTED.to_string()
+            } else {
+                word.chars()
+                    .map(|c| if c.is_numeric() { '#' } else { c })
+                    .collect()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    words.chars().take(MAX_TITLE_CHARS).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_scripted_focus() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut focus = ScriptedFocus::new()
+            .then(at(10), Focus::new("firefox"))
+            .then(at(20), Focus::new("code").with_title("main.rs"));
+
+        assert_eq!(focus.focused(at(5)), None);
+        assert_eq!(focus.focused(at(10)), Some(Focus::new("firefox")));
+        assert_eq!(
+            focus.focused(at(25)).map(|focus| focus.application),
+            Some("code".to_string())
+        );
+    }
+
+    #[test]
+    fn test_focus_history() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let mut history = FocusHistory::default();
+
+        history.record(at(10), Some(Focus::new("firefox")));
+        history.record(at(20), None);
+        history.record(at(30), Some(Focus::new("code")));
+        assert_eq!(history.at(at(5)), None);
+        assert_eq!(history.at(at(15)), Some(Focus::new("firefox")));
+        assert_eq!(history.at(at(25)), None);
+        assert_eq!(history.at(at(35)), Some(Focus::new("code")));
+
+        // Changes from long ago are forgotten, apart from the one still in force
+        history.record(at(1000), Some(Focus::new("firefox")));
+        assert_eq!(history.changes.len(), 2);
+        assert_eq!(history.at(at(900)), Some(Focus::new("code")));
+        assert_eq!(history.at(at(15)), None);
+    }
+
+    #[test]
+    fn test_redact_title() {
+        assert_eq!(
+            redact_title("Inbox (12) - jane@example.com - Mail"),
+            "Inbox (##) - … - Mail"
+        );
+        assert_eq!(
+            redact_title("notes.md  ~/work/secret - Editor"),
+            "notes.md … - Editor"
+        );
+        assert_eq!(
+            redact_title(&"a".repeat(200)).chars().count(),
+            MAX_TITLE_CHARS
+        );
+    }
+}
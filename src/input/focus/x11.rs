@@ -0,0 +1,319 @@
+use anyhow::{bail, Result};
+use log::info;
+use std::ffi::{c_char, c_int, c_uchar, c_ulong, CStr};
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use ::x11::xlib;
+
+use super::{Focus, FocusHistory, FocusProvider};
+
+/// How often the watcher looks for focus changes the X server has announced
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type ErrorHandler =
+    Option<unsafe extern "C" fn(*mut xlib::Display, *mut xlib::XErrorEvent) -> c_int>;
+
+/// The watcher's display, whose errors are ignored
+static WATCHED_DISPLAY: AtomicPtr<xlib::Display> = AtomicPtr::new(ptr::null_mut());
+/// The error handler installed before the watcher's, which gets every other display's errors
+static PREVIOUS_HANDLER: Mutex<ErrorHandler> = Mutex::new(None);
+
+/// Follows the focused window of an X server, through the EWMH `_NET_ACTIVE_WINDOW` hint
+/// that every current window manager sets.
+///
+/// The application is the window's `WM_CLASS` class, e.g. `firefox`. A thread of its own
+/// listens for the X server announcing focus changes on a dedicated connection, so looking
+/// up the focus for a keystroke makes no round trips to the server.
+pub struct X11Focus {
+    history: Arc<Mutex<FocusHistory>>,
+    stop: Arc<AtomicBool>,
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl X11Focus {
+    /// Connects to the display named by `DISPLAY`, also following window titles if
+    /// `window_titles` is set. Call it before the input source starts, as it readies Xlib
+    /// for use from several threads
+    pub fn connect(window_titles: bool) -> Result<Self> {
+        let mut connection = Connection::open(window_titles)?;
+        let history = Arc::new(Mutex::new(FocusHistory::default()));
+        let focus = connection.focused();
+        lock(&history).record(SystemTime::now(), focus);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let watcher = thread::spawn({
+            let history = history.clone();
+            let stop = stop.clone();
+            move || connection.watch(&history, &stop)
+        });
+        info!("Recording the focused application from the X server");
+
+        Ok(Self {
+            history,
+            stop,
+            watcher: Some(watcher),
+        })
+    }
+}
+
+impl FocusProvider for X11Focus {
+    fn focused(&mut self, time: SystemTime) -> Option<Focus> {
+        lock(&self.history).at(time)
+    }
+}
+
+impl Drop for X11Focus {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+    }
+}
+
+fn lock(history: &Mutex<FocusHistory>) -> std::sync::MutexGuard<'_, FocusHistory> {
+    history.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The watcher's own connection to the X server
+struct Connection {
+    display: *mut xlib::Display,
+    root: xlib::Window,
+    net_active_window: xlib::Atom,
+    net_wm_name: xlib::Atom,
+    utf8_string: xlib::Atom,
+    window_titles: bool,
+    /// The active window, watched for title changes
+    active: Option<xlib::Window>,
+}
+
+// The display is only used by the watcher thread once it has been handed over
+unsafe impl Send for Connection {}
+
+impl Connection {
+    fn open(window_titles: bool) -> Result<Self> {
+        // SAFETY: the display is checked for null before use and closed on drop
+        unsafe {
+            // rdev talks to the X server from the capture thread too
+            xlib::XInitThreads();
+
+            let display = xlib::XOpenDisplay(ptr::null());
+            if display.is_null() {
+                bail!("Cannot connect to the X server, is DISPLAY set?");
+            }
+
+            // The focused window can close between two requests, which must not take the
+            // daemon down with Xlib's default handler. Other connections keep theirs
+            WATCHED_DISPLAY.store(display, Ordering::Release);
+            *PREVIOUS_HANDLER.lock().unwrap_or_else(|e| e.into_inner()) =
+                xlib::XSetErrorHandler(Some(ignore_watched_errors));
+
+            let root = xlib::XDefaultRootWindow(display);
+            xlib::XSelectInput(display, root, xlib::PropertyChangeMask);
+
+            let atom = |name: &CStr| xlib::XInternAtom(display, name.as_ptr(), xlib::False);
+            Ok(Self {
+                display,
+                root,
+                net_active_window: atom(c"_NET_ACTIVE_WINDOW"),
+                net_wm_name: atom(c"_NET_WM_NAME"),
+                utf8_string: atom(c"UTF8_STRING"),
+                window_titles,
+                active: None,
+            })
+        }
+    }
+
+    /// Records the focus in `history` whenever it changes, until `stop` is set
+    fn watch(mut self, history: &Mutex<FocusHistory>, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            if self.focus_changed() {
+                let focus = self.focused();
+                lock(history).record(SystemTime::now(), focus);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Whether the X server announced a new active window, or a new title for it, since
+    /// last asked
+    fn focus_changed(&mut self) -> bool {
+        let mut changed = false;
+
+        // SAFETY: XPending and XNextEvent only touch this connection's event queue
+        unsafe {
+            while xlib::XPending(self.display) > 0 {
+                let mut event: xlib::XEvent = mem::zeroed();
+                xlib::XNextEvent(self.display, &mut event);
+                if event.get_type() != xlib::PropertyNotify {
+                    continue;
+                }
+
+                let atom = event.property.atom;
+                changed |= atom == self.net_active_window
+                    || (self.window_titles
+                        && (atom == self.net_wm_name || atom == xlib::XA_WM_NAME));
+            }
+        }
+
+        changed
+    }
+
+    /// The application with the focus now, watching its window for title changes
+    fn focused(&mut self) -> Option<Focus> {
+        let window = self.active_window();
+        if self.window_titles && window != self.active {
+            // SAFETY: selecting input on a window that is gone only raises an ignored error
+            unsafe {
+                if let Some(previous) = self.active {
+                    xlib::XSelectInput(self.display, previous, xlib::NoEventMask);
+                }
+                if let Some(window) = window {
+                    xlib::XSelectInput(self.display, window, xlib::PropertyChangeMask);
+                }
+            }
+        }
+        self.active = window;
+
+        let window = window?;
+        let application = self.class(window)?;
+        Some(Focus {
+            application,
+            window_title: if self.window_titles {
+                self.title(window)
+            } else {
+                None
+            },
+        })
+    }
+
+    fn active_window(&self) -> Option<xlib::Window> {
+        let (format, data) = self.property(self.root, self.net_active_window, xlib::XA_WINDOW)?;
+        // 32 bit properties come back as an array of C longs
+        if format != 32 || data.len() < size_of::<c_ulong>() {
+            return None;
+        }
+
+        let window = c_ulong::from_ne_bytes(data[..size_of::<c_ulong>()].try_into().ok()?);
+        (window != 0).then_some(window)
+    }
+
+    fn class(&self, window: xlib::Window) -> Option<String> {
+        // SAFETY: XGetClassHint fills both strings on success, each freed with XFree
+        unsafe {
+            let mut hint = xlib::XClassHint {
+                res_name: ptr::null_mut(),
+                res_class: ptr::null_mut(),
+            };
+            if xlib::XGetClassHint(self.display, window, &mut hint) == 0 {
+                return None;
+            }
+
+            let class = take_string(hint.res_class);
+            take_string(hint.res_name);
+            class
+        }
+    }
+
+    fn title(&self, window: xlib::Window) -> Option<String> {
+        if let Some((8, data)) = self.property(window, self.net_wm_name, self.utf8_string) {
+            return Some(String::from_utf8_lossy(&data).into_owned());
+        }
+
+        // SAFETY: XFetchName hands back a string to free with XFree, or nothing
+        unsafe {
+            let mut name: *mut c_char = ptr::null_mut();
+            xlib::XFetchName(self.display, window, &mut name);
+            take_string(name)
+        }
+    }
+
+    /// Reads a window property, returning its format and raw bytes
+    fn property(
+        &self,
+        window: xlib::Window,
+        property: xlib::Atom,
+        kind: xlib::Atom,
+    ) -> Option<(c_int, Vec<u8>)> {
+        let mut actual_type = 0;
+        let mut format = 0;
+        let mut items = 0;
+        let mut bytes_after = 0;
+        let mut data: *mut c_uchar = ptr::null_mut();
+
+        // SAFETY: the out pointers are valid, the returned buffer is copied and freed
+        unsafe {
+            let status = xlib::XGetWindowProperty(
+                self.display,
+                window,
+                property,
+                0,
+                1024,
+                xlib::False,
+                kind,
+                &mut actual_type,
+                &mut format,
+                &mut items,
+                &mut bytes_after,
+                &mut data,
+            );
+            if status != xlib::Success as c_int || data.is_null() {
+                return None;
+            }
+
+            let item_size = match format {
+                8 => 1,
+                16 => size_of::<std::ffi::c_short>(),
+                32 => size_of::<std::ffi::c_long>(),
+                _ => 0,
+            };
+            let bytes = std::slice::from_raw_parts(data, items as usize * item_size).to_vec();
+            xlib::XFree(data.cast());
+
+            (actual_type == kind).then_some((format, bytes))
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // SAFETY: the display was opened in `open` and is not used after this
+        unsafe {
+            xlib::XSetErrorHandler(*PREVIOUS_HANDLER.lock().unwrap_or_else(|e| e.into_inner()));
+            WATCHED_DISPLAY.store(ptr::null_mut(), Ordering::Release);
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}
+
+/// Copies and frees a string returned by Xlib
+unsafe fn take_string(string: *mut c_char) -> Option<String> {
+    if string.is_null() {
+        return None;
+    }
+
+    let owned = CStr::from_ptr(string).to_string_lossy().into_owned();
+    xlib::XFree(string.cast());
+    Some(owned)
+}
+
+/// Ignores errors on the watcher's connection and hands the rest to the previous handler
+unsafe extern "C" fn ignore_watched_errors(
+    display: *mut xlib::Display,
+    event: *mut xlib::XErrorEvent,
+) -> c_int {
+    if display == WATCHED_DISPLAY.load(Ordering::Acquire) {
+        return 0;
+    }
+
+    let previous = *PREVIOUS_HANDLER.lock().unwrap_or_else(|e| e.into_inner());
+    match previous {
+        Some(handler) => handler(display, event),
+        None => 0,
+    }
+}
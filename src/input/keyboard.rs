@@ -1,210 +1,428 @@
-use chrono::Utc;
 use log::{debug, info, warn};
 use rdev::{Event, EventType, Key};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::models::events::KeyEvent;
+use super::keymap::KeyMap;
+use super::layout::Layout;
+use super::naming::canonical_key_id;
+use super::text::{Composer, TypedText};
+use crate::models::events::{CharClass, KeyEvent};
 
-pub fn handle_keyboard_event(event: &Event) -> Option<KeyEvent> {
-    match &event.event_type {
-        EventType::KeyPress(_) => {
-            debug!("Raw Event: {:?}", event);
-            let key_name = parse_name_from_event(event);
+/// Presses of a held key closer together than this are treated as OS auto-repeat,
+/// anything longer means the release was missed and the key was pressed again
+const REPEAT_WINDOW_MS: i64 = 2000;
 
-            let key_event = KeyEvent::new(key_name, Utc::now().timestamp_millis());
+/// Pairs key presses with their releases to work out how long each key was held.
+///
+/// A keystroke is emitted once its key is released, carrying the press timestamp and
+/// the dwell time. Presses while the key is already down are flagged as auto-repeat and
+/// held back until then too, so they follow the press they repeat.
+#[derive(Default)]
+pub struct KeyTracker {
+    held: HashMap<Key, HeldKey>,
+    keymap: Arc<KeyMap>,
+    layout: Arc<Layout>,
+    composer: Composer,
+}
 
-            info!("Key Event: {:?}", key_event);
-            Some(key_event)
-        }
-        _ => None,
+struct HeldKey {
+    event: KeyEvent,
+    /// Auto-repeats since the press, in order
+    repeats: Vec<KeyEvent>,
+    /// Timestamp of the latest press, including repeats
+    last_press: i64,
+}
+
+impl HeldKey {
+    /// The press followed by its repeats
+    fn into_events(self) -> Vec<KeyEvent> {
+        let mut events = vec![self.event];
+        events.extend(self.repeats);
+        events
     }
 }
 
-/// Parses the name of the key from the event
-fn parse_name_from_event(event: &Event) -> String {
-    match event.event_type {
-        EventType::KeyPress(key) => {
-            match key {
-                // Modifiers
-                Key::MetaLeft => meta_key_name(true),
-                Key::MetaRight => meta_key_name(false),
-                Key::Alt => alt_key_name(true),
-                Key::AltGr => alt_key_name(false),
-
-                Key::ControlLeft => "ctrl_left".to_string(),
-                Key::ControlRight => "ctrl_right".to_string(),
-
-                // Special keys
-                Key::Backspace => "backspace".to_string(),
-                Key::Return => "return".to_string(),
-                Key::Delete => "delete".to_string(),
-                Key::Escape => "escape".to_string(),
-                Key::ShiftLeft => "shift_left".to_string(),
-                Key::ShiftRight => "shift_right".to_string(),
-                Key::Tab => "tab".to_string(),
-
-                // Arrow keys
-                Key::DownArrow => "down".to_string(),
-                Key::UpArrow => "up".to_string(),
-                Key::LeftArrow => "left".to_string(),
-                Key::RightArrow => "right".to_string(),
-
-                // Other keys
-                Key::End => "end".to_string(),
-                Key::Home => "home".to_string(),
-                Key::Insert => "insert".to_string(),
-                Key::CapsLock => "caps_lock".to_string(),
-
-                // Function keys
-                Key::F1 => "f1".to_string(),
-                Key::F2 => "f2".to_string(),
-                Key::F3 => "f3".to_string(),
-                Key::F4 => "f4".to_string(),
-                Key::F5 => "f5".to_string(),
-                Key::F6 => "f6".to_string(),
-                Key::F7 => "f7".to_string(),
-                Key::F8 => "f8".to_string(),
-                Key::F9 => "f9".to_string(),
-                Key::F10 => "f10".to_string(),
-                Key::F11 => "f11".to_string(),
-                Key::F12 => "f12".to_string(),
-
-                // Navigation and special keys
-                Key::PageDown => "page_down".to_string(),
-                Key::PageUp => "page_up".to_string(),
-                Key::Space => "space".to_string(),
-                Key::PrintScreen => "print_screen".to_string(),
-                Key::ScrollLock => "scroll_lock".to_string(),
-                Key::Pause => "pause".to_string(),
-                Key::NumLock => "num_lock".to_string(),
-
-                // Number row
-                Key::BackQuote => "`".to_string(),
-                Key::Num1 => "1".to_string(),
-                Key::Num2 => "2".to_string(),
-                Key::Num3 => "3".to_string(),
-                Key::Num4 => "4".to_string(),
-                Key::Num5 => "5".to_string(),
-                Key::Num6 => "6".to_string(),
-                Key::Num7 => "7".to_string(),
-                Key::Num8 => "8".to_string(),
-                Key::Num9 => "9".to_string(),
-                Key::Num0 => "0".to_string(),
-                Key::Minus => "-".to_string(),
-                Key::Equal => "=".to_string(),
-
-                // Letter keys
-                Key::KeyQ => "q".to_string(),
-                Key::KeyW => "w".to_string(),
-                Key::KeyE => "e".to_string(),
-                Key::KeyR => "r".to_string(),
-                Key::KeyT => "t".to_string(),
-                Key::KeyY => "y".to_string(),
-                Key::KeyU => "u".to_string(),
-                Key::KeyI => "i".to_string(),
-                Key::KeyO => "o".to_string(),
-                Key::KeyP => "p".to_string(),
-                Key::KeyA => "a".to_string(),
-                Key::KeyS => "s".to_string(),
-                Key::KeyD => "d".to_string(),
-                Key::KeyF => "f".to_string(),
-                Key::KeyG => "g".to_string(),
-                Key::KeyH => "h".to_string(),
-                Key::KeyJ => "j".to_string(),
-                Key::KeyK => "k".to_string(),
-                Key::KeyL => "l".to_string(),
-                Key::KeyZ => "z".to_string(),
-                Key::KeyX => "x".to_string(),
-                Key::KeyC => "c".to_string(),
-                Key::KeyV => "v".to_string(),
-                Key::KeyB => "b".to_string(),
-                Key::KeyN => "n".to_string(),
-                Key::KeyM => "m".to_string(),
-
-                // Punctuation and brackets
-                Key::LeftBracket => "[".to_string(),
-                Key::RightBracket => "]".to_string(),
-                Key::SemiColon => ";".to_string(),
-                Key::Quote => "'".to_string(),
-                Key::BackSlash => "\\".to_string(),
-                Key::IntlBackslash => "\\".to_string(),
-                Key::Comma => ",".to_string(),
-                Key::Dot => ".".to_string(),
-                Key::Slash => "/".to_string(),
-
-                // Numpad
-                Key::KpReturn => "numpad_enter".to_string(),
-                Key::KpMinus => "numpad_minus".to_string(),
-                Key::KpPlus => "numpad_plus".to_string(),
-                Key::KpMultiply => "numpad_multiply".to_string(),
-                Key::KpDivide => "numpad_divide".to_string(),
-                Key::Kp0 => "numpad_0".to_string(),
-                Key::Kp1 => "numpad_1".to_string(),
-                Key::Kp2 => "numpad_2".to_string(),
-                Key::Kp3 => "numpad_3".to_string(),
-                Key::Kp4 => "numpad_4".to_string(),
-                Key::Kp5 => "numpad_5".to_string(),
-                Key::Kp6 => "numpad_6".to_string(),
-                Key::Kp7 => "numpad_7".to_string(),
-                Key::Kp8 => "numpad_8".to_string(),
-                Key::Kp9 => "numpad_9".to_string(),
-                Key::KpDelete => "numpad_delete".to_string(),
-
-                // Special
-                Key::Function => "fn".to_string(),
-                Key::Unknown(code) => match map_custom_unknowns(code) {
-                    Some(name) => name,
-                    None => {
-                        // Try to parse the name as a last resort
-                        match &event.name {
-                            Some(name) => {
-                                // TODO: Should better handle control/unicode chars
-                                if name.chars().any(|c| c.is_ascii_punctuation()) {
-                                    warn!("unknown key event with special char: {:?}", event);
-                                    format!("unknown_{}", code)
-                                } else {
-                                    name.to_string()
-                                }
-                            }
-                            None => {
-                                warn!("unknown key event: {:?}", event);
-                                format!("unknown_{}", code)
-                            }
-                        }
-                    }
-                },
+impl KeyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_keymap(keymap: Arc<KeyMap>) -> Self {
+        Self {
+            keymap,
+            ..Self::default()
+        }
+    }
+
+    /// Records the characters typed under `layout` instead of QWERTY
+    pub fn with_layout(mut self, layout: Arc<Layout>) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Changes names and layout for keys pressed from now on, held keys are unaffected
+    pub fn set_keymap(&mut self, keymap: Arc<KeyMap>, layout: Arc<Layout>) {
+        self.keymap = keymap;
+        self.layout = layout;
+    }
+
+    pub fn keymap(&self) -> &KeyMap {
+        &self.keymap
+    }
+
+    /// Whether a press for `key` has been seen without its release
+    pub fn is_held(&self, key: Key) -> bool {
+        self.held.contains_key(&key)
+    }
+
+    /// Keystrokes completed by `event`, each press followed by its repeats
+    pub fn handle(&mut self, event: &Event) -> Vec<KeyEvent> {
+        match event.event_type {
+            EventType::KeyPress(key) => self.press(key, event),
+            EventType::KeyRelease(key) => self.release(key, event),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Keys still held, with their repeats and in the order they were pressed, for when
+    /// capture stops before they are released. Their dwell is unknown
+    pub fn finish(&mut self) -> Vec<KeyEvent> {
+        let mut held = self.held.drain().map(|(_, held)| held).collect::<Vec<_>>();
+        held.sort_by_key(|held| held.event.timestamp);
+        held.into_iter().flat_map(HeldKey::into_events).collect()
+    }
+
+    fn press(&mut self, key: Key, event: &Event) -> Vec<KeyEvent> {
+        debug!("Raw Event: {:?}", event);
+        let timestamp = event_millis(event.time);
+
+        if let Some(held) = self.held.get_mut(&key) {
+            if timestamp - held.last_press <= REPEAT_WINDOW_MS {
+                held.last_press = timestamp;
+
+                let mut repeat = KeyEvent::new(held.event.key_name.clone(), timestamp);
+                repeat.key_id = held.event.key_id.clone();
+                repeat.logical_key = held.event.logical_key.clone();
+                repeat.typed_text = held.event.typed_text.clone();
+                repeat.char_class = held.event.char_class;
+                repeat.is_repeat = true;
+                debug!("Key Event: {:?}", repeat);
+                held.repeats.push(repeat);
+                return Vec::new();
             }
         }
-        _ => panic!("We should not be parsing non-keypress events"),
+
+        let mut key_event = KeyEvent::new(parse_name_from_event(event, &self.keymap), timestamp);
+        key_event.key_id = canonical_key_id(key).map(str::to_string);
+        key_event.logical_key = key_event
+            .key_id
+            .as_deref()
+            .and_then(|id| self.layout.logical(id))
+            .map(str::to_string);
+        if let Some(typed) = self.composer.feed(event.name.as_deref()) {
+            key_event.typed_text = Some(typed.text);
+            key_event.char_class = Some(typed.class);
+        }
+        let previous = self.held.insert(
+            key,
+            HeldKey {
+                event: key_event,
+                repeats: Vec::new(),
+                last_press: timestamp,
+            },
+        );
+
+        // The release for the previous press never arrived, so its dwell is unknown
+        match previous {
+            Some(held) => {
+                warn!("missed release for {:?}", held.event.key_name);
+                held.into_events()
+            }
+            None => Vec::new(),
+        }
     }
-}
 
-/// Manually maps unknown keys to their corresponding names
-fn map_custom_unknowns(code: u32) -> Option<String> {
-    match code {
-        115 => Some("home".to_string()),
-        117 => Some("delete".to_string()),
-        62 => Some("ctrl_right".to_string()),
-        _ => None,
+    fn release(&mut self, key: Key, event: &Event) -> Vec<KeyEvent> {
+        // Keys already held when capture started have no matching press
+        let Some(mut held) = self.held.remove(&key) else {
+            return Vec::new();
+        };
+
+        held.event.dwell_ms = Some((event_millis(event.time) - held.event.timestamp).max(0));
+        info!("Key Event: {:?}", held.event);
+        held.into_events()
     }
 }
 
-// Some keys have better names for their archs
-// These function maps those keys to their better names
+pub(crate) fn event_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Parses the name of the key from the event
+pub(crate) fn parse_name_from_event(event: &Event, keymap: &KeyMap) -> String {
+    let EventType::KeyPress(key) = event.event_type else {
+        panic!("We should not be parsing non-keypress events");
+    };
+
+    if let Some(name) = keymap.name(key) {
+        return name;
+    }
 
-#[cfg(target_os = "macos")]
-fn meta_key_name(is_left: bool) -> String {
-    if is_left {
-        "command_left".to_string()
-    } else {
-        "command_right".to_string()
+    let Key::Unknown(code) = key else {
+        // Only reachable if an entry is missing from the bundled table
+        warn!("no name mapped for {:?}", key);
+        return format!("{:?}", key).to_lowercase();
+    };
+
+    // Fall back to the text the key typed, which covers keys only found on some layouts
+    match event.name.as_deref().and_then(TypedText::new) {
+        Some(typed) if typed.class != CharClass::Whitespace => typed.text,
+        _ => {
+            warn!("unknown key event: {:?}", event);
+            format!("unknown_{}", code)
+        }
     }
 }
 
-#[cfg(target_os = "macos")]
-fn alt_key_name(is_left: bool) -> String {
-    if is_left {
-        "opt_left".to_string()
-    } else {
-        "opt_right".to_string()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::naming::KeyNaming;
+    use std::time::Duration;
+
+    fn event(event_type: EventType, millis: u64, name: Option<&str>) -> Event {
+        Event {
+            time: UNIX_EPOCH + Duration::from_millis(millis),
+            name: name.map(str::to_string),
+            event_type,
+        }
+    }
+
+    #[test]
+    fn test_tracker_records_dwell_time() {
+        let mut tracker = KeyTracker::new();
+
+        assert!(tracker
+            .handle(&event(EventType::KeyPress(Key::KeyA), 1000, Some("a")))
+            .is_empty());
+
+        let key_event = tracker
+            .handle(&event(EventType::KeyRelease(Key::KeyA), 1085, None))
+            .remove(0);
+        assert_eq!(key_event.key_name, "a");
+        assert_eq!(key_event.timestamp, 1000);
+        assert_eq!(key_event.dwell_ms, Some(85));
+        assert!(!key_event.is_repeat);
+    }
+
+    #[test]
+    fn test_tracker_flags_auto_repeat() {
+        let mut tracker = KeyTracker::new();
+
+        tracker.handle(&event(EventType::KeyPress(Key::KeyA), 1000, Some("a")));
+        for t in [1500, 1530, 1560] {
+            assert!(tracker
+                .handle(&event(EventType::KeyPress(Key::KeyA), t, Some("a")))
+                .is_empty());
+        }
+
+        // The press comes first, followed by its repeats in the order they happened
+        let events = tracker.handle(&event(EventType::KeyRelease(Key::KeyA), 1600, None));
+        assert_eq!(
+            events.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+            vec![1000, 1500, 1530, 1560]
+        );
+        assert!(!events[0].is_repeat);
+        assert_eq!(events[0].dwell_ms, Some(600));
+        assert!(events[1..]
+            .iter()
+            .all(|e| e.is_repeat && e.dwell_ms.is_none()));
+    }
+
+    #[test]
+    fn test_tracker_overlapping_keys() {
+        let mut tracker = KeyTracker::new();
+
+        tracker.handle(&event(EventType::KeyPress(Key::ShiftLeft), 1000, None));
+        tracker.handle(&event(EventType::KeyPress(Key::KeyA), 1050, Some("A")));
+
+        let a = tracker
+            .handle(&event(EventType::KeyRelease(Key::KeyA), 1100, None))
+            .remove(0);
+        let shift = tracker
+            .handle(&event(EventType::KeyRelease(Key::ShiftLeft), 1200, None))
+            .remove(0);
+
+        assert_eq!((a.key_name.as_str(), a.dwell_ms), ("a", Some(50)));
+        assert_eq!(
+            (shift.key_name.as_str(), shift.dwell_ms),
+            ("shift_left", Some(200))
+        );
+    }
+
+    #[test]
+    fn test_tracker_missed_release() {
+        let mut tracker = KeyTracker::new();
+
+        tracker.handle(&event(EventType::KeyPress(Key::KeyA), 1000, Some("a")));
+        let stale = tracker
+            .handle(&event(EventType::KeyPress(Key::KeyA), 10_000, Some("a")))
+            .remove(0);
+        assert_eq!(stale.timestamp, 1000);
+        assert_eq!(stale.dwell_ms, None);
+        assert!(!stale.is_repeat);
+
+        // Releases without a press are ignored
+        assert!(tracker
+            .handle(&event(EventType::KeyRelease(Key::KeyB), 10_050, None))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_tracker_finish_flushes_held_keys() {
+        let mut tracker = KeyTracker::new();
+
+        tracker.handle(&event(EventType::KeyPress(Key::ShiftLeft), 1000, None));
+        tracker.handle(&event(EventType::KeyPress(Key::KeyA), 1050, Some("A")));
+        tracker.handle(&event(EventType::KeyPress(Key::KeyA), 1600, Some("A")));
+
+        let held = tracker
+            .finish()
+            .into_iter()
+            .map(|e| (e.key_name, e.timestamp, e.dwell_ms, e.is_repeat))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            held,
+            vec![
+                ("shift_left".to_string(), 1000, None, false),
+                ("a".to_string(), 1050, None, false),
+                ("a".to_string(), 1600, None, true),
+            ]
+        );
+        assert!(tracker.finish().is_empty());
+        assert!(!tracker.is_held(Key::KeyA));
+    }
+
+    #[test]
+    fn test_tracker_names_modifiers_per_platform() {
+        for (naming, expected) in [
+            (KeyNaming::MACOS, "command_left"),
+            (KeyNaming::LINUX, "super_left"),
+            (KeyNaming::WINDOWS, "win_left"),
+        ] {
+            let keymap = KeyMap::bundled().with_naming(naming);
+            let mut tracker = KeyTracker::with_keymap(Arc::new(keymap));
+            tracker.handle(&event(EventType::KeyPress(Key::MetaLeft), 1000, None));
+            let key_event = tracker
+                .handle(&event(EventType::KeyRelease(Key::MetaLeft), 1100, None))
+                .remove(0);
+
+            assert_eq!(key_event.key_name, expected);
+            // The identifier is the same whatever the platform calls the key
+            assert_eq!(key_event.key_id.as_deref(), Some("MetaLeft"));
+        }
+    }
+
+    #[test]
+    fn test_tracker_records_logical_key_for_layout() {
+        let layout = Layout::bundled("dvorak").unwrap();
+        let mut tracker = KeyTracker::new().with_layout(Arc::new(layout));
+
+        tracker.handle(&event(EventType::KeyPress(Key::KeyS), 1000, Some("o")));
+        tracker.handle(&event(EventType::KeyPress(Key::Space), 1010, None));
+        let s = tracker
+            .handle(&event(EventType::KeyRelease(Key::KeyS), 1050, None))
+            .remove(0);
+        let space = tracker
+            .handle(&event(EventType::KeyRelease(Key::Space), 1060, None))
+            .remove(0);
+
+        // The physical position keeps its QWERTY name, the layout gives the character
+        assert_eq!(s.key_name, "s");
+        assert_eq!(s.key_id.as_deref(), Some("KeyS"));
+        assert_eq!(s.logical_key.as_deref(), Some("o"));
+        assert_eq!(space.logical_key, None);
+    }
+
+    /// Presses and releases `key`, which the OS reports as typing `name`
+    fn tap(tracker: &mut KeyTracker, key: Key, millis: u64, name: Option<&str>) -> KeyEvent {
+        tracker.handle(&event(EventType::KeyPress(key), millis, name));
+        tracker
+            .handle(&event(EventType::KeyRelease(key), millis + 50, None))
+            .remove(0)
+    }
+
+    #[test]
+    fn test_tracker_records_normalised_text() {
+        let mut tracker = KeyTracker::new();
+
+        // Decomposed output from the OS is stored composed
+        let e = tap(&mut tracker, Key::KeyE, 1000, Some("e\u{301}"));
+        assert_eq!(e.key_name, "e");
+        assert_eq!(e.typed_text.as_deref(), Some("é"));
+        assert_eq!(e.char_class, Some(CharClass::Letter));
+
+        // Non-Latin input keeps the physical key name alongside the typed text
+        let zhe = tap(&mut tracker, Key::SemiColon, 2000, Some("ж"));
+        assert_eq!(zhe.key_name, ";");
+        assert_eq!(zhe.typed_text.as_deref(), Some("ж"));
+        assert_eq!(zhe.char_class, Some(CharClass::Letter));
+
+        // Shortcuts report control characters, which are not text
+        let ctrl_c = tap(&mut tracker, Key::KeyC, 3000, Some("\u{3}"));
+        assert_eq!(ctrl_c.typed_text, None);
+        assert_eq!(ctrl_c.char_class, None);
+    }
+
+    #[test]
+    fn test_tracker_composes_dead_keys() {
+        let mut tracker = KeyTracker::new();
+
+        // A dead key reported as a lone combining mark types nothing itself
+        let dead = tap(&mut tracker, Key::LeftBracket, 1000, Some("\u{302}"));
+        assert_eq!(dead.typed_text, None);
+
+        let a = tap(&mut tracker, Key::KeyA, 2000, Some("a"));
+        assert_eq!(a.typed_text.as_deref(), Some("â"));
+
+        // A dead key reported without text, then the composed character
+        tap(&mut tracker, Key::LeftBracket, 3000, None);
+        let u = tap(&mut tracker, Key::KeyU, 4000, Some("ü"));
+        assert_eq!(u.typed_text.as_deref(), Some("ü"));
+    }
+
+    #[test]
+    fn test_tracker_repeat_keeps_typed_text() {
+        let mut tracker = KeyTracker::new();
+
+        tracker.handle(&event(EventType::KeyPress(Key::KeyE), 1000, Some("é")));
+        tracker.handle(&event(EventType::KeyPress(Key::KeyE), 1500, Some("é")));
+        let repeat = tracker
+            .handle(&event(EventType::KeyRelease(Key::KeyE), 1600, None))
+            .remove(1);
+        assert!(repeat.is_repeat);
+        assert_eq!(repeat.typed_text.as_deref(), Some("é"));
+    }
+
+    #[test]
+    fn test_unknown_key_named_by_typed_text() {
+        let keymap = KeyMap::bundled();
+        let unknown = |name: Option<&str>| {
+            parse_name_from_event(
+                &event(EventType::KeyPress(Key::Unknown(999)), 0, name),
+                &keymap,
+            )
+        };
+
+        assert_eq!(unknown(Some("e\u{301}")), "é");
+        assert_eq!(unknown(Some("ß")), "ß");
+        assert_eq!(unknown(Some("¿")), "¿");
+        assert_eq!(unknown(Some("\u{7}")), "unknown_999");
+        assert_eq!(unknown(Some(" ")), "unknown_999");
+        assert_eq!(unknown(None), "unknown_999");
     }
 }
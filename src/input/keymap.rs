@@ -0,0 +1,217 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use rdev::Key;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use super::naming::{canonical_key_id, is_canonical_key_id, KeyNaming};
+use crate::config::expand_home;
+
+/// The mapping table shipped with MetMac
+const BUNDLED: &str = include_str!("keymap.toml");
+
+/// Display names for keys, read from the bundled table and optionally extended by a user
+/// override file of the same format:
+///
+/// ```toml
+/// [keys]
+/// CapsLock = "hyper"
+///
+/// [unknown]
+/// 179 = "mic_mute"
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap {
+    naming: KeyNaming,
+    /// Names by canonical key id
+    keys: HashMap<String, String>,
+    /// Names by the raw code of keys rdev reports as `Key::Unknown`
+    unknown: HashMap<u32, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct KeyMapFile {
+    keys: HashMap<String, String>,
+    unknown: HashMap<String, String>,
+}
+
+impl KeyMap {
+    pub fn bundled() -> Self {
+        Self::parse(BUNDLED).expect("bundled keymap is valid")
+    }
+
+    /// The bundled table with the entries of `override_path` on top, if given
+    pub fn load(override_path: Option<&Path>) -> Result<Self> {
+        let mut keymap = Self::bundled();
+
+        if let Some(path) = override_path {
+            let path = expand_home(path)?;
+            info!("Loading key mapping overrides from {:?}", path);
+
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read keymap {:?}", path))?;
+            let overrides =
+                Self::parse(&contents).with_context(|| format!("Invalid keymap {:?}", path))?;
+            keymap.extend(overrides);
+        }
+
+        Ok(keymap)
+    }
+
+    /// Reads a keymap, failing on keys in `[keys]` that are not canonical key ids, as
+    /// their entries would never match
+    pub fn parse(contents: &str) -> Result<Self> {
+        let file: KeyMapFile = toml::from_str(contents)?;
+
+        let mut unknown_ids = file
+            .keys
+            .keys()
+            .map(String::as_str)
+            .filter(|id| !is_canonical_key_id(id))
+            .collect::<Vec<_>>();
+        if !unknown_ids.is_empty() {
+            unknown_ids.sort_unstable();
+            bail!(
+                "Unknown key ids in [keys]: {}, expected ids such as KeyA, CapsLock or \
+                 MetaLeft",
+                unknown_ids.join(", ")
+            );
+        }
+
+        let unknown = file
+            .unknown
+            .into_iter()
+            .map(|(code, name)| {
+                let code = code
+                    .parse()
+                    .with_context(|| format!("Unknown key code {:?} is not a number", code))?;
+                Ok((code, name))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            naming: KeyNaming::default(),
+            keys: file.keys,
+            unknown,
+        })
+    }
+
+    /// Adds the entries of `other`, replacing any with the same key
+    pub fn extend(&mut self, other: KeyMap) {
+        self.keys.extend(other.keys);
+        self.unknown.extend(other.unknown);
+    }
+
+    /// Uses `naming` for the keys named differently on each platform
+    pub fn with_naming(mut self, naming: KeyNaming) -> Self {
+        self.naming = naming;
+        self
+    }
+
+    pub fn naming(&self) -> &KeyNaming {
+        &self.naming
+    }
+
+    /// The display name of `key`, `None` if nothing maps it. Entries in the table win over
+    /// the platform's names for meta and alt, which it leaves out unless overridden
+    pub fn name(&self, key: Key) -> Option<String> {
+        let name = match key {
+            Key::Unknown(code) => self.unknown_name(code),
+            key => canonical_key_id(key).and_then(|id| self.keys.get(id).map(String::as_str)),
+        };
+
+        name.or_else(|| self.naming.name(key)).map(str::to_string)
+    }
+
+    /// The canonical id of the key named `name`, `None` for names not in the table
+    pub fn key_id(&self, name: &str) -> Option<&str> {
+        self.keys
+            .iter()
+            .find(|(_, key_name)| *key_name == name)
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// The name mapped to a raw unknown key code
+    pub fn unknown_name(&self, code: u32) -> Option<&str> {
+        self.unknown.get(&code).map(String::as_str)
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self::bundled()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_bundled_keymap_names_every_known_key() {
+        let keymap = KeyMap::bundled().with_naming(KeyNaming::MACOS);
+
+        assert_eq!(keymap.name(Key::KeyA).as_deref(), Some("a"));
+        assert_eq!(keymap.name(Key::BackSlash).as_deref(), Some("\\"));
+        assert_eq!(keymap.name(Key::KpReturn).as_deref(), Some("numpad_enter"));
+        assert_eq!(keymap.name(Key::Alt).as_deref(), Some("opt_left"));
+        assert_eq!(keymap.name(Key::Unknown(115)).as_deref(), Some("home"));
+        assert_eq!(keymap.name(Key::Unknown(999)), None);
+        assert_eq!(keymap.keys.len(), 101);
+    }
+
+    #[test]
+    fn test_override_file_extends_bundled_table() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(
+            file,
+            r#"
+            [keys]
+            CapsLock = "hyper"
+
+            [unknown]
+            179 = "mic_mute"
+            "#
+        )?;
+
+        let keymap = KeyMap::load(Some(file.path()))?;
+        assert_eq!(keymap.name(Key::CapsLock).as_deref(), Some("hyper"));
+        assert_eq!(keymap.name(Key::Unknown(179)).as_deref(), Some("mic_mute"));
+        assert_eq!(keymap.name(Key::KeyQ).as_deref(), Some("q"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_override_rejects_non_numeric_codes() {
+        assert!(KeyMap::parse("[unknown]\nabc = \"x\"\n").is_err());
+    }
+
+    #[test]
+    fn test_override_names_platform_keys() -> Result<()> {
+        let mut keymap = KeyMap::bundled().with_naming(KeyNaming::LINUX);
+        keymap.extend(KeyMap::parse("[keys]\nMetaLeft = \"cmd\"\n")?);
+
+        assert_eq!(keymap.name(Key::MetaLeft).as_deref(), Some("cmd"));
+        assert_eq!(keymap.name(Key::MetaRight).as_deref(), Some("super_right"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_override_rejects_unknown_key_ids() {
+        let error = KeyMap::parse("[keys]\nCapslock = \"hyper\"\nKeyA = \"a\"\n").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Unknown key ids in [keys]: Capslock,"),
+            "{}",
+            error
+        );
+    }
+}
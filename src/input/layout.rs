@@ -0,0 +1,129 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config::expand_home;
+
+/// Layouts shipped with MetMac, by the name used to select them
+const BUNDLED: &[(&str, &str)] = &[
+    ("qwerty", include_str!("layouts/qwerty.toml")),
+    ("dvorak", include_str!("layouts/dvorak.toml")),
+    ("colemak", include_str!("layouts/colemak.toml")),
+    ("azerty", include_str!("layouts/azerty.toml")),
+];
+
+/// A keyboard layout, mapping physical keys to the characters they type.
+///
+/// Key names and ids describe where a key sits on a QWERTY board, the layout says what
+/// it types for the user. Layout files look like:
+///
+/// ```toml
+/// name = "Dvorak"
+///
+/// [keys]
+/// KeyQ = "'"
+/// KeyW = ","
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    pub name: String,
+    /// Characters typed without modifiers, by canonical key id
+    pub keys: HashMap<String, String>,
+}
+
+impl Layout {
+    /// Names of the bundled layouts
+    pub fn bundled_names() -> impl Iterator<Item = &'static str> {
+        BUNDLED.iter().map(|(name, _)| *name)
+    }
+
+    pub fn bundled(name: &str) -> Option<Self> {
+        BUNDLED
+            .iter()
+            .find(|(bundled, _)| bundled.eq_ignore_ascii_case(name))
+            .map(|(_, contents)| Self::parse(contents).expect("bundled layout is valid"))
+    }
+
+    /// Resolves `layout`, the name of a bundled layout or the path of a layout file
+    pub fn resolve(layout: &str) -> Result<Self> {
+        if let Some(bundled) = Self::bundled(layout) {
+            return Ok(bundled);
+        }
+
+        let path = expand_home(Path::new(layout))?;
+        if !path.exists() {
+            bail!(
+                "Unknown layout {:?}, expected one of {} or a layout file",
+                layout,
+                Self::bundled_names().collect::<Vec<_>>().join(", ")
+            );
+        }
+
+        info!("Loading keyboard layout from {:?}", path);
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read layout {:?}", path))?;
+        Self::parse(&contents).with_context(|| format!("Invalid layout {:?}", path))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// The character the key with `key_id` types, `None` for keys that do not type one
+    pub fn logical(&self, key_id: &str) -> Option<&str> {
+        self.keys.get(key_id).map(String::as_str)
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::bundled("qwerty").expect("qwerty is bundled")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_bundled_layouts() {
+        for name in Layout::bundled_names() {
+            let layout = Layout::bundled(name).unwrap();
+            // Every layout covers the same 48 character keys
+            assert_eq!(layout.keys.len(), 48, "{}", name);
+        }
+
+        let typed = |layout: &str| {
+            let layout = Layout::bundled(layout).unwrap();
+            ["KeyQ", "KeyS", "KeyE", "KeyM"]
+                .iter()
+                .map(|id| layout.logical(id).unwrap())
+                .collect::<String>()
+        };
+        assert_eq!(typed("qwerty"), "qsem");
+        assert_eq!(typed("dvorak"), "'o.m");
+        assert_eq!(typed("colemak"), "qrfm");
+        assert_eq!(typed("AZERTY"), "ase,");
+    }
+
+    #[test]
+    fn test_resolve_layout_file() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "name = \"Custom\"\n[keys]\nKeyQ = \"x\"")?;
+
+        let layout = Layout::resolve(file.path().to_str().unwrap())?;
+        assert_eq!(layout.name, "Custom");
+        assert_eq!(layout.logical("KeyQ"), Some("x"));
+        assert_eq!(layout.logical("KeyW"), None);
+
+        assert!(Layout::resolve("workman").is_err());
+
+        Ok(())
+    }
+}
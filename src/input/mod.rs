@@ -1 +1,521 @@
+#[cfg(target_os = "linux")]
+pub mod evdev;
+pub mod focus;
 pub mod keyboard;
+pub mod keymap;
+pub mod layout;
+pub mod modifiers;
+pub mod mouse;
+pub mod naming;
+pub mod pause;
+pub mod source;
+pub mod text;
+
+use anyhow::Result;
+use rdev::{Event, EventType};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::models::events::{InputEvent, KeyEvent, ShortcutEvent};
+use crate::privacy::PrivacyLevel;
+use focus::{redact_title, FocusProvider};
+use keyboard::{event_millis, parse_name_from_event, KeyTracker};
+use keymap::KeyMap;
+use layout::Layout;
+use modifiers::{is_modifier, normalize_combo, ModifierState};
+use mouse::MouseTracker;
+use pause::{PauseSource, PauseState};
+
+/// Settings of an [`InputProcessor`] that can change while capture runs
+#[derive(Debug, Clone, Default)]
+pub struct ProcessorSettings {
+    pub keymap: Arc<KeyMap>,
+    pub layout: Arc<Layout>,
+    pub privacy: PrivacyLevel,
+    /// Normalised combo that pauses and resumes capture
+    pub pause_hotkey: Option<String>,
+    pub pause_timeout: Option<Duration>,
+}
+
+impl ProcessorSettings {
+    /// Loads the keymap and layout and checks the pause hotkey the config names
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let input = &config.input;
+
+        Ok(Self {
+            keymap: Arc::new(KeyMap::load(input.keymap_path.as_deref())?),
+            layout: Arc::new(Layout::resolve(&input.layout)?),
+            privacy: config.privacy.level,
+            pause_hotkey: input
+                .pause_hotkey
+                .as_deref()
+                .map(normalize_combo)
+                .transpose()?,
+            pause_timeout: input.pause_timeout_secs.map(Duration::from_secs),
+        })
+    }
+}
+
+/// Turns raw input events into everything that gets persisted
+#[derive(Default)]
+pub struct InputProcessor {
+    focus: Option<Box<dyn FocusProvider>>,
+    /// Record redacted window titles along with the focused application
+    window_titles: bool,
+    keys: KeyTracker,
+    modifiers: ModifierState,
+    mouse: MouseTracker,
+    privacy: PrivacyLevel,
+    pause: PauseState,
+    /// Combo that pauses and resumes capture, as named by `ModifierState::combo`
+    pause_hotkey: Option<String>,
+}
+
+impl InputProcessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_settings(settings: ProcessorSettings) -> Self {
+        let mut processor = Self::default();
+        processor.apply(settings);
+        processor
+    }
+
+    /// Switches to new settings without losing track of held keys or a running pause
+    pub fn apply(&mut self, settings: ProcessorSettings) {
+        self.keys.set_keymap(settings.keymap, settings.layout);
+        self.privacy = settings.privacy;
+        self.pause_hotkey = settings.pause_hotkey;
+        self.pause.set_auto_resume(settings.pause_timeout);
+    }
+
+    /// Names keys with `keymap` instead of the bundled table
+    pub fn with_keymap(keymap: Arc<KeyMap>) -> Self {
+        Self {
+            keys: KeyTracker::with_keymap(keymap),
+            ..Self::default()
+        }
+    }
+
+    /// Records the characters typed under `layout` instead of QWERTY
+    pub fn with_layout(mut self, layout: Arc<Layout>) -> Self {
+        self.keys = self.keys.with_layout(layout);
+        self
+    }
+
+    /// Stores only what `privacy` allows instead of every key
+    pub fn with_privacy(mut self, privacy: PrivacyLevel) -> Self {
+        self.privacy = privacy;
+        self
+    }
+
+    /// Toggles capture with `hotkey`, a normalised combo, and resumes a pause by itself
+    /// after `auto_resume`
+    pub fn with_pause(mut self, hotkey: Option<String>, auto_resume: Option<Duration>) -> Self {
+        self.pause_hotkey = hotkey;
+        self.pause = PauseState::new(auto_resume);
+        self
+    }
+
+    /// Tags keystrokes with the application `focus` reports, and its redacted window title
+    /// if `window_titles` is set
+    pub fn with_focus(mut self, focus: Box<dyn FocusProvider>, window_titles: bool) -> Self {
+        self.focus = Some(focus);
+        self.window_titles = window_titles;
+        self
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    /// When the current pause ends by itself, if it does
+    pub fn resume_at(&self) -> Option<i64> {
+        self.pause.resume_at()
+    }
+
+    /// Stops recording input until [`resume`](Self::resume), returning the mouse activity
+    /// aggregated so far
+    pub fn pause(&mut self, now: SystemTime, source: PauseSource) -> Vec<InputEvent> {
+        let now = event_millis(now);
+        if !self.pause.pause(now, source) {
+            return Vec::new();
+        }
+
+        self.mouse
+            .take(now)
+            .map(InputEvent::from)
+            .into_iter()
+            .collect()
+    }
+
+    /// Resumes recording, returning the pause that ended
+    pub fn resume(&mut self, now: SystemTime) -> Vec<InputEvent> {
+        self.pause
+            .resume(event_millis(now))
+            .map(InputEvent::from)
+            .into_iter()
+            .collect()
+    }
+
+    /// Resumes if the pause has timed out, for when no input arrives to notice it
+    pub fn expire(&mut self, now: SystemTime) -> Vec<InputEvent> {
+        self.pause
+            .expire(event_millis(now))
+            .map(InputEvent::from)
+            .into_iter()
+            .collect()
+    }
+
+    pub fn process(&mut self, event: &Event) -> Vec<InputEvent> {
+        let now = event_millis(event.time);
+        let mut events = self.expire(event.time);
+        let mut hotkey = false;
+
+        if let EventType::KeyPress(key) = event.event_type {
+            // Auto-repeat of a held chord is not another use of the shortcut
+            if !is_modifier(key) && !self.keys.is_held(key) && self.modifiers.is_chord() {
+                let name = parse_name_from_event(event, self.keys.keymap());
+                let combo = self.modifiers.combo(&name);
+                if self.pause_hotkey.as_ref() == Some(&combo) {
+                    hotkey = true;
+                } else if self.pause.is_recording(now) {
+                    events.push(ShortcutEvent::new(combo, now).into());
+                }
+            }
+        }
+
+        // Keys are still tracked while paused, so held keys are known on resume
+        self.modifiers.update(event);
+
+        let keys = self.keys.handle(event);
+        events.extend(self.record_keys(keys));
+
+        if !self.pause.is_paused() {
+            if let Some(activity) = self.mouse.handle(event) {
+                events.push(activity.into());
+            }
+        }
+
+        if hotkey {
+            if self.pause.is_paused() {
+                events.extend(self.resume(event.time));
+            } else {
+                events.extend(self.pause(event.time, PauseSource::Hotkey));
+            }
+        }
+
+        events
+            .into_iter()
+            .filter_map(|event| self.privacy.apply(event))
+            .collect()
+    }
+
+    /// Tags keystrokes with the application focused when they were pressed, dropping those
+    /// typed while paused
+    fn record_keys(&mut self, keys: Vec<KeyEvent>) -> Vec<InputEvent> {
+        // Redaction misses names and document titles, so only the application is kept
+        // below the full privacy level
+        let window_titles = self.window_titles && self.privacy == PrivacyLevel::Full;
+
+        let mut events = Vec::new();
+        for mut key_event in keys {
+            if !self.pause.is_recording(key_event.timestamp) {
+                continue;
+            }
+
+            let pressed = UNIX_EPOCH + Duration::from_millis(key_event.timestamp.max(0) as u64);
+            if let Some(focus) = self.focus.as_mut().and_then(|focus| focus.focused(pressed)) {
+                key_event.application = Some(focus.application);
+                key_event.window_title = focus
+                    .window_title
+                    .filter(|_| window_titles)
+                    .map(|title| redact_title(&title));
+            }
+            events.push(key_event.into());
+        }
+
+        events
+    }
+
+    /// Emits the mouse activity aggregated so far, for when no further input arrives to
+    /// close its bucket
+    pub fn tick(&mut self, now: SystemTime) -> Vec<InputEvent> {
+        self.mouse
+            .take(event_millis(now))
+            .map(InputEvent::from)
+            .into_iter()
+            .filter_map(|event| self.privacy.apply(event))
+            .collect()
+    }
+
+    /// Emits whatever is still being aggregated, called when the input source stops
+    pub fn finish(&mut self, now: SystemTime) -> Vec<InputEvent> {
+        // Keys held as capture stops were pressed all the same
+        let keys = self.keys.finish();
+        let mut events = self.record_keys(keys);
+        // A pause still running ends with capture
+        events.extend(self.resume(now));
+        events.extend(self.mouse.take(event_millis(now)).map(InputEvent::from));
+
+        events
+            .into_iter()
+            .filter_map(|event| self.privacy.apply(event))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::events::PauseInterval;
+    use rdev::{Button, Key};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn event(event_type: EventType, millis: u64) -> Event {
+        Event {
+            time: UNIX_EPOCH + Duration::from_millis(millis),
+            name: None,
+            event_type,
+        }
+    }
+
+    #[test]
+    fn test_processor_records_shortcuts() {
+        let mut processor = InputProcessor::new();
+
+        let sequence = [
+            EventType::KeyPress(Key::ControlLeft),
+            EventType::KeyPress(Key::ShiftLeft),
+            EventType::KeyPress(Key::KeyT),
+            EventType::KeyPress(Key::KeyT), // auto-repeat
+            EventType::KeyRelease(Key::KeyT),
+            EventType::KeyRelease(Key::ShiftLeft),
+            EventType::KeyRelease(Key::ControlLeft),
+            EventType::KeyPress(Key::KeyT),
+        ];
+
+        let events = sequence
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, event_type)| processor.process(&event(event_type, i as u64 * 10)))
+            .collect::<Vec<_>>();
+
+        let shortcuts = events
+            .iter()
+            .filter_map(|event| match event {
+                InputEvent::Shortcut(shortcut) => Some(shortcut),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            shortcuts,
+            vec![&ShortcutEvent::new("ctrl+shift+t".to_string(), 20)]
+        );
+
+        // The individual keys are still recorded
+        let keys = events
+            .iter()
+            .filter(|event| matches!(event, InputEvent::Key(_)))
+            .count();
+        assert_eq!(keys, 4);
+    }
+
+    #[test]
+    fn test_processor_applies_privacy_level() {
+        let mut processor = InputProcessor::new().with_privacy(PrivacyLevel::Counts);
+
+        let sequence = [
+            EventType::KeyPress(Key::ControlLeft),
+            EventType::KeyPress(Key::KeyC),
+            EventType::KeyRelease(Key::KeyC),
+            EventType::KeyRelease(Key::ControlLeft),
+        ];
+
+        let events = sequence
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, event_type)| processor.process(&event(event_type, i as u64 * 10)))
+            .collect::<Vec<_>>();
+
+        // No shortcut, and nothing about which keys were pressed
+        assert_eq!(events.len(), 2);
+        for event in events {
+            let InputEvent::Key(key) = event else {
+                panic!("expected only key events, got {:?}", event);
+            };
+            assert_eq!(key.key_name, crate::privacy::COUNTED_KEY_NAME);
+            assert_eq!(key.key_id, None);
+        }
+    }
+
+    #[test]
+    fn test_processor_pause_hotkey() {
+        let mut processor = InputProcessor::new().with_pause(Some("ctrl+alt+p".to_string()), None);
+
+        let hotkey = [
+            EventType::KeyPress(Key::ControlLeft),
+            EventType::KeyPress(Key::Alt),
+            EventType::KeyPress(Key::KeyP),
+            EventType::KeyRelease(Key::KeyP),
+            EventType::KeyRelease(Key::Alt),
+            EventType::KeyRelease(Key::ControlLeft),
+        ];
+        let typing = [
+            EventType::KeyPress(Key::KeyA),
+            EventType::KeyRelease(Key::KeyA),
+        ];
+
+        let mut time = 0;
+        let mut run = |processor: &mut InputProcessor, sequence: &[EventType]| {
+            sequence
+                .iter()
+                .flat_map(|event_type| {
+                    time += 10;
+                    processor.process(&event(*event_type, time))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        run(&mut processor, &hotkey);
+        assert!(processor.is_paused());
+
+        // Nothing typed while paused is recorded, and the hotkey itself never is
+        assert!(run(&mut processor, &typing).is_empty());
+
+        let resumed = run(&mut processor, &hotkey);
+        assert!(!processor.is_paused());
+        assert_eq!(
+            resumed,
+            vec![PauseInterval {
+                started_at: 30,
+                ended_at: 110,
+                paused_by: PauseSource::Hotkey,
+            }
+            .into()]
+        );
+
+        let typed = run(&mut processor, &typing);
+        assert!(matches!(&typed[..], [InputEvent::Key(key)] if key.key_name == "a"));
+    }
+
+    #[test]
+    fn test_processor_tags_focused_application() {
+        use focus::{Focus, ScriptedFocus};
+
+        let focus = ScriptedFocus::new()
+            .then(
+                UNIX_EPOCH,
+                Focus::new("code").with_title("main.rs - 2 changes"),
+            )
+            .then(
+                UNIX_EPOCH + Duration::from_millis(100),
+                Focus::new("firefox"),
+            );
+        let typed = |privacy| {
+            let mut processor = InputProcessor::new()
+                .with_privacy(privacy)
+                .with_focus(Box::new(focus.clone()), true);
+
+            // The second key is released after the focus moved, but went to the first window
+            [0, 90, 150]
+                .into_iter()
+                .flat_map(|millis| {
+                    let mut events =
+                        processor.process(&event(EventType::KeyPress(Key::KeyA), millis));
+                    events.extend(
+                        processor.process(&event(EventType::KeyRelease(Key::KeyA), millis + 20)),
+                    );
+                    events
+                })
+                .filter_map(|event| match event {
+                    InputEvent::Key(key) => Some((key.application, key.window_title)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let code = (
+            Some("code".to_string()),
+            Some("main.rs - # changes".to_string()),
+        );
+        assert_eq!(
+            typed(PrivacyLevel::Full),
+            vec![code.clone(), code, (Some("firefox".to_string()), None)]
+        );
+
+        // Below full, titles are not kept even when asked for
+        assert!(typed(PrivacyLevel::KeyClass)
+            .iter()
+            .all(|(application, title)| application.is_some() && title.is_none()));
+    }
+
+    #[test]
+    fn test_processor_finish_flushes_held_keys() {
+        let mut processor = InputProcessor::new().with_privacy(PrivacyLevel::Counts);
+
+        processor.process(&event(EventType::KeyPress(Key::KeyA), 100));
+        processor.process(&event(EventType::KeyPress(Key::KeyB), 150));
+        processor.process(&event(EventType::KeyRelease(Key::KeyB), 200));
+
+        let events = processor.finish(UNIX_EPOCH + Duration::from_millis(500));
+        let [InputEvent::Key(key)] = &events[..] else {
+            panic!("expected the held key, got {:?}", events);
+        };
+        assert_eq!(key.timestamp, 100);
+        assert_eq!(key.dwell_ms, None);
+        // Still stored at the configured privacy level
+        assert_eq!(key.key_name, crate::privacy::COUNTED_KEY_NAME);
+    }
+
+    #[test]
+    fn test_processor_emits_mouse_activity_without_further_input() {
+        let mut processor = InputProcessor::new();
+        let start = 60_000 * 1000;
+
+        processor.process(&event(EventType::ButtonPress(Button::Left), start));
+        processor.process(&event(EventType::ButtonPress(Button::Left), start + 100));
+        processor.process(&event(EventType::ButtonPress(Button::Right), start + 200));
+
+        let clicks = |events: Vec<InputEvent>| match &events[..] {
+            [InputEvent::Mouse(activity)] => (activity.left_clicks, activity.right_clicks),
+            _ => panic!("expected mouse activity, got {:?}", events),
+        };
+        assert_eq!(
+            clicks(processor.tick(UNIX_EPOCH + Duration::from_millis(start + 3000))),
+            (1, 1)
+        );
+        assert!(processor
+            .tick(UNIX_EPOCH + Duration::from_millis(start + 6000))
+            .is_empty());
+
+        processor.process(&event(EventType::ButtonPress(Button::Left), start + 7000));
+        assert_eq!(
+            clicks(processor.finish(UNIX_EPOCH + Duration::from_millis(start + 8000))),
+            (1, 0)
+        );
+    }
+
+    #[test]
+    fn test_processor_finish_ends_pause() {
+        let mut processor = InputProcessor::new();
+
+        processor.pause(
+            UNIX_EPOCH + Duration::from_millis(100),
+            PauseSource::Command,
+        );
+        let events = processor.finish(UNIX_EPOCH + Duration::from_millis(500));
+        assert_eq!(
+            events,
+            vec![PauseInterval {
+                started_at: 100,
+                ended_at: 500,
+                paused_by: PauseSource::Command,
+            }
+            .into()]
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{bail, Result};
+use rdev::{Event, EventType, Key};
+
+/// Whether right alt is AltGr, which types characters such as `@` or `€` on most non-US
+/// layouts rather than making shortcuts. On macOS both option keys are alike
+const RIGHT_ALT_IS_ALTGR: bool = !cfg!(target_os = "macos");
+
+/// Modifier names used in combos, in the order [`ModifierState::combo`] writes them
+pub const COMBO_MODIFIERS: [&str; 4] = ["ctrl", "alt", "shift", "meta"];
+
+pub fn is_modifier(key: Key) -> bool {
+    matches!(
+        key,
+        Key::ControlLeft
+            | Key::ControlRight
+            | Key::Alt
+            | Key::AltGr
+            | Key::ShiftLeft
+            | Key::ShiftRight
+            | Key::MetaLeft
+            | Key::MetaRight
+    )
+}
+
+/// Modifier keys currently held down, tracked from press and release events
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ModifierState {
+    ctrl_left: bool,
+    ctrl_right: bool,
+    alt_left: bool,
+    alt_right: bool,
+    shift_left: bool,
+    shift_right: bool,
+    meta_left: bool,
+    meta_right: bool,
+}
+
+impl ModifierState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the held modifiers, returns true if the event was for a modifier key
+    pub fn update(&mut self, event: &Event) -> bool {
+        let (key, pressed) = match event.event_type {
+            EventType::KeyPress(key) => (key, true),
+            EventType::KeyRelease(key) => (key, false),
+            _ => return false,
+        };
+
+        if !is_modifier(key) {
+            return false;
+        }
+
+        let slot = match key {
+            Key::ControlLeft => &mut self.ctrl_left,
+            Key::ControlRight => &mut self.ctrl_right,
+            Key::Alt => &mut self.alt_left,
+            Key::AltGr => &mut self.alt_right,
+            Key::ShiftLeft => &mut self.shift_left,
+            Key::ShiftRight => &mut self.shift_right,
+            Key::MetaLeft => &mut self.meta_left,
+            Key::MetaRight => &mut self.meta_right,
+            _ => return false,
+        };
+        *slot = pressed;
+        true
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.ctrl_left || self.ctrl_right
+    }
+
+    /// Whether an alt key that makes shortcuts is held, AltGr does not
+    pub fn alt(&self) -> bool {
+        self.alt_left || (self.alt_right && !RIGHT_ALT_IS_ALTGR)
+    }
+
+    pub fn shift(&self) -> bool {
+        self.shift_left || self.shift_right
+    }
+
+    pub fn meta(&self) -> bool {
+        self.meta_left || self.meta_right
+    }
+
+    /// Shift on its own only changes the character typed, so it does not make a shortcut
+    pub fn is_chord(&self) -> bool {
+        self.ctrl() || self.alt() || self.meta()
+    }
+
+    /// Builds the shortcut name for `key_name` pressed with the held modifiers,
+    /// always in the order ctrl, alt, shift, meta
+    pub fn combo(&self, key_name: &str) -> String {
+        let mut parts = Vec::with_capacity(5);
+        if self.ctrl() {
+            parts.push("ctrl");
+        }
+        if self.alt() {
+            parts.push("alt");
+        }
+        if self.shift() {
+            parts.push("shift");
+        }
+        if self.meta() {
+            parts.push("meta");
+        }
+        parts.push(key_name);
+        parts.join("+")
+    }
+}
+
+/// Rewrites a user supplied combo such as `Alt+Ctrl+P` the way [`ModifierState::combo`]
+/// names it, `ctrl+alt+p`
+pub fn normalize_combo(combo: &str) -> Result<String> {
+    let lowered = combo.trim().to_lowercase();
+    let Some((modifiers, key)) = lowered.rsplit_once('+') else {
+        bail!("Shortcut {:?} needs at least one modifier", combo);
+    };
+
+    let modifiers = modifiers.split('+').map(str::trim).collect::<Vec<_>>();
+    if let Some(unknown) = modifiers.iter().find(|m| !COMBO_MODIFIERS.contains(m)) {
+        bail!(
+            "Unknown modifier {:?} in {:?}, expected {}",
+            unknown,
+            combo,
+            COMBO_MODIFIERS.join(", ")
+        );
+    }
+    // Shift on its own is not a chord, so such a shortcut would never be seen
+    if modifiers.iter().all(|m| *m == "shift") {
+        bail!("Shortcut {:?} needs ctrl, alt or meta", combo);
+    }
+    if key.trim().is_empty() {
+        bail!("Shortcut {:?} has no key", combo);
+    }
+
+    let mut parts = COMBO_MODIFIERS
+        .into_iter()
+        .filter(|m| modifiers.contains(m))
+        .collect::<Vec<_>>();
+    parts.push(key.trim());
+    Ok(parts.join("+"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn event(event_type: EventType) -> Event {
+        Event {
+            time: SystemTime::now(),
+            name: None,
+            event_type,
+        }
+    }
+
+    #[test]
+    fn test_modifier_state_tracks_both_sides() {
+        let mut state = ModifierState::new();
+
+        assert!(state.update(&event(EventType::KeyPress(Key::ControlLeft))));
+        assert!(state.update(&event(EventType::KeyPress(Key::ControlRight))));
+        assert!(state.update(&event(EventType::KeyRelease(Key::ControlLeft))));
+        assert!(state.ctrl());
+
+        state.update(&event(EventType::KeyRelease(Key::ControlRight)));
+        assert!(!state.ctrl());
+
+        assert!(!state.update(&event(EventType::KeyPress(Key::KeyA))));
+    }
+
+    #[test]
+    fn test_modifier_state_combo() {
+        let mut state = ModifierState::new();
+        state.update(&event(EventType::KeyPress(Key::ShiftRight)));
+        assert!(!state.is_chord());
+
+        state.update(&event(EventType::KeyPress(Key::ControlLeft)));
+        assert!(state.is_chord());
+        assert_eq!(state.combo("t"), "ctrl+shift+t");
+
+        state.update(&event(EventType::KeyPress(Key::MetaLeft)));
+        state.update(&event(EventType::KeyPress(Key::Alt)));
+        assert_eq!(state.combo("c"), "ctrl+alt+shift+meta+c");
+    }
+
+    #[test]
+    fn test_altgr_is_not_a_chord() {
+        let mut state = ModifierState::new();
+        state.update(&event(EventType::KeyPress(Key::AltGr)));
+
+        if cfg!(target_os = "macos") {
+            assert_eq!(state.combo("q"), "alt+q");
+        } else {
+            // AltGr+Q types `@` on a German layout
+            assert!(!state.is_chord());
+            assert_eq!(state.combo("q"), "q");
+
+            state.update(&event(EventType::KeyPress(Key::ControlLeft)));
+            assert_eq!(state.combo("q"), "ctrl+q");
+        }
+    }
+
+    #[test]
+    fn test_normalize_combo() {
+        assert_eq!(normalize_combo("Shift+Ctrl+P").unwrap(), "ctrl+shift+p");
+        assert_eq!(
+            normalize_combo("meta + alt + pause").unwrap(),
+            "alt+meta+pause"
+        );
+
+        assert!(normalize_combo("p").is_err());
+        assert!(normalize_combo("shift+p").is_err());
+        assert!(normalize_combo("hyper+p").is_err());
+        assert!(normalize_combo("ctrl+").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use rdev::{Button, Event, EventType};
+
+use super::keyboard::event_millis;
+use crate::models::events::MouseActivity;
+
+const BUCKET_MS: i64 = 60 * 1000;
+
+/// How often a partial aggregate is emitted, so a quiet stop loses little activity
+const EMIT_INTERVAL_MS: i64 = 5 * 1000;
+
+/// Aggregates clicks, scrolling and cursor travel into per-minute buckets
+#[derive(Default)]
+pub struct MouseTracker {
+    current: Option<MouseActivity>,
+    last_emit: i64,
+    last_position: Option<(f64, f64)>,
+}
+
+impl MouseTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a mouse event, returning an aggregate once its bucket closes or it has been
+    /// held for a few seconds
+    pub fn handle(&mut self, event: &Event) -> Option<MouseActivity> {
+        if !matches!(
+            event.event_type,
+            EventType::ButtonPress(_) | EventType::MouseMove { .. } | EventType::Wheel { .. }
+        ) {
+            return None;
+        }
+
+        let timestamp = event_millis(event.time);
+        let bucket_start = timestamp - timestamp.rem_euclid(BUCKET_MS);
+
+        let finished = match &self.current {
+            Some(current) if current.bucket_start != bucket_start => self.take(timestamp),
+            _ => None,
+        };
+
+        let current = self
+            .current
+            .get_or_insert_with(|| MouseActivity::new(bucket_start));
+        match event.event_type {
+            EventType::ButtonPress(button) => match button {
+                Button::Left => current.left_clicks += 1,
+                Button::Right => current.right_clicks += 1,
+                Button::Middle => current.middle_clicks += 1,
+                Button::Unknown(_) => current.other_clicks += 1,
+            },
+            EventType::Wheel { delta_x, delta_y } => {
+                current.scroll_x += delta_x.abs();
+                current.scroll_y += delta_y.abs();
+            }
+            EventType::MouseMove { x, y } => {
+                if let Some((last_x, last_y)) = self.last_position {
+                    current.travel_px += (x - last_x).hypot(y - last_y);
+                }
+                self.last_position = Some((x, y));
+            }
+            _ => {}
+        }
+
+        if finished.is_some() {
+            return finished;
+        }
+        if timestamp - self.last_emit >= EMIT_INTERVAL_MS {
+            return self.take(timestamp);
+        }
+        None
+    }
+
+    /// Takes whatever has been aggregated so far
+    pub fn take(&mut self, now: i64) -> Option<MouseActivity> {
+        self.last_emit = now;
+        self.current.take().filter(|activity| !activity.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn event(event_type: EventType, millis: u64) -> Event {
+        Event {
+            time: UNIX_EPOCH + Duration::from_millis(millis),
+            name: None,
+            event_type,
+        }
+    }
+
+    #[test]
+    fn test_mouse_tracker_aggregates_within_bucket() {
+        let mut tracker = MouseTracker::new();
+        let start = 60_000 * 1000;
+
+        // The first event emits straight away as nothing has been emitted yet
+        tracker.handle(&event(EventType::MouseMove { x: 0.0, y: 0.0 }, start));
+
+        let sequence = [
+            EventType::MouseMove { x: 3.0, y: 4.0 },
+            EventType::ButtonPress(Button::Left),
+            EventType::ButtonRelease(Button::Left),
+            EventType::ButtonPress(Button::Right),
+            EventType::ButtonPress(Button::Unknown(4)),
+            EventType::Wheel {
+                delta_x: 0,
+                delta_y: -3,
+            },
+            EventType::Wheel {
+                delta_x: 1,
+                delta_y: 2,
+            },
+        ];
+        for (i, event_type) in sequence.into_iter().enumerate() {
+            assert_eq!(
+                tracker.handle(&event(event_type, start + 100 + i as u64)),
+                None
+            );
+        }
+
+        let activity = tracker.take(start as i64 + 1000).unwrap();
+        assert_eq!(
+            activity,
+            MouseActivity {
+                bucket_start: start as i64,
+                left_clicks: 1,
+                right_clicks: 1,
+                middle_clicks: 0,
+                other_clicks: 1,
+                scroll_x: 1,
+                scroll_y: 5,
+                travel_px: 5.0,
+            }
+        );
+        assert_eq!(tracker.take(start as i64 + 2000), None);
+    }
+
+    #[test]
+    fn test_mouse_tracker_emits_on_bucket_change() {
+        let mut tracker = MouseTracker::new();
+        let start = 60_000 * 1000;
+
+        tracker.handle(&event(EventType::ButtonPress(Button::Left), start));
+        tracker.handle(&event(EventType::ButtonPress(Button::Left), start + 1000));
+
+        let finished = tracker
+            .handle(&event(
+                EventType::ButtonPress(Button::Middle),
+                start + 60_000,
+            ))
+            .unwrap();
+        assert_eq!(finished.bucket_start, start as i64);
+        assert_eq!(finished.left_clicks, 1);
+
+        let next = tracker.take(start as i64 + 61_000).unwrap();
+        assert_eq!(next.bucket_start, start as i64 + 60_000);
+        assert_eq!(next.middle_clicks, 1);
+    }
+
+    #[test]
+    fn test_mouse_tracker_ignores_keyboard() {
+        let mut tracker = MouseTracker::new();
+        assert_eq!(
+            tracker.handle(&event(EventType::KeyPress(rdev::Key::KeyA), 1000)),
+            None
+        );
+        assert_eq!(tracker.take(2000), None);
+    }
+}
@@ -0,0 +1,215 @@
+use rdev::Key;
+
+/// Display names for the keys whose labels differ between platforms.
+///
+/// Every other key has the same name everywhere. Names are for people, data from
+/// different platforms should be merged on [`canonical_key_id`] instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyNaming {
+    pub meta_left: &'static str,
+    pub meta_right: &'static str,
+    pub alt_left: &'static str,
+    /// Right alt, which is AltGr on most non-US layouts
+    pub alt_right: &'static str,
+}
+
+impl KeyNaming {
+    pub const MACOS: KeyNaming = KeyNaming {
+        meta_left: "command_left",
+        meta_right: "command_right",
+        alt_left: "opt_left",
+        alt_right: "opt_right",
+    };
+
+    pub const LINUX: KeyNaming = KeyNaming {
+        meta_left: "super_left",
+        meta_right: "super_right",
+        alt_left: "alt_left",
+        alt_right: "altgr",
+    };
+
+    pub const WINDOWS: KeyNaming = KeyNaming {
+        meta_left: "win_left",
+        meta_right: "win_right",
+        alt_left: "alt_left",
+        alt_right: "alt_right",
+    };
+
+    /// The naming for the platform we are running on
+    pub const fn native() -> KeyNaming {
+        if cfg!(target_os = "macos") {
+            Self::MACOS
+        } else if cfg!(target_os = "windows") {
+            Self::WINDOWS
+        } else {
+            Self::LINUX
+        }
+    }
+
+    /// The platform specific name of `key`, `None` for keys named the same everywhere
+    pub fn name(&self, key: Key) -> Option<&'static str> {
+        match key {
+            Key::MetaLeft => Some(self.meta_left),
+            Key::MetaRight => Some(self.meta_right),
+            Key::Alt => Some(self.alt_left),
+            Key::AltGr => Some(self.alt_right),
+            _ => None,
+        }
+    }
+}
+
+impl Default for KeyNaming {
+    fn default() -> Self {
+        Self::native()
+    }
+}
+
+/// Platform neutral identifier of the physical key, following the W3C `KeyboardEvent.code`
+/// values, e.g. `KeyA`, `MetaLeft` or `Numpad0`.
+///
+/// Unknown keys have no identifier as their codes are platform specific.
+pub fn canonical_key_id(key: Key) -> Option<&'static str> {
+    KEY_IDS
+        .iter()
+        .find(|(known, _)| *known == key)
+        .map(|(_, id)| *id)
+}
+
+/// Whether `id` is the identifier of some key, see [`canonical_key_id`]
+pub fn is_canonical_key_id(id: &str) -> bool {
+    KEY_IDS.iter().any(|(_, known)| *known == id)
+}
+
+/// Every key with an identifier
+const KEY_IDS: [(Key, &str); 105] = [
+    (Key::Alt, "AltLeft"),
+    (Key::AltGr, "AltRight"),
+    (Key::Backspace, "Backspace"),
+    (Key::CapsLock, "CapsLock"),
+    (Key::ControlLeft, "ControlLeft"),
+    (Key::ControlRight, "ControlRight"),
+    (Key::Delete, "Delete"),
+    (Key::DownArrow, "ArrowDown"),
+    (Key::End, "End"),
+    (Key::Escape, "Escape"),
+    (Key::F1, "F1"),
+    (Key::F2, "F2"),
+    (Key::F3, "F3"),
+    (Key::F4, "F4"),
+    (Key::F5, "F5"),
+    (Key::F6, "F6"),
+    (Key::F7, "F7"),
+    (Key::F8, "F8"),
+    (Key::F9, "F9"),
+    (Key::F10, "F10"),
+    (Key::F11, "F11"),
+    (Key::F12, "F12"),
+    (Key::Home, "Home"),
+    (Key::LeftArrow, "ArrowLeft"),
+    (Key::MetaLeft, "MetaLeft"),
+    (Key::MetaRight, "MetaRight"),
+    (Key::PageDown, "PageDown"),
+    (Key::PageUp, "PageUp"),
+    (Key::Return, "Enter"),
+    (Key::RightArrow, "ArrowRight"),
+    (Key::ShiftLeft, "ShiftLeft"),
+    (Key::ShiftRight, "ShiftRight"),
+    (Key::Space, "Space"),
+    (Key::Tab, "Tab"),
+    (Key::UpArrow, "ArrowUp"),
+    (Key::PrintScreen, "PrintScreen"),
+    (Key::ScrollLock, "ScrollLock"),
+    (Key::Pause, "Pause"),
+    (Key::NumLock, "NumLock"),
+    (Key::BackQuote, "Backquote"),
+    (Key::Num1, "Digit1"),
+    (Key::Num2, "Digit2"),
+    (Key::Num3, "Digit3"),
+    (Key::Num4, "Digit4"),
+    (Key::Num5, "Digit5"),
+    (Key::Num6, "Digit6"),
+    (Key::Num7, "Digit7"),
+    (Key::Num8, "Digit8"),
+    (Key::Num9, "Digit9"),
+    (Key::Num0, "Digit0"),
+    (Key::Minus, "Minus"),
+    (Key::Equal, "Equal"),
+    (Key::KeyQ, "KeyQ"),
+    (Key::KeyW, "KeyW"),
+    (Key::KeyE, "KeyE"),
+    (Key::KeyR, "KeyR"),
+    (Key::KeyT, "KeyT"),
+    (Key::KeyY, "KeyY"),
+    (Key::KeyU, "KeyU"),
+    (Key::KeyI, "KeyI"),
+    (Key::KeyO, "KeyO"),
+    (Key::KeyP, "KeyP"),
+    (Key::LeftBracket, "BracketLeft"),
+    (Key::RightBracket, "BracketRight"),
+    (Key::KeyA, "KeyA"),
+    (Key::KeyS, "KeyS"),
+    (Key::KeyD, "KeyD"),
+    (Key::KeyF, "KeyF"),
+    (Key::KeyG, "KeyG"),
+    (Key::KeyH, "KeyH"),
+    (Key::KeyJ, "KeyJ"),
+    (Key::KeyK, "KeyK"),
+    (Key::KeyL, "KeyL"),
+    (Key::SemiColon, "Semicolon"),
+    (Key::Quote, "Quote"),
+    (Key::BackSlash, "Backslash"),
+    (Key::IntlBackslash, "IntlBackslash"),
+    (Key::KeyZ, "KeyZ"),
+    (Key::KeyX, "KeyX"),
+    (Key::KeyC, "KeyC"),
+    (Key::KeyV, "KeyV"),
+    (Key::KeyB, "KeyB"),
+    (Key::KeyN, "KeyN"),
+    (Key::KeyM, "KeyM"),
+    (Key::Comma, "Comma"),
+    (Key::Dot, "Period"),
+    (Key::Slash, "Slash"),
+    (Key::Insert, "Insert"),
+    (Key::KpReturn, "NumpadEnter"),
+    (Key::KpMinus, "NumpadSubtract"),
+    (Key::KpPlus, "NumpadAdd"),
+    (Key::KpMultiply, "NumpadMultiply"),
+    (Key::KpDivide, "NumpadDivide"),
+    (Key::Kp0, "Numpad0"),
+    (Key::Kp1, "Numpad1"),
+    (Key::Kp2, "Numpad2"),
+    (Key::Kp3, "Numpad3"),
+    (Key::Kp4, "Numpad4"),
+    (Key::Kp5, "Numpad5"),
+    (Key::Kp6, "Numpad6"),
+    (Key::Kp7, "Numpad7"),
+    (Key::Kp8, "Numpad8"),
+    (Key::Kp9, "Numpad9"),
+    (Key::KpDelete, "NumpadDecimal"),
+    (Key::Function, "Fn"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_platform_names() {
+        assert_eq!(KeyNaming::MACOS.name(Key::MetaLeft), Some("command_left"));
+        assert_eq!(KeyNaming::LINUX.name(Key::MetaRight), Some("super_right"));
+        assert_eq!(KeyNaming::LINUX.name(Key::AltGr), Some("altgr"));
+        assert_eq!(KeyNaming::WINDOWS.name(Key::MetaLeft), Some("win_left"));
+        assert_eq!(KeyNaming::WINDOWS.name(Key::Alt), Some("alt_left"));
+        assert_eq!(KeyNaming::WINDOWS.name(Key::KeyA), None);
+    }
+
+    #[test]
+    fn test_canonical_id_is_platform_neutral() {
+        assert_eq!(canonical_key_id(Key::MetaLeft), Some("MetaLeft"));
+        assert_eq!(canonical_key_id(Key::Alt), Some("AltLeft"));
+        assert_eq!(canonical_key_id(Key::Kp0), Some("Numpad0"));
+        assert_eq!(canonical_key_id(Key::Unknown(42)), None);
+        assert!(is_canonical_key_id("MetaLeft"));
+        assert!(!is_canonical_key_id("Capslock"));
+    }
+}
@@ -0,0 +1,150 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::models::events::PauseInterval;
+
+/// What paused capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum PauseSource {
+    /// The pause hotkey, `input.pause_hotkey`
+    Hotkey,
+    /// A control command sent to the daemon
+    Command,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pause {
+    started_at: i64,
+    source: PauseSource,
+    resume_at: Option<i64>,
+}
+
+/// Whether capture is paused, with the optional timeout after which it resumes by itself.
+///
+/// Times are milliseconds since the epoch, like event timestamps.
+#[derive(Debug, Default)]
+pub struct PauseState {
+    current: Option<Pause>,
+    auto_resume: Option<Duration>,
+    /// When the last pause ended
+    last_resumed: Option<i64>,
+}
+
+impl PauseState {
+    pub fn new(auto_resume: Option<Duration>) -> Self {
+        Self {
+            current: None,
+            auto_resume,
+            last_resumed: None,
+        }
+    }
+
+    /// Changes the timeout of pauses started from now on
+    pub fn set_auto_resume(&mut self, auto_resume: Option<Duration>) {
+        self.auto_resume = auto_resume;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Whether an event from `timestamp` is recorded. Keys pressed during a pause, or to
+    /// end it, and released after it are not
+    pub fn is_recording(&self, timestamp: i64) -> bool {
+        !self.is_paused() && self.last_resumed.is_none_or(|resumed| timestamp > resumed)
+    }
+
+    /// When the current pause ends by itself, if it does
+    pub fn resume_at(&self) -> Option<i64> {
+        self.current.and_then(|pause| pause.resume_at)
+    }
+
+    /// Pauses capture, returning false if it was paused already
+    pub fn pause(&mut self, now: i64, source: PauseSource) -> bool {
+        if self.current.is_some() {
+            return false;
+        }
+
+        let resume_at = self
+            .auto_resume
+            .map(|timeout| now + timeout.as_millis() as i64);
+        info!("Capture paused by {:?}", source);
+        self.current = Some(Pause {
+            started_at: now,
+            source,
+            resume_at,
+        });
+        true
+    }
+
+    /// Resumes capture, returning the pause that ended, `None` if it was not paused
+    pub fn resume(&mut self, now: i64) -> Option<PauseInterval> {
+        let pause = self.current.take()?;
+        info!("Capture resumed");
+
+        // Never past the timeout, the pause was over by then even if noticed late
+        let ended_at = pause.resume_at.map_or(now, |resume_at| now.min(resume_at));
+        self.last_resumed = Some(ended_at);
+
+        Some(PauseInterval {
+            started_at: pause.started_at,
+            ended_at,
+            paused_by: pause.source,
+        })
+    }
+
+    /// Resumes if the auto resume timeout has passed by `now`
+    pub fn expire(&mut self, now: i64) -> Option<PauseInterval> {
+        match self.resume_at() {
+            Some(resume_at) if now >= resume_at => self.resume(now),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_and_resume() {
+        let mut state = PauseState::new(None);
+
+        assert!(state.is_recording(500));
+        assert!(state.pause(1000, PauseSource::Command));
+        assert!(!state.pause(2000, PauseSource::Hotkey));
+        assert!(state.is_paused());
+        assert!(!state.is_recording(500));
+        assert_eq!(state.expire(i64::MAX), None);
+
+        assert_eq!(
+            state.resume(5000),
+            Some(PauseInterval {
+                started_at: 1000,
+                ended_at: 5000,
+                paused_by: PauseSource::Command,
+            })
+        );
+        assert!(!state.is_paused());
+        assert!(!state.is_recording(5000));
+        assert!(state.is_recording(5001));
+        assert_eq!(state.resume(6000), None);
+    }
+
+    #[test]
+    fn test_pause_auto_resumes() {
+        let mut state = PauseState::new(Some(Duration::from_secs(60)));
+
+        state.pause(1000, PauseSource::Hotkey);
+        assert_eq!(state.resume_at(), Some(61_000));
+        assert_eq!(state.expire(60_999), None);
+
+        // Noticed late, e.g. on the next keystroke, but ends at the timeout
+        let pause = state.expire(90_000).unwrap();
+        assert_eq!(pause.ended_at, 61_000);
+        assert!(!state.is_paused());
+    }
+}
@@ -0,0 +1,629 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info, warn};
+use rdev::{Event, EventType, Key};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+use super::pause::PauseSource;
+use super::{InputProcessor, ProcessorSettings};
+use crate::config::{expand_home, InputConfig};
+use crate::models::events::InputEvent;
+use crate::pipeline::EventSender;
+
+/// Where raw input events come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum InputSourceKind {
+    /// The live keyboard and mouse, through rdev
+    Rdev,
+    /// Linux input devices read directly, for Wayland and TTYs
+    Evdev,
+    /// A previously recorded event log
+    Replay,
+    /// Scripted typing of a fixed piece of text
+    Synthetic,
+}
+
+/// Produces raw input events.
+///
+/// `run` blocks the calling thread, handing every event to `callback` until the source is
+/// exhausted (`Ok`) or fails. Live sources never finish on their own.
+pub trait InputSource: Send {
+    fn run(self: Box<Self>, callback: EventCallback) -> Result<()>;
+}
+
+pub type EventCallback = Box<dyn FnMut(Event) + Send>;
+
+/// Builds the source selected in the config
+pub fn from_config(config: &InputConfig) -> Result<Box<dyn InputSource>> {
+    let source: Box<dyn InputSource> = match config.source {
+        InputSourceKind::Rdev => Box::new(RdevSource),
+        #[cfg(target_os = "linux")]
+        InputSourceKind::Evdev => {
+            use super::evdev::EvdevSource;
+
+            if config.evdev_devices.is_empty() {
+                Box::new(EvdevSource::detect()?)
+            } else {
+                Box::new(EvdevSource::new(config.evdev_devices.clone()))
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        InputSourceKind::Evdev => bail!("The evdev source is only available on Linux"),
+        InputSourceKind::Replay => {
+            let path = config
+                .replay_path
+                .as_ref()
+                .context("input.replay_path is required for the replay source")?;
+            Box::new(ReplaySource::new(expand_home(path)?).realtime(config.replay_realtime))
+        }
+        InputSourceKind::Synthetic => Box::new(SyntheticSource::typing(&config.synthetic_text)?),
+    };
+
+    Ok(source)
+}
+
+/// Runs `source` on its own thread, feeding the events from `processor` to `sender`.
+///
+/// Live sources never return and cannot be cancelled, so a plain thread is used rather than
+/// a blocking task, which would hold up runtime shutdown. The receiver resolves once the
+/// source finishes, the handle controls capture while it runs.
+pub fn spawn_capture(
+    source: Box<dyn InputSource>,
+    processor: InputProcessor,
+    sender: EventSender,
+    recorder: Option<Recorder>,
+) -> (CaptureHandle, oneshot::Receiver<Result<()>>) {
+    let (result_tx, result_rx) = oneshot::channel();
+
+    let status = Arc::new(PauseStatus::default());
+    let capture = Arc::new(Mutex::new(Capture {
+        processor,
+        sender,
+        recorder,
+        status: status.clone(),
+    }));
+    lock(&capture).publish();
+    let handle = CaptureHandle {
+        capture: capture.clone(),
+        status,
+    };
+
+    thread::spawn(move || {
+        let result = source.run(Box::new({
+            let capture = capture.clone();
+            move |event| lock(&capture).handle(event)
+        }));
+
+        lock(&capture).finish();
+        let _ = result_tx.send(result);
+    });
+
+    (handle, result_rx)
+}
+
+/// Controls a running capture from outside the input thread.
+///
+/// The input thread holds the capture while it sends events, which blocks for as long as
+/// the pipeline is full under [`Block`](crate::pipeline::BackpressurePolicy::Block), so
+/// changes are made on a blocking thread and the pause state is read from a copy.
+#[derive(Clone)]
+pub struct CaptureHandle {
+    capture: Arc<Mutex<Capture>>,
+    status: Arc<PauseStatus>,
+}
+
+impl CaptureHandle {
+    /// Stops recording input, returning false if capture was paused already
+    pub async fn pause(&self, source: PauseSource) -> bool {
+        self.with_capture(move |capture| {
+            if capture.processor.is_paused() {
+                return false;
+            }
+
+            let events = capture.processor.pause(SystemTime::now(), source);
+            capture.send(events);
+            true
+        })
+        .await
+    }
+
+    /// Resumes recording, returning false if capture was not paused
+    pub async fn resume(&self) -> bool {
+        self.with_capture(|capture| {
+            let events = capture.processor.resume(SystemTime::now());
+            let resumed = !events.is_empty();
+            capture.send(events);
+            resumed
+        })
+        .await
+    }
+
+    /// Resumes if the pause has timed out
+    pub async fn expire(&self) {
+        self.with_capture(|capture| {
+            let events = capture.processor.expire(SystemTime::now());
+            capture.send(events);
+        })
+        .await
+    }
+
+    /// Switches the processor to new settings, for a config reload
+    pub async fn apply_settings(&self, settings: ProcessorSettings) {
+        self.with_capture(|capture| {
+            capture.processor.apply(settings);
+            capture.publish();
+        })
+        .await
+    }
+
+    /// Sends the mouse activity aggregated so far, which otherwise waits for more input
+    pub async fn tick(&self) {
+        self.with_capture(|capture| {
+            let events = capture.processor.tick(SystemTime::now());
+            capture.send(events);
+        })
+        .await
+    }
+
+    /// Sends what the processor is still holding, for when the daemon stops while a live
+    /// source keeps running
+    pub async fn finish(&self) {
+        self.with_capture(Capture::finish).await
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.status.paused.load(Ordering::Acquire)
+    }
+
+    /// When the current pause ends by itself, if it does
+    pub fn resume_at(&self) -> Option<SystemTime> {
+        let resume_at = self.status.resume_at.load(Ordering::Acquire);
+        (resume_at != NO_RESUME)
+            .then(|| UNIX_EPOCH + Duration::from_millis(resume_at.max(0) as u64))
+    }
+
+    /// Runs `f` on a blocking thread once the input thread lets go of the capture
+    async fn with_capture<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Capture) -> T + Send + 'static,
+    ) -> T {
+        let capture = self.capture.clone();
+        tokio::task::spawn_blocking(move || f(&mut lock(&capture)))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
+
+/// Locks the capture, carrying on after a panic while it was held, as one bad event should
+/// not end pausing and status for good
+fn lock(capture: &Mutex<Capture>) -> MutexGuard<'_, Capture> {
+    capture.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Stands for a pause without a timeout in [`PauseStatus::resume_at`]
+const NO_RESUME: i64 = i64::MIN;
+
+/// The processor's pause state as of its last change
+struct PauseStatus {
+    paused: AtomicBool,
+    /// Milliseconds since the epoch, [`NO_RESUME`] if the pause does not time out
+    resume_at: AtomicI64,
+}
+
+impl Default for PauseStatus {
+    fn default() -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            resume_at: AtomicI64::new(NO_RESUME),
+        }
+    }
+}
+
+struct Capture {
+    processor: InputProcessor,
+    sender: EventSender,
+    recorder: Option<Recorder>,
+    status: Arc<PauseStatus>,
+}
+
+impl Capture {
+    fn handle(&mut self, event: Event) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&event);
+        }
+        let events = self.processor.process(&event);
+        self.send(events);
+    }
+
+    /// Sends what the processor is still holding once the source has stopped
+    fn finish(&mut self) {
+        let events = self.processor.finish(SystemTime::now());
+        self.send(events);
+    }
+
+    /// Sends `events` once the pause state they follow from is published
+    fn send(&mut self, events: Vec<InputEvent>) {
+        self.publish();
+        for input_event in events {
+            self.sender.send(input_event);
+        }
+    }
+
+    fn publish(&self) {
+        self.status.resume_at.store(
+            self.processor.resume_at().unwrap_or(NO_RESUME),
+            Ordering::Release,
+        );
+        self.status
+            .paused
+            .store(self.processor.is_paused(), Ordering::Release);
+    }
+}
+
+/// Live capture through `rdev::listen`, which needs a display server
+pub struct RdevSource;
+
+impl InputSource for RdevSource {
+    fn run(self: Box<Self>, callback: EventCallback) -> Result<()> {
+        rdev::listen(callback).map_err(|e| anyhow!("Error listening to events: {:?}", e))
+    }
+}
+
+/// Scripted events, useful for exercising the whole capture path without a keyboard
+pub struct SyntheticSource {
+    events: Vec<Event>,
+}
+
+impl SyntheticSource {
+    pub fn new(events: Vec<Event>) -> Self {
+        Self { events }
+    }
+
+    /// Presses and releases a key for each character of `text`, 100ms apart with a 50ms
+    /// dwell, starting from the current time
+    pub fn typing(text: &str) -> Result<Self> {
+        Self::typing_at(text, SystemTime::now())
+    }
+
+    pub fn typing_at(text: &str, start: SystemTime) -> Result<Self> {
+        let mut events = Vec::with_capacity(text.len() * 4);
+        let mut time = start;
+
+        for c in text.chars() {
+            let (key, shifted) =
+                key_for_char(c).with_context(|| format!("Cannot type {:?} synthetically", c))?;
+
+            if shifted {
+                events.push(synthetic_event(
+                    EventType::KeyPress(Key::ShiftLeft),
+                    time,
+                    None,
+                ));
+            }
+            events.push(synthetic_event(
+                EventType::KeyPress(key),
+                time,
+                Some(c.to_string()),
+            ));
+            events.push(synthetic_event(
+                EventType::KeyRelease(key),
+                time + Duration::from_millis(50),
+                None,
+            ));
+            if shifted {
+                events.push(synthetic_event(
+                    EventType::KeyRelease(Key::ShiftLeft),
+                    time + Duration::from_millis(60),
+                    None,
+                ));
+            }
+
+            time += Duration::from_millis(100);
+        }
+
+        Ok(Self { events })
+    }
+}
+
+impl InputSource for SyntheticSource {
+    fn run(self: Box<Self>, mut callback: EventCallback) -> Result<()> {
+        info!("Emitting {} synthetic events", self.events.len());
+        for event in self.events {
+            callback(event);
+        }
+        Ok(())
+    }
+}
+
+fn synthetic_event(event_type: EventType, time: SystemTime, name: Option<String>) -> Event {
+    Event {
+        time,
+        name,
+        event_type,
+    }
+}
+
+/// Maps a character to the key that types it on a US layout, and whether shift is needed
+fn key_for_char(c: char) -> Option<(Key, bool)> {
+    const LETTERS: [Key; 26] = [
+        Key::KeyA,
+        Key::KeyB,
+        Key::KeyC,
+        Key::KeyD,
+        Key::KeyE,
+        Key::KeyF,
+        Key::KeyG,
+        Key::KeyH,
+        Key::KeyI,
+        Key::KeyJ,
+        Key::KeyK,
+        Key::KeyL,
+        Key::KeyM,
+        Key::KeyN,
+        Key::KeyO,
+        Key::KeyP,
+        Key::KeyQ,
+        Key::KeyR,
+        Key::KeyS,
+        Key::KeyT,
+        Key::KeyU,
+        Key::KeyV,
+        Key::KeyW,
+        Key::KeyX,
+        Key::KeyY,
+        Key::KeyZ,
+    ];
+    const DIGITS: [Key; 10] = [
+        Key::Num0,
+        Key::Num1,
+        Key::Num2,
+        Key::Num3,
+        Key::Num4,
+        Key::Num5,
+        Key::Num6,
+        Key::Num7,
+        Key::Num8,
+        Key::Num9,
+    ];
+
+    let key = match c {
+        'a'..='z' => (LETTERS[(c as u8 - b'a') as usize], false),
+        'A'..='Z' => (LETTERS[(c as u8 - b'A') as usize], true),
+        '0'..='9' => (DIGITS[(c as u8 - b'0') as usize], false),
+        ' ' => (Key::Space, false),
+        '\n' => (Key::Return, false),
+        '\t' => (Key::Tab, false),
+        '-' => (Key::Minus, false),
+        '=' => (Key::Equal, false),
+        ',' => (Key::Comma, false),
+        '.' => (Key::Dot, false),
+        '/' => (Key::Slash, false),
+        ';' => (Key::SemiColon, false),
+        '\'' => (Key::Quote, false),
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+/// Replays an event log written by [`Recorder`], one JSON encoded `rdev::Event` per line
+pub struct ReplaySource {
+    path: PathBuf,
+    realtime: bool,
+}
+
+impl ReplaySource {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            realtime: false,
+        }
+    }
+
+    /// Sleep between events to reproduce the original timing, instead of replaying as
+    /// fast as possible
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+}
+
+impl InputSource for ReplaySource {
+    fn run(self: Box<Self>, mut callback: EventCallback) -> Result<()> {
+        info!("Replaying events from {:?}", self.path);
+        let file = File::open(&self.path)
+            .with_context(|| format!("Failed to open event log {:?}", self.path))?;
+
+        let mut previous: Option<SystemTime> = None;
+        let mut replayed = 0;
+
+        for (line_number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let event: Event = match serde_json::from_str(&line) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Skipping line {} of event log: {}", line_number + 1, e);
+                    continue;
+                }
+            };
+
+            if self.realtime {
+                if let Some(gap) = previous.and_then(|p| event.time.duration_since(p).ok()) {
+                    std::thread::sleep(gap);
+                }
+                previous = Some(event.time);
+            }
+
+            callback(event);
+            replayed += 1;
+        }
+
+        info!("Replayed {} events", replayed);
+        Ok(())
+    }
+}
+
+/// Writes raw events to an event log that [`ReplaySource`] can play back.
+///
+/// The log contains everything typed, so it should only be used for debugging.
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let path = expand_home(path)?;
+        if path.exists() {
+            bail!("Refusing to overwrite existing event log {:?}", path);
+        }
+
+        warn!("Recording raw input events to {:?}", path);
+        let file = File::create(&path)
+            .with_context(|| format!("Failed to create event log {:?}", path))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, event: &Event) {
+        let result = serde_json::to_writer(&mut self.writer, event)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(self.writer.write_all(b"\n")?))
+            .and_then(|_| Ok(self.writer.flush()?));
+
+        if let Err(e) = result {
+            debug!("Failed to record event: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{self, BackpressurePolicy};
+    use crate::storage::{buffer::KeyEventBuffer, connection::Database};
+    use tempfile::{NamedTempFile, TempDir};
+
+    fn collect(source: Box<dyn InputSource>) -> Result<Vec<Event>> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        source.run(Box::new(move |event| {
+            let _ = tx.send(event);
+        }))?;
+        Ok(rx.into_iter().collect())
+    }
+
+    #[test]
+    fn test_synthetic_source_types_text() -> Result<()> {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let source = SyntheticSource::typing_at("Hi", start)?;
+
+        let mut processor = InputProcessor::new();
+        let keys = collect(Box::new(source))?
+            .iter()
+            .flat_map(|event| processor.process(event))
+            .filter_map(|event| match event {
+                InputEvent::Key(key) => Some((key.key_name, key.dwell_ms)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            keys,
+            vec![
+                ("h".to_string(), Some(50)),
+                ("shift_left".to_string(), Some(60)),
+                ("i".to_string(), Some(50)),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_synthetic_source_rejects_unknown_chars() {
+        assert!(SyntheticSource::typing("😀").is_err());
+    }
+
+    #[test]
+    fn test_record_and_replay() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let log_path = tmp_dir.path().join("events.jsonl");
+
+        let recorded = collect(Box::new(SyntheticSource::typing("ab")?))?;
+        let mut recorder = Recorder::create(&log_path)?;
+        for event in &recorded {
+            recorder.record(event);
+        }
+        drop(recorder);
+
+        // Corrupt lines are skipped rather than aborting the replay
+        let mut log = std::fs::read_to_string(&log_path)?;
+        log.push_str("not an event\n");
+        std::fs::write(&log_path, log)?;
+
+        let replayed = collect(Box::new(ReplaySource::new(log_path.clone())))?;
+        assert_eq!(replayed.len(), recorded.len());
+        for (replayed, recorded) in replayed.iter().zip(&recorded) {
+            assert_eq!(replayed.event_type, recorded.event_type);
+            assert_eq!(replayed.time, recorded.time);
+            assert_eq!(replayed.name, recorded.name);
+        }
+
+        assert!(Recorder::create(&log_path).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_synthetic_capture_reaches_database() -> Result<()> {
+        // Setup database
+        let tmp_db = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_db.path());
+        let db = Database::new(tmp_db_path.clone()).await?;
+        db.run_migrations().await?;
+
+        let tmp_dir = TempDir::new()?;
+        let buffer = KeyEventBuffer::new(db, 4, Duration::from_secs(60));
+        let (sender, writer, pipeline) = pipeline::channel(
+            16,
+            BackpressurePolicy::Block,
+            tmp_dir.path().join("spill.jsonl"),
+            buffer,
+        )?;
+        let writer_handle = tokio::spawn(writer.run());
+
+        let source = Box::new(SyntheticSource::typing("hello world")?);
+        let (_, finished) = spawn_capture(source, InputProcessor::new(), sender, None);
+        finished.await??;
+
+        pipeline.close();
+        writer_handle.await??;
+
+        let db = Database::new(tmp_db_path).await?;
+        let keys = db
+            .get_events()
+            .await?
+            .into_iter()
+            .map(|event| event.key_name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec!["h", "e", "l", "l", "o", "space", "w", "o", "r", "l", "d"]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::models::events::CharClass;
+
+/// Text produced by a key press
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypedText {
+    /// NFC normalised, so composed and decomposed input compare equal
+    pub text: String,
+    /// Class of the first character
+    pub class: CharClass,
+}
+
+impl TypedText {
+    /// Normalises raw OS output, `None` if nothing visible was typed
+    pub fn new(raw: &str) -> Option<Self> {
+        let text = raw
+            .nfc()
+            .filter(|&c| c.is_whitespace() || CharClass::of(c).is_some())
+            .collect::<String>();
+        let class = CharClass::of(text.chars().next()?)?;
+
+        Some(Self { text, class })
+    }
+
+    /// Whether the text carries diacritics, as typed with a dead key or a compose sequence
+    pub fn is_composed(&self) -> bool {
+        self.text.nfd().any(is_combining_mark)
+    }
+}
+
+/// Turns the text reported for successive key presses into what was actually typed.
+///
+/// Most platforms report nothing for a dead key and the composed character for the key
+/// after it, which needs no help. Some report the dead key as a lone combining mark
+/// instead, which is held back and combined with the next character.
+#[derive(Debug, Default)]
+pub struct Composer {
+    pending_marks: String,
+}
+
+impl Composer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the text reported for a key press, returning the text it types
+    pub fn feed(&mut self, raw: Option<&str>) -> Option<TypedText> {
+        let raw = raw?;
+
+        if !raw.is_empty() && raw.chars().all(is_combining_mark) {
+            self.pending_marks.push_str(raw);
+            return None;
+        }
+
+        let marks = std::mem::take(&mut self.pending_marks);
+        let typed = TypedText::new(raw)?;
+        if marks.is_empty() {
+            return Some(typed);
+        }
+
+        // Marks go after the first character, where a dead key would have put them
+        let mut chars = typed.text.chars();
+        let first = chars.next()?;
+        let combined = std::iter::once(first)
+            .chain(marks.chars())
+            .chain(chars)
+            .collect::<String>();
+
+        TypedText::new(&combined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classifies_characters() {
+        let class = CharClass::of;
+
+        assert_eq!(class('a'), Some(CharClass::Letter));
+        assert_eq!(class('ж'), Some(CharClass::Letter));
+        assert_eq!(class('中'), Some(CharClass::Letter));
+        assert_eq!(class('٣'), Some(CharClass::Number));
+        assert_eq!(class('¿'), Some(CharClass::Punctuation));
+        assert_eq!(class('€'), Some(CharClass::Symbol));
+        assert_eq!(class('😀'), Some(CharClass::Symbol));
+        assert_eq!(class('\u{301}'), Some(CharClass::Mark));
+        assert_eq!(class('\u{a0}'), Some(CharClass::Whitespace));
+        assert_eq!(class('\u{3}'), None);
+    }
+
+    #[test]
+    fn test_typed_text_is_nfc() {
+        let decomposed = TypedText::new("e\u{301}").unwrap();
+        assert_eq!(decomposed.text, "é");
+        assert_eq!(decomposed.class, CharClass::Letter);
+        assert!(decomposed.is_composed());
+
+        assert!(!TypedText::new("e").unwrap().is_composed());
+
+        // Control characters, e.g. from ctrl+c, are not text
+        assert_eq!(TypedText::new("\u{3}"), None);
+        assert_eq!(TypedText::new(""), None);
+    }
+
+    #[test]
+    fn test_composer_combines_lone_marks() {
+        let mut composer = Composer::new();
+
+        assert_eq!(composer.feed(Some("\u{308}")), None);
+        assert_eq!(composer.feed(Some("u")).unwrap().text, "ü");
+
+        // Nothing is pending once used
+        assert_eq!(composer.feed(Some("u")).unwrap().text, "u");
+    }
+
+    #[test]
+    fn test_composer_passes_os_composed_text_through() {
+        let mut composer = Composer::new();
+
+        // A dead key the OS reports no text for, then the composed character
+        assert_eq!(composer.feed(None), None);
+        let typed = composer.feed(Some("ê")).unwrap();
+        assert_eq!(typed.text, "ê");
+        assert!(typed.is_composed());
+
+        // IME output arrives as several characters at once
+        assert_eq!(composer.feed(Some("日本語")).unwrap().text, "日本語");
+    }
+}
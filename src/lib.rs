@@ -1,3 +1,9 @@
+pub mod clock;
+pub mod config;
+pub mod control;
 pub mod input;
 pub mod models;
+pub mod pipeline;
+pub mod privacy;
+pub mod shutdown;
 pub mod storage;
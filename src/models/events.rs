@@ -1,14 +1,183 @@
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+use unicode_general_category::{get_general_category, GeneralCategory};
+
+use crate::input::pause::PauseSource;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct KeyEvent {
     pub key_name: String,
+    /// Platform neutral identifier of the physical key, see `canonical_key_id`
+    #[serde(default)]
+    pub key_id: Option<String>,
+    /// Character the key types under the configured layout, if it types one
+    #[serde(default)]
+    pub logical_key: Option<String>,
+    /// NFC normalised text the press produced, including dead key and IME compositions
+    #[serde(default)]
+    pub typed_text: Option<String>,
+    #[serde(default)]
+    pub char_class: Option<CharClass>,
     pub timestamp: i64,
+    /// How long the key was held in milliseconds, if the release was seen
+    #[serde(default)]
+    pub dwell_ms: Option<i64>,
+    /// Press generated by the OS while the key was held down
+    #[serde(default)]
+    pub is_repeat: bool,
+    /// Application that had the keyboard focus
+    #[serde(default)]
+    pub application: Option<String>,
+    /// Title of the focused window, redacted
+    #[serde(default)]
+    pub window_title: Option<String>,
 }
 
 impl KeyEvent {
     pub fn new(key_name: String, timestamp: i64) -> KeyEvent {
         KeyEvent {
             key_name,
+            key_id: None,
+            logical_key: None,
+            typed_text: None,
+            char_class: None,
             timestamp,
+            dwell_ms: None,
+            is_repeat: false,
+            application: None,
+            window_title: None,
         }
     }
 }
+
+/// Broad class of a typed character, from its Unicode general category
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum CharClass {
+    Letter,
+    /// A combining mark that had nothing to combine with
+    Mark,
+    Number,
+    Punctuation,
+    Symbol,
+    Whitespace,
+}
+
+impl CharClass {
+    /// Classifies `c`, `None` for control and other invisible characters
+    pub fn of(c: char) -> Option<Self> {
+        use GeneralCategory::*;
+
+        if c.is_whitespace() {
+            return Some(Self::Whitespace);
+        }
+
+        // Control, format, surrogate, private use and unassigned characters are not text
+        let class = match get_general_category(c) {
+            UppercaseLetter | LowercaseLetter | TitlecaseLetter | ModifierLetter | OtherLetter => {
+                Self::Letter
+            }
+            NonspacingMark | SpacingMark | EnclosingMark => Self::Mark,
+            DecimalNumber | LetterNumber | OtherNumber => Self::Number,
+            ConnectorPunctuation | DashPunctuation | OpenPunctuation | ClosePunctuation
+            | InitialPunctuation | FinalPunctuation | OtherPunctuation => Self::Punctuation,
+            MathSymbol | CurrencySymbol | ModifierSymbol | OtherSymbol => Self::Symbol,
+            SpaceSeparator | LineSeparator | ParagraphSeparator => Self::Whitespace,
+            _ => return None,
+        };
+
+        Some(class)
+    }
+}
+
+/// A non-modifier key pressed while ctrl, alt or meta was held, e.g. `ctrl+shift+t`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShortcutEvent {
+    pub combo: String,
+    pub timestamp: i64,
+}
+
+impl ShortcutEvent {
+    pub fn new(combo: String, timestamp: i64) -> ShortcutEvent {
+        ShortcutEvent { combo, timestamp }
+    }
+}
+
+/// Mouse usage aggregated over a one minute bucket, raw coordinates are never stored.
+///
+/// Several partial aggregates for the same bucket are summed when persisted.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct MouseActivity {
+    /// Start of the minute, in milliseconds since the epoch
+    pub bucket_start: i64,
+    pub left_clicks: i64,
+    pub right_clicks: i64,
+    pub middle_clicks: i64,
+    pub other_clicks: i64,
+    /// Scroll wheel ticks, regardless of direction
+    pub scroll_x: i64,
+    pub scroll_y: i64,
+    /// Distance the cursor moved in pixels
+    pub travel_px: f64,
+}
+
+impl MouseActivity {
+    pub fn new(bucket_start: i64) -> MouseActivity {
+        MouseActivity {
+            bucket_start,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left_clicks == 0
+            && self.right_clicks == 0
+            && self.middle_clicks == 0
+            && self.other_clicks == 0
+            && self.scroll_x == 0
+            && self.scroll_y == 0
+            && self.travel_px == 0.0
+    }
+}
+
+/// A stretch of time during which capture was paused, in milliseconds since the epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PauseInterval {
+    pub started_at: i64,
+    pub ended_at: i64,
+    pub paused_by: PauseSource,
+}
+
+/// Anything captured by the input layer that is persisted through the buffer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InputEvent {
+    Key(KeyEvent),
+    Shortcut(ShortcutEvent),
+    Mouse(MouseActivity),
+    Pause(PauseInterval),
+}
+
+impl From<KeyEvent> for InputEvent {
+    fn from(event: KeyEvent) -> Self {
+        InputEvent::Key(event)
+    }
+}
+
+impl From<ShortcutEvent> for InputEvent {
+    fn from(event: ShortcutEvent) -> Self {
+        InputEvent::Shortcut(event)
+    }
+}
+
+impl From<MouseActivity> for InputEvent {
+    fn from(activity: MouseActivity) -> Self {
+        InputEvent::Mouse(activity)
+    }
+}
+
+impl From<PauseInterval> for InputEvent {
+    fn from(pause: PauseInterval) -> Self {
+        InputEvent::Pause(pause)
+    }
+}
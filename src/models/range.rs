@@ -0,0 +1,535 @@
+use anyhow::{bail, Context, Result};
+use chrono::{
+    DateTime, Datelike, Days, LocalResult, NaiveDate, NaiveDateTime, Offset, TimeDelta, TimeZone,
+    Utc,
+};
+use chrono_tz::Tz;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+
+use super::stats::{BucketCount, KeyBucket, KeyCount};
+
+/// Most buckets a single query may ask for
+pub const MAX_BUCKETS: i64 = 10_000;
+
+const MINUTE_MS: i64 = 60 * 1000;
+const HOUR_MS: i64 = 60 * MINUTE_MS;
+const DAY_MS: i64 = 24 * HOUR_MS;
+
+/// Half-open span of time `[from, to)`, in milliseconds since the epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    pub from: i64,
+    pub to: i64,
+}
+
+impl TimeRange {
+    pub fn new(from: i64, to: i64) -> Result<Self> {
+        if from >= to {
+            bail!("from must be before to");
+        }
+        Ok(Self { from, to })
+    }
+
+    /// Everything ever recorded
+    pub fn all() -> Self {
+        Self {
+            from: i64::MIN,
+            to: i64::MAX,
+        }
+    }
+
+    /// The current day in `zone`
+    pub fn today(zone: Tz) -> Self {
+        Self::last_days(1, zone).expect("today is within the calendar")
+    }
+
+    /// The whole of `date` in `zone`, failing for the last day of the calendar
+    pub fn day(date: NaiveDate, zone: Tz) -> Result<Self> {
+        let next = date
+            .checked_add_days(Days::new(1))
+            .with_context(|| format!("{} is out of range", date))?;
+
+        Ok(Self {
+            from: start_of_day(zone, date),
+            to: start_of_day(zone, next),
+        })
+    }
+
+    /// The last `days` days in `zone`, today included
+    pub fn last_days(days: u32, zone: Tz) -> Result<Self> {
+        let today = Utc::now().with_timezone(&zone).date_naive();
+        let first = today
+            .checked_sub_days(Days::new(u64::from(days.saturating_sub(1))))
+            .with_context(|| format!("{} days go back too far", days))?;
+
+        Ok(Self {
+            from: start_of_day(zone, first),
+            to: Self::day(today, zone)?.to,
+        })
+    }
+
+    /// Resolves bounds given as query parameters, falling back to `default` for those
+    /// left out. A range with a `from` that `default` leaves open ends now, so it can be
+    /// grouped. Dates start at midnight in `zone`
+    pub fn parse(
+        from: Option<&str>,
+        to: Option<&str>,
+        default: TimeRange,
+        zone: Tz,
+    ) -> Result<Self> {
+        let from = from
+            .map(|from| parse_time(from, zone).context("Invalid from"))
+            .transpose()?;
+        let to = to
+            .map(|to| parse_time(to, zone).context("Invalid to"))
+            .transpose()?;
+
+        let to = match (from, to) {
+            (_, Some(to)) => to,
+            (Some(_), None) if default.to == i64::MAX => Utc::now().timestamp_millis(),
+            (_, None) => default.to,
+        };
+
+        Self::new(from.unwrap_or(default.from), to)
+    }
+
+    /// Checks that grouping the range by `bucket` stays within [`MAX_BUCKETS`]
+    pub fn check_buckets(&self, bucket: Bucket) -> Result<()> {
+        if self.from == i64::MIN {
+            bail!("from is required when grouping by {:?}", bucket);
+        }
+
+        let buckets = (self.to.saturating_sub(self.from)) / bucket.shortest_ms();
+        if buckets > MAX_BUCKETS {
+            bail!(
+                "Grouping by {:?} gives more than {} buckets, narrow the range or use a \
+                 coarser bucket",
+                bucket,
+                MAX_BUCKETS
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a point in time as milliseconds since the epoch, an RFC 3339 timestamp or a
+/// `YYYY-MM-DD` date, which means midnight in `zone`
+pub fn parse_time(value: &str, zone: Tz) -> Result<i64> {
+    if let Ok(millis) = value.parse::<i64>() {
+        return Ok(millis);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp_millis());
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(start_of_day(zone, date));
+    }
+
+    bail!(
+        "{:?} is not a timestamp in milliseconds, an RFC 3339 time or a date",
+        value
+    )
+}
+
+/// Looks up an IANA time zone such as `Europe/Berlin`
+pub fn parse_zone(name: &str) -> Result<Tz> {
+    name.parse::<Tz>()
+        .with_context(|| format!("Unknown time zone {:?}", name))
+}
+
+/// The system's time zone, from `TZ` or the system settings, UTC when neither names one
+pub fn local_zone() -> Tz {
+    let name = env::var("TZ")
+        .ok()
+        .filter(|name| name.parse::<Tz>().is_ok())
+        .or_else(|| iana_time_zone::get_timezone().ok());
+
+    match name.as_deref().map(parse_zone) {
+        Some(Ok(zone)) => zone,
+        Some(Err(e)) => {
+            warn!("{:#}, days start at midnight UTC", e);
+            Tz::UTC
+        }
+        None => {
+            warn!("Cannot tell the system time zone, days start at midnight UTC");
+            Tz::UTC
+        }
+    }
+}
+
+/// When `date` starts in `zone`
+pub fn start_of_day(zone: Tz, date: NaiveDate) -> i64 {
+    instant(zone, date.and_hms_opt(0, 0, 0).unwrap())
+}
+
+/// When the wall clock in `zone` shows `local`. That is the first time when clocks go back
+/// and, when clocks jump forward over it, the moment they jump
+fn instant(zone: Tz, local: NaiveDateTime) -> i64 {
+    match zone.from_local_datetime(&local) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time.timestamp_millis(),
+        LocalResult::None => {
+            // Read with the offset from before the jump, the skipped time lands on it. At
+            // the ends of the calendar, where there is no such time in UTC, read as UTC
+            local
+                .checked_sub_signed(TimeDelta::days(1))
+                .map(|before| zone.offset_from_utc_datetime(&before).fix())
+                .and_then(|before| local.checked_sub_offset(before))
+                .unwrap_or(local)
+                .and_utc()
+                .timestamp_millis()
+        }
+    }
+}
+
+/// Granularity events are counted at over a time range, in the local time of a zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Minute,
+    Hour,
+    /// From midnight to midnight, 23 or 25 hours when clocks change
+    Day,
+    /// Starting on Monday
+    Week,
+    /// Calendar month
+    Month,
+}
+
+impl Bucket {
+    /// Start of the bucket `timestamp` falls in, in `zone`
+    pub fn floor(self, timestamp: i64, zone: Tz) -> i64 {
+        let Some(time) = DateTime::<Utc>::from_timestamp_millis(timestamp) else {
+            return timestamp;
+        };
+        let local = time.with_timezone(&zone);
+        let date = local.date_naive();
+
+        match self {
+            // Offsets are whole minutes, and whole hours apart from a few zones on the
+            // half hour, so these follow the offset in force at the time
+            Self::Minute | Self::Hour => {
+                let unit = if self == Self::Minute {
+                    MINUTE_MS
+                } else {
+                    HOUR_MS
+                };
+                let offset = i64::from(local.offset().fix().local_minus_utc()) * 1000;
+                let local_ms = timestamp + offset;
+                local_ms - local_ms.rem_euclid(unit) - offset
+            }
+            Self::Day => start_of_day(zone, date),
+            Self::Week => date
+                .checked_sub_days(Days::new(u64::from(date.weekday().num_days_from_monday())))
+                .map_or(timestamp, |monday| start_of_day(zone, monday)),
+            Self::Month => start_of_day(zone, date.with_day(1).unwrap()),
+        }
+    }
+
+    /// Start of every bucket overlapping `range`, in time order
+    pub fn starts(self, range: TimeRange, zone: Tz) -> Vec<i64> {
+        let mut starts = Vec::new();
+        let mut start = self.floor(range.from, zone);
+        while start < range.to {
+            starts.push(start);
+            // No bucket is longer, so this lands in the next one
+            start = self.floor(start.saturating_add(self.longest_ms()), zone);
+        }
+
+        starts
+    }
+
+    /// Length of the shortest bucket, days lose an hour when clocks go forward and months
+    /// vary
+    fn shortest_ms(self) -> i64 {
+        match self {
+            Self::Minute => MINUTE_MS,
+            Self::Hour => HOUR_MS,
+            Self::Day => DAY_MS - HOUR_MS,
+            Self::Week => 7 * DAY_MS - HOUR_MS,
+            Self::Month => 28 * DAY_MS - HOUR_MS,
+        }
+    }
+
+    /// Length of the longest bucket, days gain an hour when clocks go back
+    fn longest_ms(self) -> i64 {
+        match self {
+            Self::Minute => MINUTE_MS,
+            Self::Hour => HOUR_MS,
+            Self::Day => DAY_MS + HOUR_MS,
+            Self::Week => 7 * DAY_MS + HOUR_MS,
+            Self::Month => 31 * DAY_MS + HOUR_MS,
+        }
+    }
+
+    /// Sums counts of finer buckets, each within one of these, in time order, into counts
+    /// per bucket
+    pub fn group(self, zone: Tz, counts: impl IntoIterator<Item = (i64, i64)>) -> Vec<BucketCount> {
+        let mut buckets: Vec<BucketCount> = Vec::new();
+
+        for (start, count) in counts {
+            let start = self.floor(start, zone);
+            match buckets.last_mut() {
+                Some(bucket) if bucket.start == start => bucket.count += count,
+                _ => buckets.push(BucketCount { start, count }),
+            }
+        }
+
+        buckets
+    }
+
+    /// Sums counts of each key in finer buckets into key counts per bucket, most used first
+    pub fn group_keys(
+        self,
+        zone: Tz,
+        counts: impl IntoIterator<Item = (i64, String, i64)>,
+    ) -> Vec<KeyBucket> {
+        let mut buckets = BTreeMap::<i64, HashMap<String, i64>>::new();
+        for (start, key_name, count) in counts {
+            *buckets
+                .entry(self.floor(start, zone))
+                .or_default()
+                .entry(key_name)
+                .or_default() += count;
+        }
+
+        buckets
+            .into_iter()
+            .map(|(start, keys)| {
+                let mut keys = keys
+                    .into_iter()
+                    .map(|(key_name, count)| KeyCount { key_name, count })
+                    .collect::<Vec<_>>();
+                keys.sort_by(|a, b| b.count.cmp(&a.count).then(a.key_name.cmp(&b.key_name)));
+                KeyBucket { start, keys }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(time: &str) -> i64 {
+        parse_time(time, Tz::UTC).unwrap()
+    }
+
+    fn zone(name: &str) -> Tz {
+        parse_zone(name).unwrap()
+    }
+
+    #[test]
+    fn test_parse_time_range() -> Result<()> {
+        let default = TimeRange::new(0, 1000)?;
+        let utc = Tz::UTC;
+
+        assert_eq!(TimeRange::parse(None, None, default, utc)?, default);
+        assert_eq!(
+            TimeRange::parse(
+                Some("2024-03-01"),
+                Some("2024-03-01T12:00:00+01:00"),
+                default,
+                utc
+            )?,
+            TimeRange::new(1_709_251_200_000, 1_709_290_800_000)?
+        );
+        assert_eq!(TimeRange::parse(Some("500"), None, default, utc)?.from, 500);
+
+        assert!(TimeRange::parse(Some("yesterday"), None, default, utc).is_err());
+        assert!(TimeRange::parse(Some("1000"), None, default, utc).is_err());
+
+        let since = TimeRange::parse(Some("2024-03-01"), None, TimeRange::all(), utc)?;
+        assert!(since.to <= Utc::now().timestamp_millis());
+        assert!(since.check_buckets(Bucket::Month).is_ok());
+        assert_eq!(
+            TimeRange::parse(None, None, TimeRange::all(), utc)?,
+            TimeRange::all()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_floor() {
+        let time = millis("2024-03-14T15:09:26Z");
+
+        assert_eq!(
+            Bucket::Minute.floor(time, Tz::UTC),
+            millis("2024-03-14T15:09:00Z")
+        );
+        assert_eq!(
+            Bucket::Hour.floor(time, Tz::UTC),
+            millis("2024-03-14T15:00:00Z")
+        );
+        assert_eq!(Bucket::Day.floor(time, Tz::UTC), millis("2024-03-14"));
+        // A Thursday, the week started on Monday the 11th
+        assert_eq!(Bucket::Week.floor(time, Tz::UTC), millis("2024-03-11"));
+        assert_eq!(
+            Bucket::Week.floor(millis("2024-03-11"), Tz::UTC),
+            millis("2024-03-11")
+        );
+        assert_eq!(Bucket::Month.floor(time, Tz::UTC), millis("2024-03-01"));
+    }
+
+    #[test]
+    fn test_bucket_limits() -> Result<()> {
+        let day = TimeRange::new(millis("2024-03-01"), millis("2024-03-02"))?;
+        assert!(day.check_buckets(Bucket::Minute).is_ok());
+
+        let years = TimeRange::new(millis("2000-01-01"), millis("2024-01-01"))?;
+        assert!(years.check_buckets(Bucket::Minute).is_err());
+        assert!(years.check_buckets(Bucket::Month).is_ok());
+        assert!(TimeRange::all().check_buckets(Bucket::Month).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_group() {
+        let hour = millis("2024-03-14T15:00:00Z");
+        let minutes = [(hour, 3), (hour + 59 * MINUTE_MS, 2), (hour + HOUR_MS, 1)];
+
+        assert_eq!(
+            Bucket::Hour.group(Tz::UTC, minutes),
+            vec![
+                BucketCount {
+                    start: hour,
+                    count: 5
+                },
+                BucketCount {
+                    start: hour + HOUR_MS,
+                    count: 1
+                },
+            ]
+        );
+
+        let keys = Bucket::Day.group_keys(
+            Tz::UTC,
+            [
+                (hour, "a".to_string(), 1),
+                (hour + MINUTE_MS, "b".to_string(), 2),
+                (hour + 2 * MINUTE_MS, "a".to_string(), 2),
+            ],
+        );
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            keys[0]
+                .keys
+                .iter()
+                .map(|key| (key.key_name.as_str(), key.count))
+                .collect::<Vec<_>>(),
+            vec![("a", 3), ("b", 2)]
+        );
+    }
+
+    #[test]
+    fn test_days_across_clock_changes() -> Result<()> {
+        let new_york = zone("America/New_York");
+
+        // Clocks go forward at 2am on the 10th of March and back on the 3rd of November
+        let spring = start_of_day(new_york, NaiveDate::from_ymd_opt(2024, 3, 10).unwrap());
+        let next = start_of_day(new_york, NaiveDate::from_ymd_opt(2024, 3, 11).unwrap());
+        assert_eq!(spring, millis("2024-03-10T00:00:00-05:00"));
+        assert_eq!(next - spring, 23 * HOUR_MS);
+
+        let autumn = start_of_day(new_york, NaiveDate::from_ymd_opt(2024, 11, 3).unwrap());
+        let next = start_of_day(new_york, NaiveDate::from_ymd_opt(2024, 11, 4).unwrap());
+        assert_eq!(next - autumn, 25 * HOUR_MS);
+
+        // Late evening belongs to the local day, not the UTC one
+        assert_eq!(
+            Bucket::Day.floor(millis("2024-03-10T23:30:00-04:00"), new_york),
+            spring
+        );
+        assert_eq!(
+            parse_time("2024-03-10", new_york).unwrap(),
+            millis("2024-03-10T05:00:00Z")
+        );
+
+        // The hour from 1am repeats when clocks go back, each is its own bucket
+        assert_eq!(
+            Bucket::Hour.floor(millis("2024-11-03T01:30:00-04:00"), new_york),
+            millis("2024-11-03T01:00:00-04:00")
+        );
+        assert_eq!(
+            Bucket::Hour.floor(millis("2024-11-03T01:30:00-05:00"), new_york),
+            millis("2024-11-03T01:00:00-05:00")
+        );
+
+        let days = Bucket::Day.group(
+            new_york,
+            [
+                (millis("2024-11-03T00:10:00-04:00"), 1),
+                (millis("2024-11-03T23:50:00-05:00"), 2),
+                (millis("2024-11-04T00:10:00-05:00"), 4),
+            ],
+        );
+        assert_eq!(
+            days.iter().map(|day| day.count).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+
+        let autumn_day = TimeRange::day(NaiveDate::from_ymd_opt(2024, 11, 3).unwrap(), new_york)?;
+        assert_eq!(Bucket::Hour.starts(autumn_day, new_york).len(), 25);
+        let spring_day = TimeRange::day(NaiveDate::from_ymd_opt(2024, 3, 10).unwrap(), new_york)?;
+        assert_eq!(Bucket::Hour.starts(spring_day, new_york).len(), 23);
+        assert_eq!(
+            Bucket::Day.starts(TimeRange::new(autumn, next)?, new_york),
+            vec![autumn]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_skipped_midnight() {
+        // Sao Paulo went from midnight straight to 1am on the 4th of November 2018
+        let sao_paulo = zone("America/Sao_Paulo");
+        let day = NaiveDate::from_ymd_opt(2018, 11, 4).unwrap();
+
+        assert_eq!(
+            start_of_day(sao_paulo, day),
+            millis("2018-11-04T01:00:00-02:00")
+        );
+        assert_eq!(
+            Bucket::Day.floor(millis("2018-11-04T12:00:00-02:00"), sao_paulo),
+            start_of_day(sao_paulo, day)
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_dates() -> Result<()> {
+        let new_york = zone("America/New_York");
+        let first = start_of_day(new_york, NaiveDate::MIN);
+        let last = parse_time("+262142-12-31", new_york)?;
+
+        assert!(TimeRange::day(NaiveDate::MAX, new_york).is_err());
+        assert!(TimeRange::last_days(u32::MAX, new_york).is_err());
+        assert!(TimeRange::last_days(7, new_york).is_ok());
+
+        // Buckets at the ends of the calendar stay where they are rather than overflow
+        for bucket in [Bucket::Day, Bucket::Week, Bucket::Month] {
+            bucket.floor(last, new_york);
+            bucket.floor(first, new_york);
+        }
+        let range = TimeRange::new(i64::MAX - 1000, i64::MAX)?;
+        assert_eq!(Bucket::Hour.starts(range, Tz::UTC), vec![i64::MAX - 1000]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_half_hour_zone() {
+        let kolkata = zone("Asia/Kolkata");
+
+        assert_eq!(
+            Bucket::Hour.floor(millis("2024-03-14T20:39:26+05:30"), kolkata),
+            millis("2024-03-14T20:00:00+05:30")
+        );
+        assert!(parse_zone("Mars/Olympus_Mons").is_err());
+    }
+}
@@ -1,11 +1,74 @@
-use serde::Serialize;
+use chrono::{DateTime, Datelike, Timelike};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
 
+/// Totals over a time range, today unless asked otherwise
 #[derive(Serialize)]
 pub struct DashboardStats {
     pub total_today: i64,
     pub first_ts: i64,
     pub last_ts: i64,
     pub top_keys: Vec<(String, i64)>,
+    /// Applications by keystrokes today, most used first
+    pub top_applications: Vec<(String, i64)>,
+    /// Keystrokes per bucket, when grouped by one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buckets: Option<Vec<BucketCount>>,
+}
+
+/// Keystrokes in the bucket starting at `start`, in milliseconds since the epoch
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BucketCount {
+    pub start: i64,
+    pub count: i64,
+}
+
+/// Keystrokes in each hour of one day
+#[derive(Serialize, Debug)]
+pub struct HourlyActivity {
+    /// The day, `YYYY-MM-DD`
+    pub date: String,
+    pub hours: Vec<HourCount>,
+}
+
+/// Keystrokes in the hour starting at `start`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HourCount {
+    pub start: i64,
+    /// Hour on the local clock, repeated or skipped on the days clocks change
+    pub hour: u32,
+    pub count: i64,
+}
+
+/// Keystrokes by day of the week, Monday first, and hour on the local clock
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct WeekdayHours {
+    pub counts: [[i64; 24]; 7],
+}
+
+impl WeekdayHours {
+    /// Sums counts per bucket, none straddling an hour in `zone`, into the weekday and
+    /// hour they fall on
+    pub fn from_counts(zone: Tz, counts: impl IntoIterator<Item = (i64, i64)>) -> Self {
+        let mut matrix = Self::default();
+        for (start, count) in counts {
+            let Some(time) = DateTime::from_timestamp_millis(start) else {
+                continue;
+            };
+            let local = time.with_timezone(&zone);
+            let weekday = local.weekday().num_days_from_monday() as usize;
+            matrix.counts[weekday][local.hour() as usize] += count;
+        }
+
+        matrix
+    }
+}
+
+/// Count of each key pressed in the bucket starting at `start`
+#[derive(Serialize)]
+pub struct KeyBucket {
+    pub start: i64,
+    pub keys: Vec<KeyCount>,
 }
 
 #[derive(Serialize)]
@@ -13,3 +76,81 @@ pub struct KeyCount {
     pub key_name: String,
     pub count: i64,
 }
+
+/// How keystrokes are grouped when counting keys
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyView {
+    /// By display name
+    #[default]
+    Name,
+    /// By physical key, using canonical key ids
+    Physical,
+    /// By the character typed under the layout in use at the time
+    Logical,
+}
+
+#[derive(Serialize)]
+pub struct ShortcutCount {
+    pub combo: String,
+    pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct DailyShortcuts {
+    pub day: String,
+    pub shortcuts: Vec<ShortcutCount>,
+}
+
+#[derive(Serialize, Default)]
+pub struct MouseStats {
+    pub left_clicks: i64,
+    pub right_clicks: i64,
+    pub middle_clicks: i64,
+    pub other_clicks: i64,
+    pub scroll_distance: i64,
+    pub travel_px: f64,
+}
+
+/// A stretch of typing without an idle gap longer than the threshold, in milliseconds
+/// since the epoch
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub started_at: i64,
+    pub ended_at: i64,
+    /// Keystrokes in the session
+    pub event_count: i64,
+}
+
+impl Session {
+    pub fn duration_ms(&self) -> i64 {
+        self.ended_at - self.started_at
+    }
+}
+
+/// How much of a day was spent typing
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ActivitySummary {
+    /// Time spent in sessions, idle gaps between them excluded
+    pub active_ms: i64,
+    pub session_count: usize,
+    pub longest_session: Option<Session>,
+}
+
+impl ActivitySummary {
+    pub fn from_sessions(sessions: &[Session]) -> Self {
+        Self {
+            active_ms: sessions.iter().map(Session::duration_ms).sum(),
+            session_count: sessions.len(),
+            longest_session: sessions.iter().copied().max_by_key(Session::duration_ms),
+        }
+    }
+}
+
+/// A key code stored as `unknown_<code>` because nothing mapped it to a name
+#[derive(Serialize, Debug, PartialEq)]
+pub struct UnknownKeyCount {
+    pub code: u32,
+    pub count: i64,
+    pub last_seen: i64,
+}
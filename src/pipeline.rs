@@ -0,0 +1,676 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use crate::models::events::InputEvent;
+use crate::storage::buffer::{FlushOutcome, KeyEventBuffer};
+
+/// What the capture side does when the writer falls behind and the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackpressurePolicy {
+    /// Wait for the writer to make room
+    Block,
+    /// Discard the oldest queued event to make room for the new one
+    DropOldest,
+    /// Overflow to a file on disk that the writer drains once it catches up
+    Spill,
+}
+
+/// Counters describing what happened to captured events
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    received: AtomicU64,
+    dropped: AtomicU64,
+    spilled: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct PipelineStatsSnapshot {
+    pub received: u64,
+    pub dropped: u64,
+    pub spilled: u64,
+}
+
+impl PipelineStats {
+    pub fn snapshot(&self) -> PipelineStatsSnapshot {
+        PipelineStatsSnapshot {
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            spilled: self.spilled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Shared {
+    stats: PipelineStats,
+    closed: AtomicBool,
+    close_notify: Notify,
+}
+
+/// Creates the capture side and the single writer of an event pipeline.
+///
+/// Events are delivered to `buffer` in the order they were sent.
+pub fn channel(
+    capacity: usize,
+    policy: BackpressurePolicy,
+    spill_path: PathBuf,
+    buffer: KeyEventBuffer,
+) -> Result<(EventSender, EventWriter, PipelineHandle)> {
+    let (tx, rx) = flume::bounded(capacity);
+
+    let (spill, leftovers) = match policy {
+        BackpressurePolicy::Spill => {
+            let (spill, leftovers) = SpillFile::open(spill_path)?;
+            (Some(Arc::new(spill)), leftovers)
+        }
+        _ => (None, Vec::new()),
+    };
+
+    let shared = Arc::new(Shared {
+        stats: PipelineStats::default(),
+        closed: AtomicBool::new(false),
+        close_notify: Notify::new(),
+    });
+
+    let sender = EventSender {
+        tx,
+        evict: (policy == BackpressurePolicy::DropOldest).then(|| rx.clone()),
+        policy,
+        spill: spill.clone(),
+        shared: shared.clone(),
+    };
+
+    let (commands_tx, commands) = mpsc::unbounded_channel();
+
+    let writer = EventWriter {
+        rx,
+        commands,
+        buffer,
+        spill,
+        leftovers,
+        shared: shared.clone(),
+    };
+
+    let handle = PipelineHandle {
+        shared,
+        commands: commands_tx,
+    };
+
+    Ok((sender, writer, handle))
+}
+
+/// Capture side of the pipeline, safe to use from a non-async thread
+pub struct EventSender {
+    tx: flume::Sender<InputEvent>,
+    evict: Option<flume::Receiver<InputEvent>>,
+    policy: BackpressurePolicy,
+    spill: Option<Arc<SpillFile>>,
+    shared: Arc<Shared>,
+}
+
+impl EventSender {
+    /// Queues an event for the writer, applying the backpressure policy if the queue is full.
+    ///
+    /// Events sent after the pipeline has been closed are ignored.
+    pub fn send(&self, event: impl Into<InputEvent>) {
+        let event = event.into();
+        if self.shared.closed.load(Ordering::SeqCst) {
+            debug!("Pipeline closed, ignoring event: {:?}", event);
+            return;
+        }
+        self.shared.stats.received.fetch_add(1, Ordering::Relaxed);
+
+        match self.policy {
+            BackpressurePolicy::Block => {
+                if self.tx.send(event).is_err() {
+                    warn!("Writer has stopped, event lost");
+                }
+            }
+            BackpressurePolicy::DropOldest => self.send_drop_oldest(event),
+            BackpressurePolicy::Spill => self.send_or_spill(event),
+        }
+    }
+
+    fn send_drop_oldest(&self, mut event: InputEvent) {
+        loop {
+            match self.tx.try_send(event) {
+                Ok(()) => return,
+                Err(flume::TrySendError::Full(returned)) => {
+                    event = returned;
+                    if let Some(evicted) = self.evict.as_ref().and_then(|rx| rx.try_recv().ok()) {
+                        debug!("Queue full, dropping oldest event: {:?}", evicted);
+                        self.shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Err(flume::TrySendError::Disconnected(_)) => {
+                    warn!("Writer has stopped, event lost");
+                    return;
+                }
+            }
+        }
+    }
+
+    fn send_or_spill(&self, event: InputEvent) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+
+        // Holding the spill lock while deciding keeps ordering intact, once the spill
+        // is in use every event goes there until the writer has drained it
+        let mut spill_file = spill.lock();
+        let event = if spill_file.is_empty() {
+            match self.tx.try_send(event) {
+                Ok(()) => return,
+                Err(flume::TrySendError::Full(event)) => event,
+                Err(flume::TrySendError::Disconnected(_)) => {
+                    warn!("Writer has stopped, event lost");
+                    return;
+                }
+            }
+        } else {
+            event
+        };
+
+        match spill_file.append(&event) {
+            Ok(()) => {
+                self.shared.stats.spilled.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                warn!("Failed to spill event to disk, dropping it: {:?}", e);
+                self.shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Requests handled by the writer between events
+enum WriterCommand {
+    Flush(oneshot::Sender<Result<FlushOutcome>>),
+    Status(oneshot::Sender<BufferStatus>),
+}
+
+/// What the writer is holding on to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BufferStatus {
+    /// Events received but not yet written to the database
+    pub buffered_events: usize,
+    /// Seconds since the buffer was last flushed, or created
+    pub secs_since_flush: u64,
+}
+
+/// Handle for observing and controlling a running pipeline
+#[derive(Clone)]
+pub struct PipelineHandle {
+    shared: Arc<Shared>,
+    commands: mpsc::UnboundedSender<WriterCommand>,
+}
+
+impl PipelineHandle {
+    pub fn stats(&self) -> PipelineStatsSnapshot {
+        self.shared.stats.snapshot()
+    }
+
+    /// Writes everything the writer has received so far to the database, without waiting
+    /// for the flush threshold or interval
+    pub async fn flush_now(&self) -> Result<FlushOutcome> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .send(WriterCommand::Flush(tx))
+            .map_err(|_| anyhow!("Writer has stopped"))?;
+
+        rx.await.map_err(|_| anyhow!("Writer has stopped"))?
+    }
+
+    /// Reports what the writer is holding, once it gets to the request
+    pub async fn buffer_status(&self) -> Result<BufferStatus> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .send(WriterCommand::Status(tx))
+            .map_err(|_| anyhow!("Writer has stopped"))?;
+
+        rx.await.map_err(|_| anyhow!("Writer has stopped"))
+    }
+
+    /// Stops accepting events, the writer drains everything queued and then exits
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.close_notify.notify_one();
+    }
+}
+
+/// Single consumer that owns the buffer and writes events in order
+pub struct EventWriter {
+    rx: flume::Receiver<InputEvent>,
+    commands: mpsc::UnboundedReceiver<WriterCommand>,
+    buffer: KeyEventBuffer,
+    spill: Option<Arc<SpillFile>>,
+    leftovers: Vec<InputEvent>,
+    shared: Arc<Shared>,
+}
+
+impl EventWriter {
+    /// Runs until the pipeline is closed, then drains queued events and shuts the buffer down
+    pub async fn run(mut self) -> Result<()> {
+        // Replay anything spilled by a previous run before new events
+        for event in std::mem::take(&mut self.leftovers) {
+            self.push(event).await;
+        }
+
+        loop {
+            let flush_deadline = self.buffer.flush_deadline();
+
+            tokio::select! {
+                biased;
+                event = self.rx.recv_async() => match event {
+                    Ok(event) => self.push(event).await,
+                    Err(_) => break,
+                },
+                Some(command) = self.commands.recv() => self.handle_command(command).await,
+                _ = sleep_until(flush_deadline) => {
+                    if let Err(e) = self.buffer.flush_if_due().await {
+                        warn!("Timed flush failed: {:?}", e);
+                    }
+                }
+                _ = self.shared.close_notify.notified() => break,
+            }
+
+            if self.rx.is_empty() {
+                self.drain_spill().await;
+            }
+        }
+
+        // Queued events are older than anything that was spilled
+        while let Ok(event) = self.rx.try_recv() {
+            self.push(event).await;
+        }
+        self.drain_spill().await;
+
+        let stats = self.shared.stats.snapshot();
+        info!(
+            "Pipeline stopped: {} received, {} dropped, {} spilled",
+            stats.received, stats.dropped, stats.spilled
+        );
+
+        self.buffer.shutdown().await
+    }
+
+    async fn handle_command(&mut self, command: WriterCommand) {
+        match command {
+            WriterCommand::Flush(reply) => {
+                // Anything already queued was captured before the request
+                while let Ok(event) = self.rx.try_recv() {
+                    self.push(event).await;
+                }
+                self.drain_spill().await;
+
+                let _ = reply.send(self.buffer.flush().await);
+            }
+            WriterCommand::Status(reply) => {
+                let _ = reply.send(BufferStatus {
+                    buffered_events: self.buffer.len() + self.rx.len(),
+                    secs_since_flush: self.buffer.since_last_flush().as_secs(),
+                });
+            }
+        }
+    }
+
+    async fn push(&mut self, event: InputEvent) {
+        if let Err(e) = self.buffer.push(event).await {
+            warn!("Error pushing event to buffer: {:?}", e);
+        }
+    }
+
+    async fn drain_spill(&mut self) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+
+        let events = {
+            let mut spill_file = spill.lock();
+            if spill_file.is_empty() {
+                return;
+            }
+            match spill_file.take() {
+                Ok(events) => events,
+                Err(e) => {
+                    warn!("Failed to read spilled events: {:?}", e);
+                    return;
+                }
+            }
+        };
+
+        debug!("Draining {} spilled events", events.len());
+        for event in events {
+            self.push(event).await;
+        }
+    }
+}
+
+/// Sleeps until `deadline`, or forever when there is nothing to wait for
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Append-only JSON lines file holding events that did not fit in the queue
+struct SpillFile {
+    inner: Mutex<SpillInner>,
+}
+
+struct SpillInner {
+    path: PathBuf,
+    file: File,
+    len: usize,
+}
+
+impl SpillFile {
+    /// Opens the spill file, returning any events left over from a previous run
+    fn open(path: PathBuf) -> Result<(Self, Vec<InputEvent>)> {
+        let path = crate::config::expand_home(&path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open spill file {:?}", path))?;
+
+        let mut inner = SpillInner { path, file, len: 0 };
+        let leftovers = inner.take()?;
+        if !leftovers.is_empty() {
+            info!(
+                "Found {} spilled events from a previous run",
+                leftovers.len()
+            );
+        }
+
+        Ok((
+            Self {
+                inner: Mutex::new(inner),
+            },
+            leftovers,
+        ))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SpillInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl SpillInner {
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn append(&mut self, event: &InputEvent) -> Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Reads every spilled event and truncates the file
+    fn take(&mut self) -> Result<Vec<InputEvent>> {
+        let reader = BufReader::new(File::open(&self.path)?);
+
+        let mut events = Vec::with_capacity(self.len);
+        for line in reader.lines() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(event) => events.push(event),
+                Err(e) => warn!("Skipping unreadable spilled event {:?}: {}", line, e),
+            }
+        }
+
+        self.file.set_len(0)?;
+        self.len = 0;
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::models::events::KeyEvent;
+    use crate::storage::connection::Database;
+    use std::time::Duration;
+    use tempfile::{NamedTempFile, TempDir};
+
+    async fn setup_buffer() -> Result<(KeyEventBuffer, PathBuf, NamedTempFile)> {
+        let tmp_db = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_db.path());
+        let db = Database::new(tmp_db_path.clone()).await?;
+        db.run_migrations().await?;
+
+        let buffer = KeyEventBuffer::new(db, 10, Duration::from_secs(60));
+        Ok((buffer, tmp_db_path, tmp_db))
+    }
+
+    async fn stored_keys(db_path: PathBuf) -> Result<Vec<String>> {
+        let db = Database::new(db_path).await?;
+        Ok(db
+            .get_events()
+            .await?
+            .into_iter()
+            .map(|e| e.key_name)
+            .collect())
+    }
+
+    fn key(i: i64) -> KeyEvent {
+        KeyEvent::new(format!("key{}", i), i)
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_preserves_order() -> Result<()> {
+        let (buffer, db_path, _tmp_db) = setup_buffer().await?;
+        let tmp_dir = TempDir::new()?;
+        let (sender, writer, handle) = channel(
+            4,
+            BackpressurePolicy::Block,
+            tmp_dir.path().join("spill.jsonl"),
+            buffer,
+        )?;
+
+        let writer_task = tokio::spawn(writer.run());
+
+        // Send from a blocking thread, the same way the rdev listener does
+        tokio::task::spawn_blocking(move || {
+            for i in 0..100 {
+                sender.send(key(i));
+            }
+        })
+        .await?;
+
+        handle.close();
+        writer_task.await??;
+
+        let expected = (0..100).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        assert_eq!(stored_keys(db_path).await?, expected);
+        assert_eq!(handle.stats().received, 100);
+        assert_eq!(handle.stats().dropped, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_drop_oldest() -> Result<()> {
+        let (buffer, db_path, _tmp_db) = setup_buffer().await?;
+        let tmp_dir = TempDir::new()?;
+        let (sender, writer, handle) = channel(
+            2,
+            BackpressurePolicy::DropOldest,
+            tmp_dir.path().join("spill.jsonl"),
+            buffer,
+        )?;
+
+        // Writer is not running yet, so the queue fills up
+        for i in 0..5 {
+            sender.send(key(i));
+        }
+        assert_eq!(handle.stats().dropped, 3);
+
+        handle.close();
+        writer.run().await?;
+
+        assert_eq!(stored_keys(db_path).await?, vec!["key3", "key4"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_spill_to_disk() -> Result<()> {
+        let (buffer, db_path, _tmp_db) = setup_buffer().await?;
+        let tmp_dir = TempDir::new()?;
+        let spill_path = tmp_dir.path().join("spill.jsonl");
+        let (sender, writer, handle) =
+            channel(2, BackpressurePolicy::Spill, spill_path.clone(), buffer)?;
+
+        for i in 0..5 {
+            sender.send(key(i));
+        }
+        assert_eq!(handle.stats().spilled, 3);
+        assert_eq!(fs::read_to_string(&spill_path)?.lines().count(), 3);
+
+        handle.close();
+        writer.run().await?;
+
+        let expected = (0..5).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        assert_eq!(stored_keys(db_path).await?, expected);
+        assert_eq!(fs::metadata(&spill_path)?.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_replays_spill_from_previous_run() -> Result<()> {
+        let (buffer, db_path, _tmp_db) = setup_buffer().await?;
+        let tmp_dir = TempDir::new()?;
+        let spill_path = tmp_dir.path().join("spill.jsonl");
+        fs::write(
+            &spill_path,
+            format!("{}\n", serde_json::to_string(&key(0))?),
+        )?;
+
+        let (sender, writer, handle) =
+            channel(4, BackpressurePolicy::Spill, spill_path.clone(), buffer)?;
+
+        sender.send(key(1));
+        handle.close();
+        writer.run().await?;
+
+        assert_eq!(stored_keys(db_path).await?, vec!["key0", "key1"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_flush_now() -> Result<()> {
+        let (buffer, db_path, _tmp_db) = setup_buffer().await?;
+        let tmp_dir = TempDir::new()?;
+        let (sender, writer, handle) = channel(
+            4,
+            BackpressurePolicy::Block,
+            tmp_dir.path().join("spill.jsonl"),
+            buffer,
+        )?;
+
+        let writer_task = tokio::spawn(writer.run());
+
+        // Below the threshold and well within the interval
+        sender.send(key(0));
+        sender.send(key(1));
+        assert_eq!(handle.buffer_status().await?.buffered_events, 2);
+        assert_eq!(handle.flush_now().await?, FlushOutcome::Written);
+
+        assert_eq!(stored_keys(db_path).await?, vec!["key0", "key1"]);
+        assert_eq!(handle.buffer_status().await?.buffered_events, 0);
+
+        handle.close();
+        writer_task.await??;
+        assert!(handle.flush_now().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipeline_flushes_partial_batch_on_interval() -> Result<()> {
+        // sqlx takes the paused clock jumping ahead while it waits on its worker thread for
+        // a timeout. A blocking task keeps the clock still until advanced
+        let (release_clock, held) = std::sync::mpsc::channel::<()>();
+        tokio::task::spawn_blocking(move || held.recv());
+
+        let tmp_db = NamedTempFile::new()?;
+        let db = Database::new(PathBuf::from(tmp_db.path())).await?;
+        db.run_migrations().await?;
+
+        let clock = Arc::new(MockClock::new());
+        let buffer =
+            KeyEventBuffer::with_clock(db.clone(), 10, Duration::from_secs(60), clock.clone());
+        let tmp_dir = TempDir::new()?;
+        let (sender, writer, handle) = channel(
+            4,
+            BackpressurePolicy::Block,
+            tmp_dir.path().join("spill.jsonl"),
+            buffer,
+        )?;
+        let writer_task = tokio::spawn(writer.run());
+
+        // Below the threshold, and nothing arrives after them
+        sender.send(key(0));
+        sender.send(key(1));
+        assert_eq!(handle.buffer_status().await?.buffered_events, 2);
+        assert!(db.get_events().await?.is_empty());
+
+        // The mock clock started after the runtime's, so the runtime goes a little further
+        clock.advance(Duration::from_secs(60));
+        tokio::time::advance(Duration::from_secs(61)).await;
+        tokio::task::yield_now().await;
+
+        // The writer answers once it is done with the flush
+        assert_eq!(handle.buffer_status().await?.buffered_events, 0);
+        assert_eq!(db.get_events().await?.len(), 2);
+
+        handle.close();
+        writer_task.await??;
+        drop(release_clock);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_ignores_events_after_close() -> Result<()> {
+        let (buffer, db_path, _tmp_db) = setup_buffer().await?;
+        let tmp_dir = TempDir::new()?;
+        let (sender, writer, handle) = channel(
+            4,
+            BackpressurePolicy::Block,
+            tmp_dir.path().join("spill.jsonl"),
+            buffer,
+        )?;
+
+        sender.send(key(0));
+        handle.close();
+        sender.send(key(1));
+
+        writer.run().await?;
+
+        assert_eq!(stored_keys(db_path).await?, vec!["key0"]);
+        assert_eq!(handle.stats().received, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::input::keymap::KeyMap;
+use crate::input::modifiers::COMBO_MODIFIERS;
+use crate::input::naming::KeyNaming;
+use crate::models::events::{CharClass, InputEvent, KeyEvent, ShortcutEvent};
+
+/// Key name stored for every keystroke at [`PrivacyLevel::Counts`]
+pub const COUNTED_KEY_NAME: &str = "key";
+
+/// How much of what was typed is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyLevel {
+    /// Every key, with the characters it typed
+    #[default]
+    Full,
+    /// Only the kind of key, e.g. `letter` or `navigation`
+    KeyClass,
+    /// Only that a key was pressed, no shortcuts
+    Counts,
+}
+
+impl PrivacyLevel {
+    /// Reduces a captured event to what this level stores, `None` if it is not stored at all
+    pub fn apply(self, event: InputEvent) -> Option<InputEvent> {
+        match (self, event) {
+            (Self::Full, event) | (_, event @ (InputEvent::Mouse(_) | InputEvent::Pause(_))) => {
+                Some(event)
+            }
+            (level, InputEvent::Key(event)) => Some(level.apply_key(event).into()),
+            (Self::KeyClass, InputEvent::Shortcut(event)) => {
+                Some(ShortcutEvent::new(redact_combo(&event.combo), event.timestamp).into())
+            }
+            (Self::Counts, InputEvent::Shortcut(_)) => None,
+        }
+    }
+
+    fn apply_key(self, event: KeyEvent) -> KeyEvent {
+        let key_name = match self {
+            Self::Full => return event,
+            Self::KeyClass => {
+                KeyClass::classify(event.key_id.as_deref(), &event.key_name).to_string()
+            }
+            Self::Counts => COUNTED_KEY_NAME.to_string(),
+        };
+
+        let mut stored = KeyEvent::new(key_name, event.timestamp);
+        stored.is_repeat = event.is_repeat;
+        // Which application is kept, not what its windows were called
+        stored.application = event.application;
+        if self == Self::KeyClass {
+            stored.dwell_ms = event.dwell_ms;
+        }
+        stored
+    }
+}
+
+/// Rows changed when downgrading stored history, see `Database::downgrade_privacy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DowngradeSummary {
+    pub keys: u64,
+    pub shortcuts: u64,
+    /// Keystrokes whose window title was removed
+    pub window_titles: u64,
+}
+
+/// Coarsens keystroke and shortcut timestamps to fixed buckets and shuffles their order
+/// within each bucket, so stored rows cannot be read back as text.
+///
+/// Counts per bucket, and so per hour when the bucket divides an hour, stay exact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scrambler {
+    bucket_ms: i64,
+}
+
+impl Scrambler {
+    pub fn new(bucket: Duration) -> Self {
+        let bucket_ms = bucket.as_millis().clamp(1, i64::MAX as u128) as i64;
+        Self { bucket_ms }
+    }
+
+    fn bucket_start(&self, timestamp: i64) -> i64 {
+        timestamp - timestamp.rem_euclid(self.bucket_ms)
+    }
+
+    /// Rounds the timestamp of a keystroke or shortcut down to the start of its bucket,
+    /// returning false for mouse activity and pauses which are left alone
+    pub fn coarsen(&self, event: &mut InputEvent) -> bool {
+        let timestamp = match event {
+            InputEvent::Key(event) => &mut event.timestamp,
+            InputEvent::Shortcut(event) => &mut event.timestamp,
+            InputEvent::Mouse(_) | InputEvent::Pause(_) => return false,
+        };
+        *timestamp = self.bucket_start(*timestamp);
+        true
+    }
+
+    /// When the earliest bucket of `events` ends, in milliseconds since the epoch
+    pub fn next_close(&self, events: &[InputEvent]) -> Option<i64> {
+        events
+            .iter()
+            .filter_map(event_timestamp)
+            .min()
+            .map(|timestamp| self.bucket_start(timestamp) + self.bucket_ms)
+    }
+
+    /// Takes the events whose bucket ended by `now` out of `held`, shuffled within each
+    /// bucket and with the buckets in order
+    pub fn release(&self, held: &mut Vec<InputEvent>, now: i64) -> Vec<InputEvent> {
+        let (closed, open) = std::mem::take(held).into_iter().partition(|event| {
+            event_timestamp(event)
+                .is_none_or(|timestamp| self.bucket_start(timestamp) + self.bucket_ms <= now)
+        });
+        *held = open;
+
+        self.shuffle(closed)
+    }
+
+    /// Shuffles `events` within each bucket, regardless of whether the bucket has ended
+    pub fn shuffle(&self, mut events: Vec<InputEvent>) -> Vec<InputEvent> {
+        events.shuffle(&mut rand::thread_rng());
+        // Stable, so the shuffled order within a bucket is kept
+        events.sort_by_key(|event| event_timestamp(event).map(|t| self.bucket_start(t)));
+        events
+    }
+}
+
+fn event_timestamp(event: &InputEvent) -> Option<i64> {
+    match event {
+        InputEvent::Key(event) => Some(event.timestamp),
+        InputEvent::Shortcut(event) => Some(event.timestamp),
+        InputEvent::Mouse(_) | InputEvent::Pause(_) => None,
+    }
+}
+
+/// Broad kind of a key, all that is stored at [`PrivacyLevel::KeyClass`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyClass {
+    Letter,
+    Digit,
+    Punctuation,
+    /// Space, enter and tab
+    Whitespace,
+    /// Backspace, delete and insert
+    Editing,
+    Modifier,
+    Navigation,
+    Function,
+    Other,
+}
+
+impl KeyClass {
+    pub const ALL: [KeyClass; 9] = [
+        Self::Letter,
+        Self::Digit,
+        Self::Punctuation,
+        Self::Whitespace,
+        Self::Editing,
+        Self::Modifier,
+        Self::Navigation,
+        Self::Function,
+        Self::Other,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Letter => "letter",
+            Self::Digit => "digit",
+            Self::Punctuation => "punctuation",
+            Self::Whitespace => "whitespace",
+            Self::Editing => "editing",
+            Self::Modifier => "modifier",
+            Self::Navigation => "navigation",
+            Self::Function => "function",
+            Self::Other => "other",
+        }
+    }
+
+    /// Classifies a stored keystroke, by its key id when it has one
+    pub fn classify(key_id: Option<&str>, key_name: &str) -> Self {
+        match key_id {
+            Some(key_id) => Self::of_key_id(key_id),
+            None => Self::of_name(key_name),
+        }
+    }
+
+    /// Classifies a canonical key id, see `canonical_key_id`
+    pub fn of_key_id(key_id: &str) -> Self {
+        let numbered = |prefix: &str| {
+            key_id
+                .strip_prefix(prefix)
+                .is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()))
+        };
+
+        match key_id {
+            _ if key_id.len() == 4 && key_id.starts_with("Key") => Self::Letter,
+            _ if numbered("Digit") || numbered("Numpad") => Self::Digit,
+            _ if numbered("F") => Self::Function,
+            "Backquote" | "Minus" | "Equal" | "BracketLeft" | "BracketRight" | "Semicolon"
+            | "Quote" | "Backslash" | "IntlBackslash" | "Comma" | "Period" | "Slash"
+            | "NumpadSubtract" | "NumpadAdd" | "NumpadMultiply" | "NumpadDivide"
+            | "NumpadDecimal" => Self::Punctuation,
+            "Space" | "Enter" | "NumpadEnter" | "Tab" => Self::Whitespace,
+            "Backspace" | "Delete" | "Insert" => Self::Editing,
+            "ControlLeft" | "ControlRight" | "ShiftLeft" | "ShiftRight" | "AltLeft"
+            | "AltRight" | "MetaLeft" | "MetaRight" | "CapsLock" | "Fn" => Self::Modifier,
+            "ArrowUp" | "ArrowDown" | "ArrowLeft" | "ArrowRight" | "Home" | "End" | "PageUp"
+            | "PageDown" => Self::Navigation,
+            _ => Self::Other,
+        }
+    }
+
+    /// Classifies a key by its display name, for keystrokes stored without a key id and
+    /// for shortcut combos
+    pub fn of_name(name: &str) -> Self {
+        if let Some(class) = Self::ALL.into_iter().find(|class| class.as_str() == name) {
+            return class;
+        }
+
+        let platform_modifier = [KeyNaming::MACOS, KeyNaming::LINUX, KeyNaming::WINDOWS]
+            .iter()
+            .any(|naming| {
+                [
+                    naming.meta_left,
+                    naming.meta_right,
+                    naming.alt_left,
+                    naming.alt_right,
+                ]
+                .contains(&name)
+            });
+        if platform_modifier {
+            return Self::Modifier;
+        }
+
+        static BUNDLED: OnceLock<KeyMap> = OnceLock::new();
+        if let Some(key_id) = BUNDLED.get_or_init(KeyMap::bundled).key_id(name) {
+            return Self::of_key_id(key_id);
+        }
+
+        // Keys named after the text they typed
+        let mut chars = name.chars();
+        match (chars.next().and_then(CharClass::of), chars.next()) {
+            (Some(CharClass::Letter | CharClass::Mark), None) => Self::Letter,
+            (Some(CharClass::Number), None) => Self::Digit,
+            (Some(CharClass::Punctuation | CharClass::Symbol), None) => Self::Punctuation,
+            (Some(CharClass::Whitespace), None) => Self::Whitespace,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for KeyClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Replaces the key of a shortcut combo with its class, e.g. `ctrl+shift+t` becomes
+/// `ctrl+shift+letter`
+pub fn redact_combo(combo: &str) -> String {
+    let mut key = combo;
+    for modifier in COMBO_MODIFIERS {
+        key = key
+            .strip_prefix(modifier)
+            .and_then(|rest| rest.strip_prefix('+'))
+            .unwrap_or(key);
+    }
+
+    let modifiers = &combo[..combo.len() - key.len()];
+    format!("{}{}", modifiers, KeyClass::of_name(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str, key_id: Option<&str>) -> KeyEvent {
+        let mut event = KeyEvent::new(name.to_string(), 1000);
+        event.key_id = key_id.map(str::to_string);
+        event.logical_key = Some(name.to_string());
+        event.typed_text = Some(name.to_string());
+        event.char_class = Some(CharClass::Letter);
+        event.dwell_ms = Some(80);
+        event
+    }
+
+    fn apply_key(level: PrivacyLevel, event: KeyEvent) -> KeyEvent {
+        match level.apply(event.into()) {
+            Some(InputEvent::Key(event)) => event,
+            other => panic!("expected a key event, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_classifies_keys() {
+        let class = |key_id: Option<&str>, name: &str| KeyClass::classify(key_id, name);
+
+        assert_eq!(class(Some("KeyQ"), "q"), KeyClass::Letter);
+        assert_eq!(class(Some("Digit7"), "7"), KeyClass::Digit);
+        assert_eq!(class(Some("Numpad3"), "numpad_3"), KeyClass::Digit);
+        assert_eq!(class(Some("F11"), "f11"), KeyClass::Function);
+        assert_eq!(class(Some("Slash"), "/"), KeyClass::Punctuation);
+        assert_eq!(class(Some("Enter"), "return"), KeyClass::Whitespace);
+        assert_eq!(class(Some("Backspace"), "backspace"), KeyClass::Editing);
+        assert_eq!(class(Some("MetaLeft"), "command_left"), KeyClass::Modifier);
+        assert_eq!(class(Some("PageDown"), "page_down"), KeyClass::Navigation);
+        assert_eq!(class(Some("Escape"), "escape"), KeyClass::Other);
+
+        // Rows recorded before key ids, or keys rdev does not know
+        assert_eq!(class(None, "page_up"), KeyClass::Navigation);
+        assert_eq!(class(None, "opt_right"), KeyClass::Modifier);
+        assert_eq!(class(None, "é"), KeyClass::Letter);
+        assert_eq!(class(None, "unknown_179"), KeyClass::Other);
+
+        // Already reduced to a class
+        assert_eq!(class(None, "navigation"), KeyClass::Navigation);
+    }
+
+    #[test]
+    fn test_full_keeps_everything() {
+        let event = key("a", Some("KeyA"));
+        assert_eq!(apply_key(PrivacyLevel::Full, event.clone()), event);
+    }
+
+    #[test]
+    fn test_key_class_drops_typed_content() {
+        let mut event = key("a", Some("KeyA"));
+        event.is_repeat = true;
+
+        let stored = apply_key(PrivacyLevel::KeyClass, event);
+        assert_eq!(stored.key_name, "letter");
+        assert_eq!(stored.key_id, None);
+        assert_eq!(stored.logical_key, None);
+        assert_eq!(stored.typed_text, None);
+        assert_eq!(stored.char_class, None);
+        assert_eq!(stored.timestamp, 1000);
+        assert_eq!(stored.dwell_ms, Some(80));
+        assert!(stored.is_repeat);
+
+        let shortcut = ShortcutEvent::new("ctrl+shift+t".to_string(), 1000);
+        assert_eq!(
+            PrivacyLevel::KeyClass.apply(shortcut.into()),
+            Some(ShortcutEvent::new("ctrl+shift+letter".to_string(), 1000).into())
+        );
+    }
+
+    #[test]
+    fn test_counts_keeps_only_keystrokes() {
+        let stored = apply_key(PrivacyLevel::Counts, key("a", Some("KeyA")));
+        assert_eq!(stored.key_name, COUNTED_KEY_NAME);
+        assert_eq!(stored.key_id, None);
+        assert_eq!(stored.typed_text, None);
+        assert_eq!(stored.dwell_ms, None);
+
+        let shortcut = ShortcutEvent::new("meta+c".to_string(), 1000);
+        assert_eq!(PrivacyLevel::Counts.apply(shortcut.into()), None);
+    }
+
+    #[test]
+    fn test_redact_combo() {
+        assert_eq!(
+            redact_combo("ctrl+alt+shift+meta+1"),
+            "ctrl+alt+shift+meta+digit"
+        );
+        assert_eq!(redact_combo("alt+left"), "alt+navigation");
+        assert_eq!(redact_combo("ctrl+="), "ctrl+punctuation");
+        assert_eq!(redact_combo("ctrl+letter"), "ctrl+letter");
+    }
+
+    #[test]
+    fn test_scrambler_coarsens_and_holds_open_buckets() {
+        let scrambler = Scrambler::new(Duration::from_secs(60));
+
+        let mut held = Vec::new();
+        for (i, timestamp) in [59_000, 61_000, 1_000, 119_999, 60_000]
+            .into_iter()
+            .enumerate()
+        {
+            let mut event = KeyEvent::new(format!("key{}", i), timestamp).into();
+            assert!(scrambler.coarsen(&mut event));
+            held.push(event);
+        }
+        assert_eq!(scrambler.next_close(&held), Some(60_000));
+
+        // Only the first minute has ended
+        let released = scrambler.release(&mut held, 60_000);
+        assert_eq!(released.len(), 2);
+        assert!(released
+            .iter()
+            .all(|event| event_timestamp(event) == Some(0)));
+        assert_eq!(held.len(), 3);
+        assert_eq!(scrambler.next_close(&held), Some(120_000));
+
+        let released = scrambler.release(&mut held, 120_000);
+        assert_eq!(released.len(), 3);
+        assert!(released
+            .iter()
+            .all(|event| event_timestamp(event) == Some(60_000)));
+        assert!(held.is_empty());
+    }
+
+    #[test]
+    fn test_scrambler_shuffles_within_buckets() {
+        let scrambler = Scrambler::new(Duration::from_secs(60));
+
+        let keys = |events: &[InputEvent]| {
+            events
+                .iter()
+                .map(|event| match event {
+                    InputEvent::Key(key) => key.key_name.clone(),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let typed = "abcdefghijklmnopqrstuvwxyz"
+            .chars()
+            .enumerate()
+            .map(|(i, c)| KeyEvent::new(c.to_string(), i as i64 * 100).into())
+            .chain(std::iter::once(
+                KeyEvent::new("z2".to_string(), 60_000).into(),
+            ))
+            .collect::<Vec<InputEvent>>();
+        let in_order = keys(&typed);
+
+        let shuffled = (0..5)
+            .map(|_| keys(&scrambler.shuffle(typed.clone())))
+            .collect::<Vec<_>>();
+
+        // Getting 26 keys back in typing order five times over is practically impossible
+        assert!(shuffled.iter().any(|keys| *keys != in_order));
+        for mut keys in shuffled {
+            // The later bucket stays after the earlier one
+            assert_eq!(keys.last().map(String::as_str), Some("z2"));
+            keys.sort();
+            assert_eq!(keys, in_order);
+        }
+    }
+}
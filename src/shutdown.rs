@@ -0,0 +1,24 @@
+use anyhow::Result;
+
+/// Resolves once the process is asked to stop, returning the name of the signal received
+#[cfg(unix)]
+pub async fn shutdown_signal() -> Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    let name = tokio::select! {
+        _ = sigint.recv() => "SIGINT",
+        _ = sigterm.recv() => "SIGTERM",
+    };
+
+    Ok(name)
+}
+
+/// Resolves once the process is asked to stop, returning the name of the signal received
+#[cfg(not(unix))]
+pub async fn shutdown_signal() -> Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
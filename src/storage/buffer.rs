@@ -1,33 +1,87 @@
 use anyhow::Result;
-use log::{debug, warn};
-use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use crate::models::events::KeyEvent;
+use crate::clock::{Clock, SystemClock};
+use crate::models::events::InputEvent;
+use crate::privacy::Scrambler;
 
 use super::connection::Database;
+use super::spool::Spool;
+
+/// Where a flushed batch ended up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushOutcome {
+    /// Written to the database, or there was nothing to write
+    Written,
+    /// The database rejected it, so it was spooled to be written on a later flush
+    Spooled,
+}
 
 pub struct KeyEventBuffer {
-    events: Vec<KeyEvent>,
+    events: Vec<InputEvent>,
+    /// Events waiting for their scrambling bucket to end before they can be flushed
+    held: Vec<InputEvent>,
+    /// When the oldest event currently held was pushed
+    oldest_event: Option<Instant>,
     last_flush: Instant,
     flush_threshold: usize,
     flush_interval: Duration,
+    clock: Arc<dyn Clock>,
 
     db: Database,
+    spool: Option<Spool>,
+    scrambler: Option<Scrambler>,
 }
 
 impl KeyEventBuffer {
     pub fn new(db: Database, flush_threshold: usize, flush_interval: Duration) -> Self {
+        Self::with_clock(db, flush_threshold, flush_interval, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(
+        db: Database,
+        flush_threshold: usize,
+        flush_interval: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         Self {
             events: Vec::with_capacity(flush_threshold),
-            last_flush: Instant::now(),
+            held: Vec::new(),
+            oldest_event: None,
+            last_flush: clock.now(),
             flush_threshold,
             flush_interval,
+            clock,
             db,
+            spool: None,
+            scrambler: None,
         }
     }
 
-    pub async fn push(&mut self, key: KeyEvent) -> Result<()> {
-        self.events.push(key);
+    /// Batches the database rejects are written to `spool` instead of being held in memory
+    pub fn with_spool(mut self, spool: Spool) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Keystrokes and shortcuts are coarsened and shuffled by `scrambler`, and held back
+    /// until their bucket ends so rows from one bucket are written together
+    pub fn with_scrambler(mut self, scrambler: Scrambler) -> Self {
+        self.scrambler = Some(scrambler);
+        self
+    }
+
+    pub async fn push(&mut self, event: impl Into<InputEvent>) -> Result<()> {
+        let mut event = event.into();
+        match self.scrambler {
+            Some(scrambler) if scrambler.coarsen(&mut event) => {
+                self.held.push(event);
+                self.release_closed_buckets();
+            }
+            _ => self.add_ready(vec![event]),
+        }
 
         if self.should_flush() {
             debug!(
@@ -39,27 +93,180 @@ impl KeyEventBuffer {
         Ok(())
     }
 
+    fn add_ready(&mut self, events: Vec<InputEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        if self.events.is_empty() {
+            self.oldest_event = Some(self.clock.now());
+        }
+        self.events.extend(events);
+    }
+
+    fn wall_millis(&self) -> i64 {
+        let since_epoch = self.clock.wall_time().duration_since(UNIX_EPOCH);
+        since_epoch.map_or(0, |elapsed| elapsed.as_millis() as i64)
+    }
+
+    /// Makes the held events whose bucket has ended ready to flush
+    fn release_closed_buckets(&mut self) {
+        let Some(scrambler) = self.scrambler else {
+            return;
+        };
+
+        let now = self.wall_millis();
+        let released = scrambler.release(&mut self.held, now);
+        self.add_ready(released);
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len() + self.held.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty() && self.held.is_empty()
+    }
+
+    pub fn last_flush(&self) -> Instant {
+        self.last_flush
+    }
+
+    pub fn since_last_flush(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.last_flush)
+    }
+
     pub fn should_flush(&self) -> bool {
         self.events.len() >= self.flush_threshold
-            || self.last_flush.elapsed() >= self.flush_interval
+            || self
+                .flush_deadline()
+                .is_some_and(|deadline| self.clock.now() >= deadline)
+    }
+
+    /// The point at which the buffered events have been held for the full flush interval,
+    /// `None` while the buffer is empty
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        let flush = self
+            .oldest_event
+            .map(|oldest_event| oldest_event + self.flush_interval);
+
+        // Held events need a wake up once their bucket ends
+        let bucket_close = self
+            .scrambler
+            .and_then(|scrambler| scrambler.next_close(&self.held))
+            .map(|close| {
+                let wait = close.saturating_sub(self.wall_millis()).max(0);
+                self.clock.now() + Duration::from_millis(wait as u64)
+            });
+
+        match (flush, bucket_close) {
+            (Some(flush), Some(close)) => Some(flush.min(close)),
+            (flush, close) => flush.or(close),
+        }
+    }
+
+    /// Flushes if the buffered events are due, called periodically regardless of new input
+    pub async fn flush_if_due(&mut self) -> Result<()> {
+        self.release_closed_buckets();
+        if self.should_flush() {
+            debug!(
+                "Flush interval elapsed, flushing {} events",
+                self.events.len()
+            );
+            self.flush().await?;
+        }
+        Ok(())
     }
 
-    pub async fn flush(&mut self) -> Result<()> {
+    /// Writes the buffered events to the database, or to the spool if the database fails.
+    /// Errors only when the batch could be written to neither and is kept for a retry
+    pub async fn flush(&mut self) -> Result<FlushOutcome> {
         if self.events.is_empty() {
-            return Ok(());
+            return Ok(FlushOutcome::Written);
         }
 
         let batch = std::mem::take(&mut self.events);
-        let batch_len = batch.len();
 
-        if let Err(e) = self.db.insert_events(&batch).await {
-            warn!("failed to flush {} events to database: {}", batch_len, e);
-            self.events = batch;
-            return Err(e);
+        // Spooled events are older, so they go in first to keep rows in capture order
+        let result = match self.replay_spool().await {
+            Ok(_) => self.db.insert_events(&batch).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            warn!("failed to flush {} events to database: {}", batch.len(), e);
+            return self.handle_failed_batch(batch, e);
         }
 
-        self.last_flush = Instant::now();
-        Ok(())
+        self.oldest_event = None;
+        self.last_flush = self.clock.now();
+        Ok(FlushOutcome::Written)
+    }
+
+    /// Writes any spooled batches to the database, returning how many events were replayed
+    pub async fn replay_spool(&mut self) -> Result<usize> {
+        let Some(spool) = &self.spool else {
+            return Ok(0);
+        };
+        if spool.is_empty()? {
+            return Ok(0);
+        }
+
+        let events = spool
+            .read_all()?
+            .batches
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        // A crash between the insert and clearing the spool replays these events again,
+        // duplicates are preferable to losing them
+        self.db.insert_events(&events).await?;
+        spool.clear()?;
+
+        info!("Replayed {} spooled events", events.len());
+        Ok(events.len())
+    }
+
+    /// Moves a batch the database rejected to the spool, falling back to keeping it in memory
+    fn handle_failed_batch(
+        &mut self,
+        batch: Vec<InputEvent>,
+        error: anyhow::Error,
+    ) -> Result<FlushOutcome> {
+        if let Some(spool) = &self.spool {
+            match spool.append(&batch) {
+                Ok(()) => {
+                    info!("{} events spooled to {:?}", batch.len(), spool.path());
+                    self.oldest_event = None;
+                    return Ok(FlushOutcome::Spooled);
+                }
+                Err(e) => warn!("failed to spool {} events: {:?}", batch.len(), e),
+            }
+        }
+
+        self.events = batch;
+        // Give the database a full interval before retrying
+        self.oldest_event = Some(self.clock.now());
+        Err(error)
+    }
+
+    /// Drains any buffered events and closes the database.
+    ///
+    /// The database is closed even if the final flush fails, in which case
+    /// the flush error is returned.
+    pub async fn shutdown(mut self) -> Result<()> {
+        info!("Shutting down buffer, flushing {} events", self.len());
+
+        // Buckets still open are written as they are, still shuffled
+        if let Some(scrambler) = self.scrambler {
+            let held = scrambler.shuffle(std::mem::take(&mut self.held));
+            self.add_ready(held);
+        }
+
+        // Spooled events are replayed on the next start
+        let result = self.flush().await;
+        self.db.close().await;
+        result.map(|_| ())
     }
 }
 
@@ -68,8 +275,10 @@ mod tests {
     use std::path::PathBuf;
 
     use super::*;
+    use crate::clock::MockClock;
+    use crate::models::events::KeyEvent;
     use chrono::Utc;
-    use tempfile::NamedTempFile;
+    use tempfile::{NamedTempFile, TempDir};
 
     #[tokio::test]
     async fn test_buffer_flush_by_threshold() -> Result<()> {
@@ -87,10 +296,7 @@ mod tests {
 
         for i in 0..5 {
             buffer
-                .push(KeyEvent {
-                    timestamp: Utc::now().timestamp(),
-                    key_name: format!("key{}", i),
-                })
+                .push(KeyEvent::new(format!("key{}", i), Utc::now().timestamp()))
                 .await?;
         }
 
@@ -109,32 +315,189 @@ mod tests {
         db.run_migrations().await?;
 
         let flush_threshold = 10; // Large enough to not trigger by threshold
-
-        // Note: This is assuming pushing to buffer is essentially instant
-        // this ensures the unit test runs quickly but *may* cause flakiness
-        let flush_interval = 1;
+        let flush_interval = Duration::from_secs(3);
+        let clock = Arc::new(MockClock::new());
 
         let mut buffer =
-            KeyEventBuffer::new(db, flush_threshold, Duration::from_secs(flush_interval));
+            KeyEventBuffer::with_clock(db, flush_threshold, flush_interval, clock.clone());
 
         for i in 0..5 {
             buffer
-                .push(KeyEvent {
-                    timestamp: Utc::now().timestamp(),
-                    key_name: format!("key{}", i),
-                })
+                .push(KeyEvent::new(format!("key{}", i), Utc::now().timestamp()))
                 .await?;
         }
 
-        // Sleep and then trigger a new keypress to trigger flush by interval
-        tokio::time::sleep(Duration::from_secs(flush_interval + 1)).await;
+        // Not due yet
+        clock.advance(Duration::from_secs(2));
+        buffer.flush_if_due().await?;
+        assert_eq!(buffer.db.get_events().await?.len(), 0);
+
+        // No further input is needed once the interval has elapsed
+        clock.advance(Duration::from_secs(1));
+        buffer.flush_if_due().await?;
+        assert_eq!(buffer.db.get_events().await?.len(), 5);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.flush_deadline(), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_buffer_interval_starts_at_oldest_event() -> Result<()> {
+        // Setup database
+        let tmp_db = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_db.path());
+        let db = Database::new(tmp_db_path).await?;
+        db.run_migrations().await?;
+
+        let clock = Arc::new(MockClock::new());
+        let mut buffer = KeyEventBuffer::with_clock(db, 10, Duration::from_secs(3), clock.clone());
 
+        // Idle time before the first event does not count towards the interval
+        clock.advance(Duration::from_secs(60));
         buffer
-            .push(KeyEvent::new("key6".to_string(), Utc::now().timestamp()))
+            .push(KeyEvent::new("key0".to_string(), Utc::now().timestamp()))
             .await?;
+        assert_eq!(
+            buffer.flush_deadline(),
+            Some(clock.now() + Duration::from_secs(3))
+        );
+        assert_eq!(buffer.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_buffer_shutdown_flushes_remaining_events() -> Result<()> {
+        // Setup database
+        let tmp_db = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_db.path());
+        let db = Database::new(tmp_db_path.clone()).await?;
+        db.run_migrations().await?;
+
+        // Neither the threshold nor the interval will trigger a flush
+        let mut buffer = KeyEventBuffer::new(db, 30, Duration::from_secs(60));
+
+        for i in 0..3 {
+            buffer
+                .push(KeyEvent::new(format!("key{}", i), Utc::now().timestamp()))
+                .await?;
+        }
+
+        buffer.shutdown().await?;
+
+        // Reopen the database, as shutdown closes the pool
+        let db = Database::new(tmp_db_path).await?;
+        let events = db.get_events().await?;
+        assert_eq!(events.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_buffer_spools_failed_flush() -> Result<()> {
+        // Setup database
+        let tmp_db = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_db.path());
+        let db = Database::new(tmp_db_path.clone()).await?;
+        db.run_migrations().await?;
+
+        let tmp_dir = TempDir::new()?;
+        let spool_path = tmp_dir.path().join("spool.bin");
+
+        // A closed pool makes every insert fail
+        db.close().await;
+        let mut buffer = KeyEventBuffer::new(db, 30, Duration::from_secs(60))
+            .with_spool(Spool::open(&spool_path, 1024 * 1024)?);
+
+        for i in 0..3 {
+            buffer.push(KeyEvent::new(format!("key{}", i), i)).await?;
+        }
+        assert_eq!(buffer.flush().await?, FlushOutcome::Spooled);
+        assert!(buffer.is_empty());
+
+        // The next run replays the spool ahead of anything new
+        let db = Database::new(tmp_db_path).await?;
+        let mut buffer = KeyEventBuffer::new(db, 30, Duration::from_secs(60))
+            .with_spool(Spool::open(&spool_path, 1024 * 1024)?);
+
+        buffer.push(KeyEvent::new("key3".to_string(), 3)).await?;
+        assert_eq!(buffer.flush().await?, FlushOutcome::Written);
+
+        let keys = buffer
+            .db
+            .get_events()
+            .await?
+            .into_iter()
+            .map(|e| e.key_name)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["key0", "key1", "key2", "key3"]);
+        assert!(Spool::open(&spool_path, 1024 * 1024)?.is_empty()?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_buffer_keeps_batch_when_spool_is_full() -> Result<()> {
+        // Setup database
+        let tmp_db = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_db.path());
+        let db = Database::new(tmp_db_path).await?;
+        db.run_migrations().await?;
+
+        let tmp_dir = TempDir::new()?;
+        db.close().await;
+        let mut buffer = KeyEventBuffer::new(db, 30, Duration::from_secs(60))
+            .with_spool(Spool::open(&tmp_dir.path().join("spool.bin"), 16)?);
+
+        buffer.push(KeyEvent::new("key0".to_string(), 0)).await?;
+        assert!(buffer.flush().await.is_err());
+        assert_eq!(buffer.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_buffer_scrambles_within_buckets() -> Result<()> {
+        // Setup database
+        let tmp_db = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_db.path());
+        let db = Database::new(tmp_db_path).await?;
+        db.run_migrations().await?;
+
+        let clock = Arc::new(MockClock::at(UNIX_EPOCH + Duration::from_secs(3600)));
+        let scrambler = Scrambler::new(Duration::from_secs(60));
+        let mut buffer = KeyEventBuffer::with_clock(db, 5, Duration::from_secs(3), clock.clone())
+            .with_scrambler(scrambler);
+
+        // Typed during the first ten seconds of the hour
+        let start = 3_600_000;
+        for (i, key) in ["h", "u", "n", "t", "e", "r", "2"].iter().enumerate() {
+            buffer
+                .push(KeyEvent::new(key.to_string(), start + i as i64 * 1000))
+                .await?;
+        }
+        clock.advance(Duration::from_secs(10));
+
+        // Held until the minute ends, even past the threshold and interval
+        buffer.flush_if_due().await?;
+        assert_eq!(buffer.db.get_events().await?.len(), 0);
+        assert_eq!(buffer.len(), 7);
+        assert_eq!(
+            buffer.flush_deadline(),
+            Some(clock.now() + Duration::from_secs(50))
+        );
+
+        clock.advance(Duration::from_secs(50));
+        buffer.flush_if_due().await?;
 
         let events = buffer.db.get_events().await?;
-        assert_eq!(events.len(), 6);
+        assert_eq!(events.len(), 7);
+        assert!(events.iter().all(|event| event.timestamp == start));
+
+        let mut keys = events.into_iter().map(|e| e.key_name).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["2", "e", "h", "n", "r", "t", "u"]);
 
         Ok(())
     }
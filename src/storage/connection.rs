@@ -1,30 +1,38 @@
-use anyhow::{Context, Result};
-use directories::BaseDirs;
+use anyhow::Result;
+use chrono::{DateTime, Timelike};
+use chrono_tz::Tz;
 use log::{debug, info};
 
-use crate::models::events::KeyEvent;
-use crate::models::stats::{DashboardStats, KeyCount};
+use crate::config::expand_home;
+use crate::input::pause::PauseSource;
+use crate::models::events::{CharClass, InputEvent, KeyEvent, PauseInterval};
+use crate::models::range::{Bucket, TimeRange};
+use crate::models::stats::{
+    BucketCount, DailyShortcuts, DashboardStats, HourCount, HourlyActivity, KeyBucket, KeyCount,
+    KeyView, MouseStats, Session, ShortcutCount, UnknownKeyCount, WeekdayHours,
+};
+use crate::privacy::{redact_combo, DowngradeSummary, KeyClass, PrivacyLevel, COUNTED_KEY_NAME};
+use crate::storage::rollup::{Rollup, RollupBatch};
 use sqlx::sqlite::SqlitePoolOptions;
-use sqlx::SqlitePool;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
+
+/// Gap between keystrokes that ends a session unless configured otherwise
+pub const DEFAULT_IDLE_THRESHOLD: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Clone)]
 pub struct Database {
     pool: SqlitePool,
+    /// Longest gap between keystrokes within one session
+    idle_threshold_ms: i64,
 }
 impl Database {
     pub async fn new(path: PathBuf) -> Result<Self> {
         // Handling nice input strings like ~/.metmac etc
-        let expanded_path = if path.starts_with("~") {
-            let base_dirs = BaseDirs::new().context("Failed to get base directory")?;
-            let home_dir = base_dirs.home_dir();
-
-            let without_tilde = &path.strip_prefix("~").unwrap_or(&path);
-            home_dir.join(without_tilde)
-        } else {
-            path.clone()
-        };
+        let expanded_path = expand_home(&path)?;
 
         if let Some(parent) = expanded_path.parent() {
             if !parent.exists() {
@@ -41,7 +49,16 @@ impl Database {
             .connect(&database_url)
             .await?;
 
-        Ok(Self { pool })
+        Ok(Self {
+            pool,
+            idle_threshold_ms: DEFAULT_IDLE_THRESHOLD.as_millis() as i64,
+        })
+    }
+
+    /// Splits typing sessions at gaps longer than `threshold`
+    pub fn with_idle_threshold(mut self, threshold: Duration) -> Self {
+        self.idle_threshold_ms = threshold.as_millis() as i64;
+        self
     }
 
     pub async fn run_migrations(&self) -> Result<()> {
@@ -56,29 +73,395 @@ impl Database {
         Ok(())
     }
 
-    pub async fn insert_events(&self, events: &[KeyEvent]) -> Result<()> {
+    /// Closes all pooled connections, waiting for in-flight queries to finish
+    pub async fn close(&self) {
+        info!("Closing database connection");
+        self.pool.close().await;
+    }
+
+    pub async fn insert_events(&self, events: &[InputEvent]) -> Result<()> {
         debug!("Inserting {} events into the database", events.len());
 
         let mut tx = self.pool.begin().await?;
+        // Ids of the applications seen in this batch, most batches are a single one
+        let mut applications = HashMap::new();
+        let mut key_timestamps = Vec::new();
+        let mut rollups = RollupBatch::default();
 
         for event in events {
             debug!("Inserting event: {:?}", event);
-            sqlx::query!(
+            match event {
+                InputEvent::Key(event) => {
+                    if !event.is_repeat {
+                        key_timestamps.push(event.timestamp);
+                    }
+                    let application_id = match &event.application {
+                        Some(name) => match applications.get(name) {
+                            Some(id) => Some(*id),
+                            None => {
+                                let id = sqlx::query_scalar!(
+                                    r#"
+                                    INSERT INTO applications (name)
+                                    VALUES (?)
+                                    ON CONFLICT (name) DO UPDATE SET name = excluded.name
+                                    RETURNING id
+                                    "#,
+                                    name
+                                )
+                                .fetch_one(&mut *tx)
+                                .await?;
+                                applications.insert(name, id);
+                                Some(id)
+                            }
+                        },
+                        None => None,
+                    };
+
+                    sqlx::query!(
+                        r#"
+                        INSERT INTO events (
+                            event_timestamp, key_name, key_id, logical_key, typed_text,
+                            char_class, dwell_ms, is_repeat, application_id, window_title
+                        )
+                        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                        "#,
+                        event.timestamp,
+                        event.key_name,
+                        event.key_id,
+                        event.logical_key,
+                        event.typed_text,
+                        event.char_class,
+                        event.dwell_ms,
+                        event.is_repeat,
+                        application_id,
+                        event.window_title,
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                    rollups.add(event, application_id);
+                }
+                InputEvent::Shortcut(event) => {
+                    sqlx::query!(
+                        r#"
+                        INSERT INTO shortcuts (event_timestamp, combo)
+                        VALUES (?, ?)
+                        "#,
+                        event.timestamp,
+                        event.combo,
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                InputEvent::Mouse(activity) => {
+                    sqlx::query!(
+                        r#"
+                        INSERT INTO mouse_activity (
+                            bucket_start, left_clicks, right_clicks, middle_clicks,
+                            other_clicks, scroll_x, scroll_y, travel_px
+                        )
+                        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                        ON CONFLICT (bucket_start) DO UPDATE SET
+                            left_clicks = left_clicks + excluded.left_clicks,
+                            right_clicks = right_clicks + excluded.right_clicks,
+                            middle_clicks = middle_clicks + excluded.middle_clicks,
+                            other_clicks = other_clicks + excluded.other_clicks,
+                            scroll_x = scroll_x + excluded.scroll_x,
+                            scroll_y = scroll_y + excluded.scroll_y,
+                            travel_px = travel_px + excluded.travel_px
+                        "#,
+                        activity.bucket_start,
+                        activity.left_clicks,
+                        activity.right_clicks,
+                        activity.middle_clicks,
+                        activity.other_clicks,
+                        activity.scroll_x,
+                        activity.scroll_y,
+                        activity.travel_px,
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                InputEvent::Pause(pause) => {
+                    sqlx::query!(
+                        r#"
+                        INSERT INTO pauses (started_at, ended_at, paused_by)
+                        VALUES (?, ?, ?)
+                        "#,
+                        pause.started_at,
+                        pause.ended_at,
+                        pause.paused_by,
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+            }
+        }
+
+        self.extend_sessions(&mut tx, key_timestamps).await?;
+        Self::add_rollups(&mut tx, &rollups).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Adds the keystrokes of a batch to every rollup
+    async fn add_rollups(conn: &mut SqliteConnection, batch: &RollupBatch) -> Result<()> {
+        for rollup in Rollup::ALL {
+            let bucket_ms = rollup.bucket_ms();
+            for (key, counts) in batch.rows(rollup) {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO rollups (
+                        bucket_ms, bucket_start, key_name, physical_key, logical_key,
+                        application_id, count, first_ts, last_ts
+                    )
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                    ON CONFLICT (
+                        bucket_ms, bucket_start, key_name, physical_key, logical_key,
+                        application_id
+                    ) DO UPDATE SET
+                        count = count + excluded.count,
+                        first_ts = MIN(first_ts, excluded.first_ts),
+                        last_ts = MAX(last_ts, excluded.last_ts)
+                    "#,
+                    bucket_ms,
+                    key.bucket_start,
+                    key.key_name,
+                    key.physical_key,
+                    key.logical_key,
+                    key.application_id,
+                    counts.count,
+                    counts.first_ts,
+                    counts.last_ts,
+                )
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts the stored keystrokes into the rollups again, for when they were written
+    /// without them. Returns the number of rollup rows
+    pub async fn rebuild_rollups(&self) -> Result<u64> {
+        info!("Rebuilding rollups");
+
+        let mut tx = self.pool.begin().await?;
+        let rows = Self::refill_rollups(&mut tx, None).await?;
+        tx.commit().await?;
+
+        Ok(rows)
+    }
+
+    /// Replaces the rollup rows of keystrokes named `key_name`, or of every keystroke, with
+    /// counts of the stored events. Returns the number of rows written
+    async fn refill_rollups(conn: &mut SqliteConnection, key_name: Option<&str>) -> Result<u64> {
+        sqlx::query!(
+            "DELETE FROM rollups WHERE ?1 IS NULL OR key_name = ?1",
+            key_name
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        let minutes = sqlx::query!(
+            r#"
+            INSERT INTO rollups
+            SELECT
+                60000,
+                event_timestamp / 60000 * 60000,
+                key_name,
+                COALESCE(key_id, key_name),
+                COALESCE(logical_key, key_name),
+                COALESCE(application_id, 0),
+                SUM(is_repeat = 0),
+                MIN(event_timestamp),
+                MAX(event_timestamp)
+            FROM events
+            WHERE ?1 IS NULL OR key_name = ?1
+            GROUP BY 2, 3, 4, 5, 6
+            "#,
+            key_name
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+
+        // Each rollup adds up the one below it
+        let mut coarser = 0;
+        for (finer, rollup) in [(Rollup::Minute, Rollup::Hour), (Rollup::Hour, Rollup::Day)] {
+            let finer_ms = finer.bucket_ms();
+            let bucket_ms = rollup.bucket_ms();
+            coarser += sqlx::query!(
                 r#"
-                INSERT INTO events (event_timestamp, key_name)
-                VALUES (?, ?)
+                INSERT INTO rollups
+                SELECT
+                    ?1,
+                    bucket_start / ?1 * ?1,
+                    key_name,
+                    physical_key,
+                    logical_key,
+                    application_id,
+                    SUM(count),
+                    MIN(first_ts),
+                    MAX(last_ts)
+                FROM rollups
+                WHERE bucket_ms = ?2 AND (?3 IS NULL OR key_name = ?3)
+                GROUP BY 2, 3, 4, 5, 6
                 "#,
-                event.timestamp,
-                event.key_name,
+                bucket_ms,
+                finer_ms,
+                key_name
             )
-            .execute(&mut *tx)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+        }
+
+        Ok(minutes + coarser)
+    }
+
+    /// Folds keystroke times into the sessions table, repeats left out as everywhere else
+    /// keystrokes are counted. Batches can arrive out of order, so each run of keystrokes
+    /// is merged with every stored session it is close enough to
+    async fn extend_sessions(
+        &self,
+        conn: &mut SqliteConnection,
+        mut timestamps: Vec<i64>,
+    ) -> Result<()> {
+        timestamps.sort_unstable();
+        let threshold = self.idle_threshold_ms;
+
+        for run in timestamps.chunk_by(|a, b| b - a <= threshold) {
+            let mut session = Session {
+                started_at: run[0],
+                ended_at: run[run.len() - 1],
+                event_count: run.len() as i64,
+            };
+
+            let low = session.started_at - threshold;
+            let high = session.ended_at + threshold;
+            let nearby = sqlx::query!(
+                r#"
+                SELECT id as "id!", started_at, ended_at, event_count
+                FROM sessions
+                WHERE ended_at >= ? AND started_at <= ?
+                ORDER BY started_at
+                "#,
+                low,
+                high
+            )
+            .fetch_all(&mut *conn)
             .await?;
+
+            for row in &nearby {
+                session.started_at = session.started_at.min(row.started_at);
+                session.ended_at = session.ended_at.max(row.ended_at);
+                session.event_count += row.event_count;
+            }
+
+            match nearby.split_first() {
+                None => {
+                    sqlx::query!(
+                        r#"
+                        INSERT INTO sessions (started_at, ended_at, event_count)
+                        VALUES (?, ?, ?)
+                        "#,
+                        session.started_at,
+                        session.ended_at,
+                        session.event_count,
+                    )
+                    .execute(&mut *conn)
+                    .await?;
+                }
+                Some((kept, bridged)) => {
+                    sqlx::query!(
+                        r#"
+                        UPDATE sessions
+                        SET started_at = ?, ended_at = ?, event_count = ?
+                        WHERE id = ?
+                        "#,
+                        session.started_at,
+                        session.ended_at,
+                        session.event_count,
+                        kept.id,
+                    )
+                    .execute(&mut *conn)
+                    .await?;
+
+                    for row in bridged {
+                        sqlx::query!("DELETE FROM sessions WHERE id = ?", row.id)
+                            .execute(&mut *conn)
+                            .await?;
+                    }
+                }
+            }
         }
 
-        tx.commit().await?;
         Ok(())
     }
 
+    /// Splits all stored keystrokes into sessions again, for after the idle threshold
+    /// changed. Returns the number of sessions
+    pub async fn rebuild_sessions(&self) -> Result<u64> {
+        info!("Rebuilding sessions");
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM sessions")
+            .execute(&mut *tx)
+            .await?;
+
+        let sessions = sqlx::query!(
+            r#"
+            INSERT INTO sessions (started_at, ended_at, event_count)
+            SELECT MIN(event_timestamp), MAX(event_timestamp), COUNT(*)
+            FROM (
+                SELECT event_timestamp, SUM(is_start) OVER (ORDER BY event_timestamp) AS session
+                FROM (
+                    SELECT
+                        event_timestamp,
+                        COALESCE(
+                            event_timestamp - LAG(event_timestamp) OVER (ORDER BY event_timestamp)
+                                > ?,
+                            1
+                        ) AS is_start
+                    FROM events
+                    WHERE is_repeat = 0
+                )
+            )
+            GROUP BY session
+            "#,
+            self.idle_threshold_ms
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+        Ok(sessions)
+    }
+
+    /// Returns the typing sessions that ended in `range`, oldest first
+    pub async fn get_sessions(&self, range: TimeRange) -> Result<Vec<Session>> {
+        debug!("Getting sessions");
+
+        let sessions = sqlx::query_as!(
+            Session,
+            r#"
+            SELECT started_at, ended_at, event_count
+            FROM sessions
+            WHERE ended_at >= ? AND ended_at < ?
+            ORDER BY started_at
+            "#,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
     pub async fn get_events(&self) -> Result<Vec<KeyEvent>> {
         debug!("Getting all events from the database");
 
@@ -87,8 +470,17 @@ impl Database {
             r#"
             SELECT
                 event_timestamp as "timestamp",
-                key_name
+                key_name,
+                key_id,
+                logical_key,
+                typed_text,
+                char_class as "char_class: CharClass",
+                dwell_ms,
+                is_repeat,
+                applications.name as "application?",
+                window_title
             FROM events
+            LEFT JOIN applications ON applications.id = events.application_id
             "#
         )
         .fetch_all(&self.pool)
@@ -97,93 +489,636 @@ impl Database {
         Ok(events)
     }
 
-    pub async fn get_stats(&self) -> Result<DashboardStats> {
-        debug!("Getting dashboard stats");
+    /// Returns totals, the active period and the most used keys and applications in `range`
+    pub async fn get_stats(&self, range: TimeRange) -> Result<DashboardStats> {
+        debug!("Getting dashboard stats for {:?}", range);
 
+        let rollup = Rollup::for_range(range);
+        let bucket_ms = rollup.bucket_ms();
+        let (whole_from, whole_to) = rollup.whole_buckets(range);
         let mut tx = self.pool.begin().await?;
 
-        let total_today = sqlx::query!(
-            r#"
-            SELECT COUNT(*) as count
-            FROM today_events
-            "#
-        )
-        .fetch_one(&mut *tx)
-        .await?
-        .count;
-
-        let active_period = sqlx::query!(
+        let totals = sqlx::query!(
             r#"
             SELECT
-                MIN(event_timestamp) as first_event,
-                MAX(event_timestamp) as last_event
-            FROM today_events
-    "#
+                COALESCE(SUM(count), 0) as "count!: i64",
+                MIN(first_ts) as "first_event: i64",
+                MAX(last_ts) as "last_event: i64"
+            FROM (
+                SELECT * FROM rollups
+                WHERE bucket_ms = ?1 AND bucket_start >= ?2 AND bucket_start < ?3
+                UNION ALL
+                SELECT * FROM keystroke_rows
+                WHERE bucket_start >= ?4 AND bucket_start < ?5
+                    AND (bucket_start < ?2 OR bucket_start >= ?3)
+            )
+            "#,
+            bucket_ms,
+            whole_from,
+            whole_to,
+            range.from,
+            range.to
         )
         .fetch_one(&mut *tx)
         .await?;
 
-        let first_ts = active_period.first_event.unwrap_or(0);
-        let last_ts = active_period.last_event.unwrap_or(0);
-
         let top_keys = sqlx::query!(
             r#"
-        SELECT key_name, COUNT(*) as count
-        FROM today_events
-        GROUP BY key_name
-        ORDER BY count DESC
-        LIMIT 10
-        "#
+            SELECT key_name, SUM(count) as "count!: i64"
+            FROM (
+                SELECT * FROM rollups
+                WHERE bucket_ms = ?1 AND bucket_start >= ?2 AND bucket_start < ?3
+                UNION ALL
+                SELECT * FROM keystroke_rows
+                WHERE bucket_start >= ?4 AND bucket_start < ?5
+                    AND (bucket_start < ?2 OR bucket_start >= ?3)
+            )
+            GROUP BY key_name
+            HAVING SUM(count) > 0
+            ORDER BY 2 DESC
+            LIMIT 10
+            "#,
+            bucket_ms,
+            whole_from,
+            whole_to,
+            range.from,
+            range.to
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| (row.key_name, row.count))
+        .collect::<Vec<_>>();
+
+        let top_applications = sqlx::query!(
+            r#"
+            SELECT applications.name, SUM(count) as "count!: i64"
+            FROM (
+                SELECT * FROM rollups
+                WHERE bucket_ms = ?1 AND bucket_start >= ?2 AND bucket_start < ?3
+                UNION ALL
+                SELECT * FROM keystroke_rows
+                WHERE bucket_start >= ?4 AND bucket_start < ?5
+                    AND (bucket_start < ?2 OR bucket_start >= ?3)
+            ) AS counts
+            JOIN applications ON applications.id = counts.application_id
+            GROUP BY applications.id
+            HAVING SUM(count) > 0
+            ORDER BY 2 DESC
+            LIMIT 10
+            "#,
+            bucket_ms,
+            whole_from,
+            whole_to,
+            range.from,
+            range.to
         )
         .fetch_all(&mut *tx)
         .await?
         .into_iter()
-        .map(|row| (row.key_name, row.count as i64))
+        .map(|row| (row.name, row.count))
         .collect::<Vec<_>>();
 
         tx.commit().await?;
 
         Ok(DashboardStats {
-            total_today,
-            first_ts,
-            last_ts,
+            total_today: totals.count,
+            first_ts: totals.first_event.unwrap_or(0),
+            last_ts: totals.last_event.unwrap_or(0),
             top_keys,
+            top_applications,
+            buckets: None,
         })
     }
 
-    /// Returns the count of each key pressed since beginning of time
-    pub async fn get_keyboard_stats(&self) -> Result<Vec<KeyCount>> {
-        debug!("Getting keyboard stats");
+    /// Returns the number of keystrokes in each `bucket` of `range` that has any, with
+    /// buckets starting on the local time of `zone`
+    pub async fn get_keystroke_buckets(
+        &self,
+        range: TimeRange,
+        bucket: Bucket,
+        zone: Tz,
+    ) -> Result<Vec<BucketCount>> {
+        debug!("Getting keystrokes per {:?} for {:?}", bucket, range);
 
-        let mut tx = self.pool.begin().await?;
+        let rollup = Rollup::for_buckets(range, bucket, zone);
+        Ok(bucket.group(zone, self.keystroke_counts(range, rollup).await?))
+    }
+
+    /// Returns the keystrokes in every hour of `day`, a whole day in `zone`, idle hours
+    /// included
+    pub async fn get_hourly(&self, day: TimeRange, zone: Tz) -> Result<HourlyActivity> {
+        debug!("Getting hourly keystrokes for {:?}", day);
+
+        let counts = self
+            .get_keystroke_buckets(day, Bucket::Hour, zone)
+            .await?
+            .into_iter()
+            .map(|bucket| (bucket.start, bucket.count))
+            .collect::<HashMap<_, _>>();
+
+        let hours = Bucket::Hour
+            .starts(day, zone)
+            .into_iter()
+            .map(|start| HourCount {
+                start,
+                hour: DateTime::from_timestamp_millis(start)
+                    .unwrap_or_default()
+                    .with_timezone(&zone)
+                    .hour(),
+                count: counts.get(&start).copied().unwrap_or(0),
+            })
+            .collect();
 
-        let key_counts = sqlx::query!(
+        Ok(HourlyActivity {
+            date: local_date(day.from, zone),
+            hours,
+        })
+    }
+
+    /// Returns the keystrokes in `range` by weekday and hour of the day in `zone`
+    pub async fn get_weekday_hours(&self, range: TimeRange, zone: Tz) -> Result<WeekdayHours> {
+        debug!("Getting keystrokes by weekday and hour for {:?}", range);
+
+        let rollup = Rollup::for_buckets(range, Bucket::Hour, zone);
+        Ok(WeekdayHours::from_counts(
+            zone,
+            self.keystroke_counts(range, rollup).await?,
+        ))
+    }
+
+    /// Counts the keystrokes in `range` per bucket of `rollup`, in time order
+    async fn keystroke_counts(&self, range: TimeRange, rollup: Rollup) -> Result<Vec<(i64, i64)>> {
+        let bucket_ms = rollup.bucket_ms();
+        let (whole_from, whole_to) = rollup.whole_buckets(range);
+        let counts = sqlx::query!(
+            r#"
+            SELECT bucket_start, SUM(count) as "count!: i64"
+            FROM (
+                SELECT * FROM rollups
+                WHERE bucket_ms = ?1 AND bucket_start >= ?2 AND bucket_start < ?3
+                UNION ALL
+                SELECT * FROM keystroke_rows
+                WHERE bucket_start >= ?4 AND bucket_start < ?5
+                    AND (bucket_start < ?2 OR bucket_start >= ?3)
+            )
+            GROUP BY bucket_start
+            HAVING SUM(count) > 0
+            ORDER BY bucket_start
+            "#,
+            bucket_ms,
+            whole_from,
+            whole_to,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(counts
+            .into_iter()
+            .map(|row| (row.bucket_start, row.count))
+            .collect())
+    }
+
+    /// Returns the count of each key pressed in `range`, grouped as `view` says
+    pub async fn get_keyboard_stats(
+        &self,
+        view: KeyView,
+        range: TimeRange,
+    ) -> Result<Vec<KeyCount>> {
+        debug!("Getting keyboard stats by {:?} for {:?}", view, range);
+
+        let rollup = Rollup::for_range(range);
+        let bucket_ms = rollup.bucket_ms();
+        let (whole_from, whole_to) = rollup.whole_buckets(range);
+        let key_counts = match view {
+            KeyView::Name => {
+                sqlx::query_as!(
+                    KeyCount,
+                    r#"
+                    SELECT key_name, SUM(count) as "count!: i64"
+                    FROM (
+                        SELECT * FROM rollups
+                        WHERE bucket_ms = ?1 AND bucket_start >= ?2 AND bucket_start < ?3
+                        UNION ALL
+                        SELECT * FROM keystroke_rows
+                        WHERE bucket_start >= ?4 AND bucket_start < ?5
+                            AND (bucket_start < ?2 OR bucket_start >= ?3)
+                    )
+                    GROUP BY key_name
+                    HAVING SUM(count) > 0
+                    ORDER BY 2 DESC
+                    "#,
+                    bucket_ms,
+                    whole_from,
+                    whole_to,
+                    range.from,
+                    range.to
+                )
+                .fetch_all(&self.pool)
+                .await?
+            }
+            KeyView::Physical => {
+                sqlx::query_as!(
+                    KeyCount,
+                    r#"
+                    SELECT physical_key as "key_name!: String", SUM(count) as "count!: i64"
+                    FROM (
+                        SELECT * FROM rollups
+                        WHERE bucket_ms = ?1 AND bucket_start >= ?2 AND bucket_start < ?3
+                        UNION ALL
+                        SELECT * FROM keystroke_rows
+                        WHERE bucket_start >= ?4 AND bucket_start < ?5
+                            AND (bucket_start < ?2 OR bucket_start >= ?3)
+                    )
+                    GROUP BY physical_key
+                    HAVING SUM(count) > 0
+                    ORDER BY 2 DESC
+                    "#,
+                    bucket_ms,
+                    whole_from,
+                    whole_to,
+                    range.from,
+                    range.to
+                )
+                .fetch_all(&self.pool)
+                .await?
+            }
+            KeyView::Logical => {
+                sqlx::query_as!(
+                    KeyCount,
+                    r#"
+                    SELECT logical_key as "key_name!: String", SUM(count) as "count!: i64"
+                    FROM (
+                        SELECT * FROM rollups
+                        WHERE bucket_ms = ?1 AND bucket_start >= ?2 AND bucket_start < ?3
+                        UNION ALL
+                        SELECT * FROM keystroke_rows
+                        WHERE bucket_start >= ?4 AND bucket_start < ?5
+                            AND (bucket_start < ?2 OR bucket_start >= ?3)
+                    )
+                    GROUP BY logical_key
+                    HAVING SUM(count) > 0
+                    ORDER BY 2 DESC
+                    "#,
+                    bucket_ms,
+                    whole_from,
+                    whole_to,
+                    range.from,
+                    range.to
+                )
+                .fetch_all(&self.pool)
+                .await?
+            }
+        };
+
+        Ok(key_counts)
+    }
+
+    /// Returns the count of each key pressed in every `bucket` of `range` that has any
+    pub async fn get_keyboard_buckets(
+        &self,
+        view: KeyView,
+        range: TimeRange,
+        bucket: Bucket,
+        zone: Tz,
+    ) -> Result<Vec<KeyBucket>> {
+        debug!(
+            "Getting keyboard stats by {:?} per {:?} for {:?}",
+            view, bucket, range
+        );
+
+        let rollup = Rollup::for_buckets(range, bucket, zone);
+        let bucket_ms = rollup.bucket_ms();
+        let (whole_from, whole_to) = rollup.whole_buckets(range);
+        let view = match view {
+            KeyView::Name => "name",
+            KeyView::Physical => "physical",
+            KeyView::Logical => "logical",
+        };
+        // Rollup rows are summed per key here, `group_keys` then puts them in buckets that
+        // follow the calendar in `zone`
+        let counts = sqlx::query!(
             r#"
-            SELECT key_name, COUNT(*) as count
+            SELECT bucket_start,
+                CASE ?6
+                    WHEN 'physical' THEN physical_key
+                    WHEN 'logical' THEN logical_key
+                    ELSE key_name
+                END as "key_name!: String",
+                SUM(count) as "count!: i64"
+            FROM (
+                SELECT * FROM rollups
+                WHERE bucket_ms = ?1 AND bucket_start >= ?2 AND bucket_start < ?3
+                UNION ALL
+                SELECT * FROM keystroke_rows
+                WHERE bucket_start >= ?4 AND bucket_start < ?5
+                    AND (bucket_start < ?2 OR bucket_start >= ?3)
+            )
+            GROUP BY 1, 2
+            HAVING SUM(count) > 0
+            "#,
+            bucket_ms,
+            whole_from,
+            whole_to,
+            range.from,
+            range.to,
+            view
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.bucket_start, row.key_name, row.count));
+
+        Ok(bucket.group_keys(zone, counts))
+    }
+
+    /// Returns the most used shortcuts for each day of `range` in `zone`, newest first
+    pub async fn get_daily_shortcuts(
+        &self,
+        range: TimeRange,
+        limit: usize,
+        zone: Tz,
+    ) -> Result<Vec<DailyShortcuts>> {
+        debug!("Getting shortcuts per day for {:?}", range);
+
+        let minutes = sqlx::query!(
+            r#"
+            SELECT
+                event_timestamp / 60000 * 60000 as "minute!: i64",
+                combo,
+                COUNT(*) as "count!: i64"
+            FROM shortcuts
+            WHERE event_timestamp >= ? AND event_timestamp < ?
+            GROUP BY 1, combo
+            "#,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.minute, row.combo, row.count));
+
+        let daily = Bucket::Day
+            .group_keys(zone, minutes)
+            .into_iter()
+            .rev()
+            .map(|day| DailyShortcuts {
+                day: local_date(day.start, zone),
+                shortcuts: day
+                    .keys
+                    .into_iter()
+                    .take(limit)
+                    .map(|key| ShortcutCount {
+                        combo: key.key_name,
+                        count: key.count,
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Ok(daily)
+    }
+
+    /// Returns the mouse usage in `range`
+    pub async fn get_mouse_stats(&self, range: TimeRange) -> Result<MouseStats> {
+        debug!("Getting mouse stats");
+
+        let row = sqlx::query!(
+            r#"
+            SELECT
+                COALESCE(SUM(left_clicks), 0) as "left_clicks!: i64",
+                COALESCE(SUM(right_clicks), 0) as "right_clicks!: i64",
+                COALESCE(SUM(middle_clicks), 0) as "middle_clicks!: i64",
+                COALESCE(SUM(other_clicks), 0) as "other_clicks!: i64",
+                COALESCE(SUM(scroll_x + scroll_y), 0) as "scroll_distance!: i64",
+                COALESCE(SUM(travel_px), 0.0) as "travel_px!: f64"
+            FROM mouse_activity
+            WHERE bucket_start >= ? AND bucket_start < ?
+            "#,
+            range.from,
+            range.to
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(MouseStats {
+            left_clicks: row.left_clicks,
+            right_clicks: row.right_clicks,
+            middle_clicks: row.middle_clicks,
+            other_clicks: row.other_clicks,
+            scroll_distance: row.scroll_distance,
+            travel_px: row.travel_px,
+        })
+    }
+
+    /// Returns the pauses in capture that ended in `range`, oldest first
+    pub async fn get_pauses(&self, range: TimeRange) -> Result<Vec<PauseInterval>> {
+        debug!("Getting pauses");
+
+        let pauses = sqlx::query_as!(
+            PauseInterval,
+            r#"
+            SELECT started_at, ended_at, paused_by as "paused_by: PauseSource"
+            FROM pauses
+            WHERE ended_at >= ? AND ended_at < ?
+            ORDER BY started_at
+            "#,
+            range.from,
+            range.to
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(pauses)
+    }
+
+    /// Returns the key codes stored as `unknown_<code>`, most frequent first
+    pub async fn get_unknown_keys(&self) -> Result<Vec<UnknownKeyCount>> {
+        debug!("Getting unknown key codes");
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT key_name as "key_name!", COUNT(*) as count, MAX(event_timestamp) as "last_seen!: i64"
             FROM events
+            WHERE key_name LIKE 'unknown\_%' ESCAPE '\'
             GROUP BY key_name
             ORDER BY count DESC
             "#
         )
-        .fetch_all(&mut *tx)
-        .await?
-        .into_iter()
-        .map(|row| KeyCount {
-            key_name: row.key_name,
-            count: row.count as i64,
-        })
-        .collect::<Vec<_>>();
+        .fetch_all(&self.pool)
+        .await?;
+
+        let unknown_keys = rows
+            .into_iter()
+            .filter_map(|row| {
+                let code = row.key_name.strip_prefix("unknown_")?.parse().ok()?;
+                Some(UnknownKeyCount {
+                    code,
+                    count: row.count,
+                    last_seen: row.last_seen,
+                })
+            })
+            .collect();
+
+        Ok(unknown_keys)
+    }
+
+    /// Renames every stored keystroke named `from`, returning how many were changed
+    pub async fn rename_key(&self, from: &str, to: &str) -> Result<u64> {
+        debug!("Renaming key {:?} to {:?}", from, to);
 
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query!(
+            "UPDATE events SET key_name = ? WHERE key_name = ?",
+            to,
+            from
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        Self::refill_rollups(&mut tx, Some(from)).await?;
+        Self::refill_rollups(&mut tx, Some(to)).await?;
         tx.commit().await?;
 
-        Ok(key_counts)
+        Ok(result.rows_affected())
+    }
+
+    /// Rewrites stored keystrokes and shortcuts to what `level` keeps.
+    ///
+    /// The database is vacuumed afterwards so the old rows do not linger in free pages.
+    /// With `dry_run` the changes are counted and rolled back.
+    pub async fn downgrade_privacy(
+        &self,
+        level: PrivacyLevel,
+        dry_run: bool,
+    ) -> Result<DowngradeSummary> {
+        info!("Downgrading stored history to {:?}", level);
+
+        let mut tx = self.pool.begin().await?;
+        let mut summary = DowngradeSummary::default();
+
+        match level {
+            PrivacyLevel::Full => {}
+            PrivacyLevel::KeyClass => {
+                let keys = sqlx::query!(
+                    r#"
+                    SELECT DISTINCT key_id, key_name
+                    FROM events
+                    WHERE key_name != ?
+                    "#,
+                    COUNTED_KEY_NAME
+                )
+                .fetch_all(&mut *tx)
+                .await?;
+
+                for key in keys {
+                    let class = KeyClass::classify(key.key_id.as_deref(), &key.key_name);
+                    // Recorded at this level already
+                    if key.key_id.is_none() && key.key_name == class.as_str() {
+                        continue;
+                    }
+
+                    let class = class.as_str();
+                    let result = sqlx::query!(
+                        r#"
+                        UPDATE events
+                        SET key_name = ?, key_id = NULL, logical_key = NULL,
+                            typed_text = NULL, char_class = NULL
+                        WHERE key_name = ? AND key_id IS ?
+                        "#,
+                        class,
+                        key.key_name,
+                        key.key_id,
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                    summary.keys += result.rows_affected();
+                }
+
+                let combos = sqlx::query_scalar!("SELECT DISTINCT combo FROM shortcuts")
+                    .fetch_all(&mut *tx)
+                    .await?;
+
+                for combo in combos {
+                    let redacted = redact_combo(&combo);
+                    if redacted == combo {
+                        continue;
+                    }
+
+                    let result = sqlx::query!(
+                        "UPDATE shortcuts SET combo = ? WHERE combo = ?",
+                        redacted,
+                        combo
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                    summary.shortcuts += result.rows_affected();
+                }
+            }
+            PrivacyLevel::Counts => {
+                summary.keys = sqlx::query!(
+                    r#"
+                    UPDATE events
+                    SET key_name = ?1, key_id = NULL, logical_key = NULL,
+                        typed_text = NULL, char_class = NULL, dwell_ms = NULL
+                    WHERE key_name != ?1 OR key_id IS NOT NULL OR logical_key IS NOT NULL
+                        OR typed_text IS NOT NULL OR char_class IS NOT NULL
+                        OR dwell_ms IS NOT NULL
+                    "#,
+                    COUNTED_KEY_NAME
+                )
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+
+                summary.shortcuts = sqlx::query!("DELETE FROM shortcuts")
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+            }
+        }
+
+        if level != PrivacyLevel::Full {
+            summary.window_titles = sqlx::query!(
+                "UPDATE events SET window_title = NULL WHERE window_title IS NOT NULL"
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+
+        if dry_run {
+            tx.rollback().await?;
+            return Ok(summary);
+        }
+
+        // Rollups keep key names per minute, which must go as well
+        Self::refill_rollups(&mut tx, None).await?;
+        tx.commit().await?;
+        sqlx::query!("VACUUM").execute(&self.pool).await?;
+
+        Ok(summary)
     }
 }
 
+/// The `YYYY-MM-DD` date of `timestamp` in `zone`
+fn local_date(timestamp: i64, zone: Tz) -> String {
+    DateTime::from_timestamp_millis(timestamp)
+        .unwrap_or_default()
+        .with_timezone(&zone)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::Utc;
+    use crate::models::events::{MouseActivity, ShortcutEvent};
+    use crate::models::stats::ActivitySummary;
+    use chrono::{NaiveDate, Utc};
     use tempfile::NamedTempFile;
 
     #[tokio::test]
@@ -206,21 +1141,652 @@ mod tests {
         let db = Database::new(tmp_db_path.clone()).await?;
         db.run_migrations().await?;
 
-        let events = vec![
-            KeyEvent {
-                timestamp: Utc::now().timestamp(),
-                key_name: "a".to_string(),
-            },
-            KeyEvent {
-                timestamp: Utc::now().timestamp(),
-                key_name: "b".to_string(),
-            },
+        let events = [
+            KeyEvent::new("a".to_string(), Utc::now().timestamp()),
+            KeyEvent::new("b".to_string(), Utc::now().timestamp()),
         ];
 
-        db.insert_events(&events).await?;
+        db.insert_events(&events.map(InputEvent::from)).await?;
         let db_events = db.get_events().await?;
         assert_eq!(db_events.len(), 2);
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_database_typed_text_round_trip() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let db = Database::new(PathBuf::from(tmp_dir.path())).await?;
+        db.run_migrations().await?;
+
+        let mut event = KeyEvent::new("e".to_string(), Utc::now().timestamp());
+        event.typed_text = Some("é".to_string());
+        event.char_class = Some(CharClass::Letter);
+        db.insert_events(&[event.into()]).await?;
+
+        let db_events = db.get_events().await?;
+        assert_eq!(db_events[0].typed_text.as_deref(), Some("é"));
+        assert_eq!(db_events[0].char_class, Some(CharClass::Letter));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_keyboard_stats_skip_repeats() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_dir.path());
+        let db = Database::new(tmp_db_path.clone()).await?;
+        db.run_migrations().await?;
+
+        let mut held = KeyEvent::new("a".to_string(), Utc::now().timestamp_millis());
+        held.dwell_ms = Some(650);
+        let mut repeat = KeyEvent::new("a".to_string(), Utc::now().timestamp_millis());
+        repeat.is_repeat = true;
+
+        db.insert_events(&[held.clone().into(), repeat.clone().into(), repeat.into()])
+            .await?;
+
+        let stats = db
+            .get_keyboard_stats(KeyView::Name, TimeRange::all())
+            .await?;
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].count, 1);
+
+        // Repeats are still stored
+        let db_events = db.get_events().await?;
+        assert_eq!(db_events.len(), 3);
+        assert_eq!(db_events[0], held);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_keyboard_stats_views() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_dir.path());
+        let db = Database::new(tmp_db_path.clone()).await?;
+        db.run_migrations().await?;
+
+        // The same physical key typed on QWERTY and on Dvorak, plus a legacy row
+        let key = |logical_key: Option<&str>| {
+            let mut event = KeyEvent::new("s".to_string(), 0);
+            event.key_id = Some("KeyS".to_string());
+            event.logical_key = logical_key.map(str::to_string);
+            InputEvent::from(event)
+        };
+        let legacy = KeyEvent::new("space".to_string(), 0).into();
+        db.insert_events(&[key(Some("s")), key(Some("o")), key(Some("o")), legacy])
+            .await?;
+
+        let counts = |stats: Vec<KeyCount>| {
+            let mut counts = stats
+                .into_iter()
+                .map(|count| (count.key_name, count.count))
+                .collect::<Vec<_>>();
+            counts.sort();
+            counts
+        };
+
+        assert_eq!(
+            counts(
+                db.get_keyboard_stats(KeyView::Physical, TimeRange::all())
+                    .await?
+            ),
+            vec![("KeyS".to_string(), 3), ("space".to_string(), 1)]
+        );
+        assert_eq!(
+            counts(
+                db.get_keyboard_stats(KeyView::Logical, TimeRange::all())
+                    .await?
+            ),
+            vec![
+                ("o".to_string(), 2),
+                ("s".to_string(), 1),
+                ("space".to_string(), 1)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_daily_shortcuts() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_dir.path());
+        let db = Database::new(tmp_db_path.clone()).await?;
+        db.run_migrations().await?;
+
+        let now = Utc::now().timestamp_millis();
+        let yesterday = now - 24 * 60 * 60 * 1000;
+        let last_month = now - 30 * 24 * 60 * 60 * 1000;
+
+        let events = [
+            ("meta+c", now),
+            ("meta+v", now),
+            ("meta+c", now),
+            ("ctrl+shift+t", yesterday),
+            ("meta+c", last_month),
+        ]
+        .map(|(combo, ts)| ShortcutEvent::new(combo.to_string(), ts).into());
+        db.insert_events(&events).await?;
+
+        let daily = db
+            .get_daily_shortcuts(TimeRange::last_days(7, Tz::UTC)?, 1, Tz::UTC)
+            .await?;
+        assert_eq!(daily.len(), 2);
+
+        assert_eq!(daily[0].shortcuts.len(), 1);
+        assert_eq!(daily[0].shortcuts[0].combo, "meta+c");
+        assert_eq!(daily[0].shortcuts[0].count, 2);
+        assert_eq!(daily[1].shortcuts[0].combo, "ctrl+shift+t");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_mouse_activity_merges_buckets() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_dir.path());
+        let db = Database::new(tmp_db_path.clone()).await?;
+        db.run_migrations().await?;
+
+        let now = Utc::now().timestamp_millis();
+        let bucket_start = now - now.rem_euclid(60_000);
+
+        let mut first = MouseActivity::new(bucket_start);
+        first.left_clicks = 2;
+        first.travel_px = 100.5;
+        let mut second = MouseActivity::new(bucket_start);
+        second.left_clicks = 1;
+        second.right_clicks = 1;
+        second.scroll_y = 7;
+        second.travel_px = 50.0;
+
+        db.insert_events(&[first.into(), second.into()]).await?;
+
+        let stats = db.get_mouse_stats(TimeRange::today(Tz::UTC)).await?;
+        assert_eq!(stats.left_clicks, 3);
+        assert_eq!(stats.right_clicks, 1);
+        assert_eq!(stats.scroll_distance, 7);
+        assert_eq!(stats.travel_px, 150.5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_unknown_keys_and_rename() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let tmp_db_path = PathBuf::from(tmp_dir.path());
+        let db = Database::new(tmp_db_path.clone()).await?;
+        db.run_migrations().await?;
+
+        let events = [
+            ("unknown_179", 1),
+            ("unknown_179", 5),
+            ("unknown_42", 3),
+            ("a", 4),
+        ]
+        .into_iter()
+        .map(|(name, ts)| KeyEvent::new(name.to_string(), ts).into())
+        .collect::<Vec<InputEvent>>();
+        db.insert_events(&events).await?;
+
+        assert_eq!(
+            db.get_unknown_keys().await?,
+            vec![
+                UnknownKeyCount {
+                    code: 179,
+                    count: 2,
+                    last_seen: 5
+                },
+                UnknownKeyCount {
+                    code: 42,
+                    count: 1,
+                    last_seen: 3
+                },
+            ]
+        );
+
+        assert_eq!(db.rename_key("unknown_179", "mic_mute").await?, 2);
+        let unknown = db.get_unknown_keys().await?;
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].code, 42);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_applications() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let db = Database::new(PathBuf::from(tmp_dir.path())).await?;
+        db.run_migrations().await?;
+
+        let now = Utc::now().timestamp_millis();
+        let key = |application: Option<&str>| {
+            let mut event = KeyEvent::new("a".to_string(), now);
+            event.application = application.map(str::to_string);
+            InputEvent::from(event)
+        };
+        db.insert_events(&[key(Some("code")), key(Some("firefox")), key(Some("code"))])
+            .await?;
+        db.insert_events(&[key(Some("code")), key(None)]).await?;
+
+        let stats = db.get_stats(TimeRange::today(Tz::UTC)).await?;
+        assert_eq!(
+            stats.top_applications,
+            vec![("code".to_string(), 3), ("firefox".to_string(), 1)]
+        );
+
+        // Each application is stored once
+        let applications = sqlx::query_scalar!("SELECT COUNT(*) FROM applications")
+            .fetch_one(&db.pool)
+            .await?;
+        assert_eq!(applications, 2);
+
+        let events = db.get_events().await?;
+        assert_eq!(events[1].application.as_deref(), Some("firefox"));
+        assert_eq!(events[4].application, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_time_ranges() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let db = Database::new(PathBuf::from(tmp_dir.path())).await?;
+        db.run_migrations().await?;
+
+        const HOUR: i64 = 3_600_000;
+        let events = [(0, "a"), (HOUR / 2, "b"), (HOUR + 1, "a"), (3 * HOUR, "a")]
+            .into_iter()
+            .map(|(ts, name)| KeyEvent::new(name.to_string(), ts).into())
+            .collect::<Vec<InputEvent>>();
+        db.insert_events(&events).await?;
+
+        let range = TimeRange::new(0, 2 * HOUR)?;
+        let stats = db.get_stats(range).await?;
+        assert_eq!(stats.total_today, 3);
+        assert_eq!((stats.first_ts, stats.last_ts), (0, HOUR + 1));
+        assert_eq!(stats.top_keys[0], ("a".to_string(), 2));
+
+        assert_eq!(
+            db.get_keystroke_buckets(TimeRange::new(0, 4 * HOUR)?, Bucket::Hour, Tz::UTC)
+                .await?,
+            vec![
+                BucketCount { start: 0, count: 2 },
+                BucketCount {
+                    start: HOUR,
+                    count: 1
+                },
+                BucketCount {
+                    start: 3 * HOUR,
+                    count: 1
+                },
+            ]
+        );
+
+        // Days start at midnight in the zone asked for
+        assert_eq!(
+            db.get_keystroke_buckets(TimeRange::new(0, 4 * HOUR)?, Bucket::Day, Tz::Asia__Tokyo)
+                .await?,
+            vec![BucketCount {
+                start: -9 * HOUR,
+                count: 4
+            }]
+        );
+
+        let keys = db
+            .get_keyboard_buckets(KeyView::Name, range, Bucket::Day, Tz::UTC)
+            .await?;
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            keys[0]
+                .keys
+                .iter()
+                .map(|key| (key.key_name.as_str(), key.count))
+                .collect::<Vec<_>>(),
+            vec![("a", 2), ("b", 1)]
+        );
+
+        // Summed per hour, keystrokes before a bound within the hour in the first one
+        let keys = db
+            .get_keyboard_buckets(
+                KeyView::Physical,
+                TimeRange::new(HOUR / 4, 4 * HOUR)?,
+                Bucket::Hour,
+                Tz::UTC,
+            )
+            .await?
+            .into_iter()
+            .map(|bucket| {
+                let counts = bucket
+                    .keys
+                    .iter()
+                    .map(|key| (key.key_name.clone(), key.count))
+                    .collect::<Vec<_>>();
+                (bucket.start, counts)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                (0, vec![("b".to_string(), 1)]),
+                (HOUR, vec![("a".to_string(), 1)]),
+                (3 * HOUR, vec![("a".to_string(), 1)]),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_hourly() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let db = Database::new(PathBuf::from(tmp_dir.path())).await?;
+        db.run_migrations().await?;
+
+        // The epoch was a Thursday
+        const HOUR: i64 = 3_600_000;
+        let events =
+            [0, HOUR / 2, HOUR + 1, 3 * HOUR].map(|ts| KeyEvent::new("a".to_string(), ts).into());
+        db.insert_events(&events).await?;
+
+        let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+        let hourly = db
+            .get_hourly(TimeRange::day(epoch, Tz::UTC)?, Tz::UTC)
+            .await?;
+        assert_eq!(hourly.date, "1970-01-01");
+        assert_eq!(hourly.hours.len(), 24);
+        assert_eq!(
+            hourly.hours[..4]
+                .iter()
+                .map(|hour| (hour.hour, hour.count))
+                .collect::<Vec<_>>(),
+            vec![(0, 2), (1, 1), (2, 0), (3, 1)]
+        );
+
+        // Nine hours ahead, the same keystrokes were typed in the morning
+        let hourly = db
+            .get_hourly(TimeRange::day(epoch, Tz::Asia__Tokyo)?, Tz::Asia__Tokyo)
+            .await?;
+        assert_eq!(hourly.hours[9].start, 0);
+        assert_eq!(hourly.hours[9].count, 2);
+        assert_eq!(hourly.hours[12].count, 1);
+
+        let matrix = db.get_weekday_hours(TimeRange::all(), Tz::UTC).await?;
+        assert_eq!(matrix.counts[3][..4], [2, 1, 0, 1]);
+        assert_eq!(matrix.counts.iter().flatten().sum::<i64>(), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_rollups() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let db = Database::new(PathBuf::from(tmp_dir.path())).await?;
+        db.run_migrations().await?;
+
+        const HOUR: i64 = 3_600_000;
+        let key = |name: &str, ts| KeyEvent::new(name.to_string(), ts).into();
+        let mut repeat = KeyEvent::new("a".to_string(), HOUR + 30_000);
+        repeat.is_repeat = true;
+
+        // The same minute, hour and day are written to by both batches
+        db.insert_events(&[key("a", HOUR), key("b", HOUR + 10), key("a", 26 * HOUR)])
+            .await?;
+        db.insert_events(&[key("a", HOUR + 20_000), repeat.into(), key("c", 2 * HOUR)])
+            .await?;
+
+        let rollups = || async {
+            sqlx::query!(
+                r#"
+                SELECT bucket_ms, bucket_start, key_name, count, first_ts, last_ts
+                FROM rollups
+                ORDER BY bucket_ms, bucket_start, key_name
+                "#
+            )
+            .fetch_all(&db.pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| {
+                        let key = (row.bucket_ms, row.bucket_start, row.key_name);
+                        (key, row.count, row.first_ts, row.last_ts)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let incremental = rollups().await?;
+        assert_eq!(db.rebuild_rollups().await?, incremental.len() as u64);
+        assert_eq!(rollups().await?, incremental);
+
+        let stats = db.get_stats(TimeRange::new(HOUR, 2 * HOUR)?).await?;
+        assert_eq!(stats.total_today, 3);
+        // The repeat is not counted but is the last thing typed
+        assert_eq!((stats.first_ts, stats.last_ts), (HOUR, HOUR + 30_000));
+
+        let counts = |stats: Vec<KeyCount>| {
+            stats
+                .into_iter()
+                .map(|key| (key.key_name, key.count))
+                .collect::<HashMap<_, _>>()
+        };
+        let all = counts(
+            db.get_keyboard_stats(KeyView::Name, TimeRange::all())
+                .await?,
+        );
+        assert_eq!(all["a"], 3);
+        assert_eq!(all.len(), 3);
+
+        // Bounds within a minute count exactly what falls between them
+        let within = TimeRange::new(HOUR + 5, HOUR + 20_000)?;
+        let stats = db.get_stats(within).await?;
+        assert_eq!(stats.total_today, 1);
+        assert_eq!((stats.first_ts, stats.last_ts), (HOUR + 10, HOUR + 10));
+        assert_eq!(
+            counts(db.get_keyboard_stats(KeyView::Name, within).await?),
+            HashMap::from([("b".to_string(), 1)])
+        );
+
+        let across = TimeRange::new(HOUR + 15_000, 2 * HOUR + 1)?;
+        assert_eq!(db.get_stats(across).await?.total_today, 2);
+        let minutes = db
+            .get_keystroke_buckets(across, Bucket::Minute, Tz::UTC)
+            .await?
+            .into_iter()
+            .map(|bucket| (bucket.start, bucket.count))
+            .collect::<Vec<_>>();
+        assert_eq!(minutes, vec![(HOUR, 1), (2 * HOUR, 1)]);
+
+        // Renaming merges into the rows already counted under the new name
+        db.rename_key("b", "a").await?;
+        let all = counts(
+            db.get_keyboard_stats(KeyView::Name, TimeRange::all())
+                .await?,
+        );
+        assert_eq!(all["a"], 4);
+        assert!(!all.contains_key("b"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_sessions() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let db = Database::new(PathBuf::from(tmp_dir.path()))
+            .await?
+            .with_idle_threshold(Duration::from_secs(60));
+        db.run_migrations().await?;
+
+        // An hour into today (UTC), so the sessions count as today's
+        let now = Utc::now().timestamp_millis();
+        let base = now - now % 86_400_000 + 3_600_000;
+        let keys = |secs: &[i64]| {
+            secs.iter()
+                .map(|secs| KeyEvent::new("a".to_string(), base + secs * 1000).into())
+                .collect::<Vec<InputEvent>>()
+        };
+
+        db.insert_events(&keys(&[0, 10])).await?;
+        db.insert_events(&keys(&[180, 200])).await?;
+        assert_eq!(db.get_sessions(TimeRange::today(Tz::UTC)).await?.len(), 2);
+
+        // Arriving late, these close the gap between the two sessions. Repeats count for
+        // neither the time nor the number of keystrokes
+        let mut late = keys(&[130, 70]);
+        let mut repeat = KeyEvent::new("a".to_string(), base + 205_000);
+        repeat.is_repeat = true;
+        late.push(repeat.into());
+        db.insert_events(&late).await?;
+        let sessions = db.get_sessions(TimeRange::today(Tz::UTC)).await?;
+        assert_eq!(
+            sessions,
+            vec![Session {
+                started_at: base,
+                ended_at: base + 200_000,
+                event_count: 6,
+            }]
+        );
+        assert_eq!(
+            ActivitySummary::from_sessions(&sessions),
+            ActivitySummary {
+                active_ms: 200_000,
+                session_count: 1,
+                longest_session: Some(sessions[0]),
+            }
+        );
+
+        // A shorter threshold splits at every gap above 30 seconds
+        let db = db.with_idle_threshold(Duration::from_secs(30));
+        assert_eq!(db.rebuild_sessions().await?, 4);
+        let durations = db
+            .get_sessions(TimeRange::today(Tz::UTC))
+            .await?
+            .iter()
+            .map(Session::duration_ms)
+            .collect::<Vec<_>>();
+        assert_eq!(durations, vec![10_000, 0, 0, 20_000]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_downgrade_privacy() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let db = Database::new(PathBuf::from(tmp_dir.path())).await?;
+        db.run_migrations().await?;
+
+        let key = |name: &str, key_id: Option<&str>| {
+            let mut event = KeyEvent::new(name.to_string(), 1);
+            event.key_id = key_id.map(str::to_string);
+            event.typed_text = Some(name.to_string());
+            event.dwell_ms = Some(90);
+            event.window_title = Some("Notes".to_string());
+            InputEvent::from(event)
+        };
+        db.insert_events(&[
+            key("p", Some("KeyP")),
+            key("w", Some("KeyW")),
+            key("1", Some("Digit1")),
+            key("page_up", None),
+            ShortcutEvent::new("ctrl+v".to_string(), 1).into(),
+        ])
+        .await?;
+
+        let key_names = || async {
+            let mut names = db
+                .get_keyboard_stats(KeyView::Name, TimeRange::all())
+                .await?
+                .into_iter()
+                .map(|key| (key.key_name, key.count))
+                .collect::<Vec<_>>();
+            names.sort();
+            anyhow::Ok(names)
+        };
+        let combos = || sqlx::query_scalar!("SELECT combo FROM shortcuts").fetch_all(&db.pool);
+
+        // A dry run reports the changes without making them
+        let summary = db.downgrade_privacy(PrivacyLevel::KeyClass, true).await?;
+        assert_eq!(
+            summary,
+            DowngradeSummary {
+                keys: 4,
+                shortcuts: 1,
+                window_titles: 4,
+            }
+        );
+        assert_eq!(key_names().await?.len(), 4);
+
+        db.downgrade_privacy(PrivacyLevel::KeyClass, false).await?;
+        assert_eq!(
+            key_names().await?,
+            vec![
+                ("digit".to_string(), 1),
+                ("letter".to_string(), 2),
+                ("navigation".to_string(), 1),
+            ]
+        );
+        assert_eq!(combos().await?, vec!["ctrl+letter".to_string()]);
+        assert!(db.get_events().await?.iter().all(|event| {
+            event.key_id.is_none()
+                && event.typed_text.is_none()
+                && event.window_title.is_none()
+                && event.dwell_ms == Some(90)
+        }));
+
+        // Downgrading again changes nothing
+        let summary = db.downgrade_privacy(PrivacyLevel::KeyClass, false).await?;
+        assert_eq!(summary, DowngradeSummary::default());
+
+        let summary = db.downgrade_privacy(PrivacyLevel::Counts, false).await?;
+        assert_eq!(
+            summary,
+            DowngradeSummary {
+                keys: 4,
+                shortcuts: 1,
+                window_titles: 0,
+            }
+        );
+        assert_eq!(key_names().await?, vec![(COUNTED_KEY_NAME.to_string(), 4)]);
+        assert!(combos().await?.is_empty());
+        assert!(db
+            .get_events()
+            .await?
+            .iter()
+            .all(|event| event.dwell_ms.is_none()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_pauses() -> Result<()> {
+        let tmp_dir = NamedTempFile::new()?;
+        let db = Database::new(PathBuf::from(tmp_dir.path())).await?;
+        db.run_migrations().await?;
+
+        let now = Utc::now().timestamp_millis();
+        let pause = |started_at, ended_at| PauseInterval {
+            started_at,
+            ended_at,
+            paused_by: PauseSource::Hotkey,
+        };
+        db.insert_events(&[
+            pause(now - 5000, now - 1000).into(),
+            pause(now - 60_000, now - 30_000).into(),
+            // Ended well before today
+            pause(0, 1000).into(),
+        ])
+        .await?;
+
+        assert_eq!(
+            db.get_pauses(TimeRange::today(Tz::UTC)).await?,
+            vec![
+                pause(now - 60_000, now - 30_000),
+                pause(now - 5000, now - 1000)
+            ]
+        );
+
+        Ok(())
+    }
 }
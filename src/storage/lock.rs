@@ -0,0 +1,139 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::config::expand_home;
+
+/// Claim on a database by a single daemon, released when dropped.
+///
+/// The lock file sits next to the database and holds the owner's pid. It is locked with an
+/// advisory file lock, which the OS releases when the owner dies, so a file left behind by
+/// a crash is recognised as stale and taken over.
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Locks the database at `database`, failing if another process holds it
+    pub fn acquire(database: &Path) -> Result<Self> {
+        let path = lock_path(database)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {:?}", path))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => match read_owner(&mut file) {
+                Some(pid) => bail!(
+                    "Another daemon (pid {}) is already recording to {:?}, lock file {:?}",
+                    pid,
+                    database,
+                    path
+                ),
+                None => bail!(
+                    "Another daemon is already recording to {:?}, lock file {:?}",
+                    database,
+                    path
+                ),
+            },
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {:?}", path))
+            }
+        }
+
+        if let Some(pid) = read_owner(&mut file) {
+            warn!("Taking over stale lock {:?} left by pid {}", path, pid);
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", process::id())?;
+        file.sync_all()?;
+        debug!("Locked {:?}", path);
+
+        Ok(Self { file, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // The file stays, removing it would let a daemon waiting on this inode and one
+        // creating a new file at the same path both get a lock. Emptied, it names no owner
+        if let Err(e) = self.file.set_len(0) {
+            warn!("Failed to clear lock file {:?}: {:?}", self.path, e);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+/// `data.db.lock` for `data.db`, resolved so different spellings of a path share a lock
+fn lock_path(database: &Path) -> Result<PathBuf> {
+    let database = expand_home(database)?;
+    let parent = match database.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent)?;
+
+    let mut name = database
+        .file_name()
+        .with_context(|| format!("Invalid database path {:?}", database))?
+        .to_os_string();
+    name.push(".lock");
+
+    Ok(fs::canonicalize(parent)?.join(name))
+}
+
+/// Pid recorded in a lock file, `None` if it is empty or unreadable
+fn read_owner(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_lock() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let database = dir.path().join("data.db");
+
+        // A crashed daemon leaves its pid behind but no lock
+        fs::write(dir.path().join("data.db.lock"), "999999\n")?;
+
+        let lock = InstanceLock::acquire(&database)?;
+        assert_eq!(
+            fs::read_to_string(lock.path())?.trim(),
+            process::id().to_string()
+        );
+
+        // Also when spelled differently
+        let error = InstanceLock::acquire(&dir.path().join(".").join("data.db"))
+            .err()
+            .context("second lock should fail")?;
+        assert!(error
+            .to_string()
+            .contains(&format!("pid {}", process::id())));
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(dir.path().join("data.db.lock"))?, "");
+        InstanceLock::acquire(&database)?;
+
+        Ok(())
+    }
+}
@@ -1,2 +1,5 @@
 pub mod buffer;
 pub mod connection;
+pub mod lock;
+pub mod rollup;
+pub mod spool;
@@ -0,0 +1,265 @@
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::iter;
+
+use crate::models::events::KeyEvent;
+use crate::models::range::{Bucket, TimeRange};
+
+/// Pre-aggregated keystroke counts, each bucket starting on a UTC boundary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rollup {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Rollup {
+    /// Every rollup, kept up to date together
+    pub const ALL: [Rollup; 3] = [Rollup::Minute, Rollup::Hour, Rollup::Day];
+
+    /// Length of the rollup's buckets, how its rows are told apart in the table
+    pub fn bucket_ms(self) -> i64 {
+        match self {
+            Self::Minute => 60 * 1000,
+            Self::Hour => 60 * 60 * 1000,
+            Self::Day => 24 * 60 * 60 * 1000,
+        }
+    }
+
+    fn floor(self, timestamp: i64) -> i64 {
+        timestamp - timestamp.rem_euclid(self.bucket_ms())
+    }
+
+    /// The coarsest rollup that does not straddle any of `boundaries`. Open ends of a
+    /// range need no alignment, and nothing finer than a minute is kept, so bounds within
+    /// a minute leave part of one to be counted from the events
+    pub fn coarsest(boundaries: impl IntoIterator<Item = i64>) -> Self {
+        let boundaries = boundaries
+            .into_iter()
+            .filter(|&boundary| boundary != i64::MIN && boundary != i64::MAX)
+            .collect::<Vec<_>>();
+
+        [Self::Day, Self::Hour]
+            .into_iter()
+            .find(|rollup| {
+                boundaries
+                    .iter()
+                    .all(|boundary| rollup.floor(*boundary) == *boundary)
+            })
+            .unwrap_or(Self::Minute)
+    }
+
+    /// The part of `range` covered by whole buckets, as `(from, to)`, past each other when
+    /// no bucket fits. The rest of the range is read from the events
+    pub fn whole_buckets(self, range: TimeRange) -> (i64, i64) {
+        let from = if range.from == i64::MIN || self.floor(range.from) == range.from {
+            range.from
+        } else {
+            self.floor(range.from).saturating_add(self.bucket_ms())
+        };
+        let to = if range.to == i64::MAX {
+            range.to
+        } else {
+            self.floor(range.to)
+        };
+
+        (from, to)
+    }
+
+    /// The coarsest rollup that answers for the whole of `range`
+    pub fn for_range(range: TimeRange) -> Self {
+        Self::coarsest([range.from, range.to])
+    }
+
+    /// The coarsest rollup that adds up into each `bucket` of `range` in `zone`. Days in
+    /// most zones start on a whole UTC hour but not at midnight UTC
+    pub fn for_buckets(range: TimeRange, bucket: Bucket, zone: Tz) -> Self {
+        // Each bucket start is checked, which is only worth it for as many as a query
+        // may ask for
+        if range.check_buckets(bucket).is_err() {
+            return Self::Minute;
+        }
+
+        let starts = bucket.starts(range, zone).into_iter();
+        Self::coarsest(
+            iter::once(range.from)
+                .chain(starts.filter(|&start| start > range.from))
+                .chain(iter::once(range.to)),
+        )
+    }
+}
+
+/// What a rollup row counts, keystrokes are grouped by all of it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RollupKey {
+    pub bucket_start: i64,
+    pub key_name: String,
+    /// The key id, or the name for keystrokes without one
+    pub physical_key: String,
+    /// The typed character, or the name for keystrokes without one
+    pub logical_key: String,
+    /// 0 when no application was recorded
+    pub application_id: i64,
+}
+
+/// Keystrokes in a rollup row, repeats only move the first and last times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RollupCounts {
+    pub count: i64,
+    pub first_ts: i64,
+    pub last_ts: i64,
+}
+
+impl RollupCounts {
+    fn merge(&mut self, other: RollupCounts) {
+        self.count += other.count;
+        self.first_ts = self.first_ts.min(other.first_ts);
+        self.last_ts = self.last_ts.max(other.last_ts);
+    }
+}
+
+/// The keystrokes of a batch counted per minute, to be added to every rollup
+#[derive(Debug, Default)]
+pub struct RollupBatch {
+    minutes: HashMap<RollupKey, RollupCounts>,
+}
+
+impl RollupBatch {
+    pub fn add(&mut self, event: &KeyEvent, application_id: Option<i64>) {
+        let key = RollupKey {
+            bucket_start: Rollup::Minute.floor(event.timestamp),
+            key_name: event.key_name.clone(),
+            physical_key: event
+                .key_id
+                .clone()
+                .unwrap_or_else(|| event.key_name.clone()),
+            logical_key: event
+                .logical_key
+                .clone()
+                .unwrap_or_else(|| event.key_name.clone()),
+            application_id: application_id.unwrap_or(0),
+        };
+        let counts = RollupCounts {
+            count: i64::from(!event.is_repeat),
+            first_ts: event.timestamp,
+            last_ts: event.timestamp,
+        };
+
+        self.minutes
+            .entry(key)
+            .and_modify(|existing| existing.merge(counts))
+            .or_insert(counts);
+    }
+
+    /// The batch's rows for `rollup`
+    pub fn rows(&self, rollup: Rollup) -> HashMap<RollupKey, RollupCounts> {
+        let mut rows = HashMap::<RollupKey, RollupCounts>::new();
+        for (key, counts) in &self.minutes {
+            let key = RollupKey {
+                bucket_start: rollup.floor(key.bucket_start),
+                ..key.clone()
+            };
+            rows.entry(key)
+                .and_modify(|existing| existing.merge(*counts))
+                .or_insert(*counts);
+        }
+
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::range::parse_time;
+
+    const HOUR: i64 = 3_600_000;
+
+    #[test]
+    fn test_coarsest_rollup() {
+        assert_eq!(Rollup::for_range(TimeRange::all()), Rollup::Day);
+        assert_eq!(
+            Rollup::for_range(TimeRange::new(24 * HOUR, i64::MAX).unwrap()),
+            Rollup::Day
+        );
+        assert_eq!(Rollup::coarsest([HOUR, 3 * HOUR]), Rollup::Hour);
+        assert_eq!(Rollup::coarsest([HOUR, HOUR + 60_000]), Rollup::Minute);
+    }
+
+    #[test]
+    fn test_whole_buckets() -> anyhow::Result<()> {
+        assert_eq!(
+            Rollup::Minute.whole_buckets(TimeRange::new(30_000, 150_000)?),
+            (60_000, 120_000)
+        );
+        assert_eq!(
+            Rollup::Minute.whole_buckets(TimeRange::new(60_000, 120_000)?),
+            (60_000, 120_000)
+        );
+        // Within a single minute nothing comes from the rollup
+        assert_eq!(
+            Rollup::Minute.whole_buckets(TimeRange::new(10_000, 20_000)?),
+            (60_000, 0)
+        );
+        assert_eq!(
+            Rollup::Day.whole_buckets(TimeRange::all()),
+            (i64::MIN, i64::MAX)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollup_for_buckets() {
+        let millis = |time| parse_time(time, Tz::UTC).unwrap();
+        let march = TimeRange::new(millis("2024-03-01"), millis("2024-04-01")).unwrap();
+
+        assert_eq!(
+            Rollup::for_buckets(march, Bucket::Day, Tz::UTC),
+            Rollup::Day
+        );
+        // Local midnights are whole hours, across the change to summer time too
+        assert_eq!(
+            Rollup::for_buckets(march, Bucket::Day, Tz::America__New_York),
+            Rollup::Hour
+        );
+        // Hours start on the half hour
+        assert_eq!(
+            Rollup::for_buckets(march, Bucket::Hour, Tz::Asia__Kolkata),
+            Rollup::Minute
+        );
+    }
+
+    #[test]
+    fn test_rollup_batch() {
+        let mut batch = RollupBatch::default();
+        let mut repeat = KeyEvent::new("a".to_string(), 30_000);
+        repeat.is_repeat = true;
+
+        batch.add(&KeyEvent::new("a".to_string(), 10_000), Some(1));
+        batch.add(&repeat, Some(1));
+        batch.add(&KeyEvent::new("a".to_string(), 70_000), Some(1));
+        batch.add(&KeyEvent::new("b".to_string(), HOUR + 5), None);
+
+        let minutes = batch.rows(Rollup::Minute);
+        assert_eq!(minutes.len(), 3);
+
+        let hours = batch.rows(Rollup::Hour);
+        let first_hour = RollupKey {
+            bucket_start: 0,
+            key_name: "a".to_string(),
+            physical_key: "a".to_string(),
+            logical_key: "a".to_string(),
+            application_id: 1,
+        };
+        assert_eq!(
+            hours[&first_hour],
+            RollupCounts {
+                count: 2,
+                first_ts: 10_000,
+                last_ts: 70_000,
+            }
+        );
+        assert_eq!(batch.rows(Rollup::Day).len(), 2);
+    }
+}
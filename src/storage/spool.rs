@@ -0,0 +1,278 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::expand_home;
+use crate::models::events::InputEvent;
+
+/// Marks the start of every frame, used to resynchronise after a corrupt frame
+const FRAME_MAGIC: &[u8; 4] = b"MMSP";
+/// Magic, payload length and payload checksum
+const HEADER_LEN: usize = 12;
+
+/// Append-only file holding batches that could not be written to the database.
+///
+/// Each batch is stored as a frame of `MMSP`, the little endian payload length, the
+/// CRC32 of the payload and the JSON encoded batch. Frames that are truncated or fail
+/// their checksum are skipped when reading, so a crash mid-write only loses that batch.
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+/// Batches recovered from the spool
+#[derive(Debug, Default)]
+pub struct SpoolContents {
+    pub batches: Vec<Vec<InputEvent>>,
+    /// Bytes that could not be decoded and were skipped
+    pub skipped_bytes: usize,
+}
+
+impl SpoolContents {
+    pub fn event_count(&self) -> usize {
+        self.batches.iter().map(Vec::len).sum()
+    }
+}
+
+impl Spool {
+    pub fn open(path: &Path, max_bytes: u64) -> Result<Self> {
+        let path = expand_home(path)?;
+        if let Some(parent) = path.parent() {
+            if !parent.exists() {
+                debug!("Creating spool directory: {:?}", parent);
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let spool = Self { path, max_bytes };
+        if !spool.is_empty()? {
+            info!("Found existing spool at {:?}", spool.path);
+        }
+
+        Ok(spool)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Size of the spool file in bytes
+    pub fn size(&self) -> Result<u64> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.size()? == 0)
+    }
+
+    /// Appends a batch as a single frame, failing if it would grow the spool past its limit
+    pub fn append(&self, batch: &[InputEvent]) -> Result<()> {
+        let payload = serde_json::to_vec(batch)?;
+        let frame_len = (HEADER_LEN + payload.len()) as u64;
+
+        let size = self.size()?;
+        if size + frame_len > self.max_bytes {
+            bail!(
+                "Spool {:?} is full ({} of {} bytes used), cannot spool {} events",
+                self.path,
+                size,
+                self.max_bytes,
+                batch.len()
+            );
+        }
+
+        let mut frame = Vec::with_capacity(frame_len as usize);
+        frame.extend_from_slice(FRAME_MAGIC);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open spool {:?}", self.path))?;
+        file.write_all(&frame)?;
+        file.sync_data()?;
+
+        debug!("Spooled {} events to {:?}", batch.len(), self.path);
+        Ok(())
+    }
+
+    /// Reads every intact batch, oldest first
+    pub fn read_all(&self) -> Result<SpoolContents> {
+        let mut bytes = Vec::new();
+        match File::open(&self.path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut bytes)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(SpoolContents::default())
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let contents = decode_frames(&bytes);
+        if contents.skipped_bytes > 0 {
+            warn!(
+                "Skipped {} corrupt bytes while reading spool {:?}",
+                contents.skipped_bytes, self.path
+            );
+        }
+
+        Ok(contents)
+    }
+
+    /// Empties the spool, called once its contents are safely in the database
+    pub fn clear(&self) -> Result<()> {
+        match File::options().write(true).open(&self.path) {
+            Ok(file) => {
+                file.set_len(0)?;
+                file.sync_data()?;
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn decode_frames(bytes: &[u8]) -> SpoolContents {
+    let mut contents = SpoolContents::default();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        match decode_frame(&bytes[offset..]) {
+            Some((batch, frame_len)) => {
+                contents.batches.push(batch);
+                offset += frame_len;
+            }
+            None => {
+                // Skip to the next thing that looks like a frame
+                let next = bytes[offset + 1..]
+                    .windows(FRAME_MAGIC.len())
+                    .position(|window| window == FRAME_MAGIC)
+                    .map(|position| offset + 1 + position)
+                    .unwrap_or(bytes.len());
+
+                contents.skipped_bytes += next - offset;
+                offset = next;
+            }
+        }
+    }
+
+    contents
+}
+
+/// Decodes the frame at the start of `bytes`, returning the batch and the frame length
+fn decode_frame(bytes: &[u8]) -> Option<(Vec<InputEvent>, usize)> {
+    if bytes.len() < HEADER_LEN || &bytes[..4] != FRAME_MAGIC {
+        return None;
+    }
+
+    let payload_len = u32::from_le_bytes(bytes[4..8].try_into().ok()?) as usize;
+    let checksum = u32::from_le_bytes(bytes[8..12].try_into().ok()?);
+
+    let payload = bytes.get(HEADER_LEN..HEADER_LEN + payload_len)?;
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+
+    let batch = serde_json::from_slice(payload).ok()?;
+    Some((batch, HEADER_LEN + payload_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::events::KeyEvent;
+    use tempfile::TempDir;
+
+    fn batch(keys: &[&str]) -> Vec<InputEvent> {
+        keys.iter()
+            .enumerate()
+            .map(|(i, key)| KeyEvent::new(key.to_string(), i as i64).into())
+            .collect()
+    }
+
+    #[test]
+    fn test_spool_round_trip() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let spool = Spool::open(&tmp_dir.path().join("spool.bin"), 1024 * 1024)?;
+        assert!(spool.is_empty()?);
+
+        spool.append(&batch(&["a", "b"]))?;
+        spool.append(&batch(&["c"]))?;
+
+        let contents = spool.read_all()?;
+        assert_eq!(contents.batches, vec![batch(&["a", "b"]), batch(&["c"])]);
+        assert_eq!(contents.event_count(), 3);
+        assert_eq!(contents.skipped_bytes, 0);
+
+        spool.clear()?;
+        assert!(spool.is_empty()?);
+        assert_eq!(spool.read_all()?.event_count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spool_size_limit() -> Result<()> {
+        // Room for one frame of a single event but not two, whatever the events serialize to
+        let frame_len = HEADER_LEN + serde_json::to_vec(&batch(&["a"]))?.len();
+        let tmp_dir = TempDir::new()?;
+        let spool = Spool::open(&tmp_dir.path().join("spool.bin"), 2 * frame_len as u64 - 1)?;
+
+        spool.append(&batch(&["a"]))?;
+        assert!(spool.append(&batch(&["b"])).is_err());
+        assert_eq!(spool.read_all()?.batches, vec![batch(&["a"])]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spool_skips_corrupt_frames() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("spool.bin");
+        let spool = Spool::open(&path, 1024 * 1024)?;
+
+        spool.append(&batch(&["a"]))?;
+        let first_frame_len = spool.size()? as usize;
+        spool.append(&batch(&["b"]))?;
+        spool.append(&batch(&["c"]))?;
+
+        // Flip a payload byte in the second frame and leave a torn write at the end
+        let mut bytes = fs::read(&path)?;
+        bytes[first_frame_len + HEADER_LEN + 2] ^= 0xff;
+        bytes.extend_from_slice(b"MMSP\x10\x00");
+        fs::write(&path, &bytes)?;
+
+        let contents = spool.read_all()?;
+        assert_eq!(contents.batches, vec![batch(&["a"]), batch(&["c"])]);
+        assert!(contents.skipped_bytes > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spool_skips_leading_garbage() -> Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let path = tmp_dir.path().join("spool.bin");
+        fs::write(&path, b"garbage")?;
+
+        let spool = Spool::open(&path, 1024 * 1024)?;
+        spool.append(&batch(&["a"]))?;
+
+        let contents = spool.read_all()?;
+        assert_eq!(contents.batches, vec![batch(&["a"])]);
+        assert_eq!(contents.skipped_bytes, 7);
+
+        Ok(())
+    }
+}